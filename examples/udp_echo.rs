@@ -0,0 +1,22 @@
+use tokio::net::TcpStream;
+
+use pangolin::socks::{NoAuthentication, Result, Socks5Datagram, TargetAddr};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let socket =
+        Socks5Datagram::<NoAuthentication<TcpStream>>::bind("172.18.0.2:1080", "0.0.0.0:7878")
+            .await?;
+
+    // An echo UDP server from my VPS.
+    let remote = "65.52.160.71:7878".parse().unwrap();
+
+    socket.send_to(b"hello", TargetAddr::Ip(remote)).await?;
+
+    let mut buf = [0; 10];
+    let (len, target_addr) = socket.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..len], b"hello");
+    assert_eq!(target_addr, TargetAddr::Ip(remote));
+
+    Ok(())
+}
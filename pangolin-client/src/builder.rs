@@ -0,0 +1,292 @@
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+use crate::{dns, limit};
+use crate::{
+    AddressFamily, Credentials, ExpectedPeer, Keepalive, Method, PhaseTimeouts, ProxyUrl,
+    Resolution, Result, SessionPermit, SocketOptions, Socks5Config, Socks5Listener, Socks5Stream,
+    TargetAddr, UnspecifiedBindAddr,
+};
+
+/// Collects the options of a connection through a proxy, created by `Socks5Stream::builder`.
+///
+/// Options not covered by a method of the builder can be set on a `Socks5Config` passed to
+/// `config`, which the other methods then adjust.
+pub struct Socks5StreamBuilder<M> {
+    proxy: Option<TargetAddr>,
+    timeout: Option<Duration>,
+    config: Socks5Config,
+    _method: PhantomData<fn() -> M>,
+}
+
+impl<M> Socks5StreamBuilder<M>
+where
+    M: Method<Stream = TcpStream>,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            proxy: None,
+            timeout: None,
+            config: Socks5Config::default(),
+            _method: PhantomData,
+        }
+    }
+
+    /// The proxy to connect to, resolved according to `address_family` if it is a domain.
+    pub fn proxy(mut self, addr: TargetAddr) -> Self {
+        self.proxy = Some(addr);
+        self
+    }
+
+    /// The proxy of `url`, along with its resolution and credentials.
+    pub fn proxy_url(mut self, url: &ProxyUrl) -> Self {
+        self.proxy = Some(url.addr().clone());
+        self.config = url.configure(&self.config);
+        self
+    }
+
+    /// Replace the configuration built so far.
+    pub fn config(mut self, config: Socks5Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The credentials offered when the method authenticates with username/password.
+    pub fn credentials<U: Into<String>, P: Into<String>>(
+        mut self,
+        username: U,
+        password: P,
+    ) -> Self {
+        self.config.credentials = Some(Credentials::new(username, password));
+        self
+    }
+
+    /// Give up on the connection, including the handshake with the proxy, after `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on the handshake with the proxy after `timeout`, see
+    /// `Socks5Config::handshake_timeout`.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Bind the connection to the proxy to `addr` before connecting.
+    pub fn local_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.local_addr = Some(addr);
+        self
+    }
+
+    /// Bind the connection to the proxy to the network device `device`.
+    #[cfg(target_os = "linux")]
+    pub fn bind_device<D: Into<String>>(mut self, device: D) -> Self {
+        self.config.bind_device = Some(device.into());
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.socket_options.nodelay = nodelay;
+        self
+    }
+
+    pub fn resolution(mut self, resolution: Resolution) -> Self {
+        self.config.resolution = resolution;
+        self
+    }
+
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.config.address_family = address_family;
+        self
+    }
+
+    /// Connect to `target_addr` through the proxy.
+    pub async fn connect(self, target_addr: TargetAddr) -> Result<Socks5Stream<M>> {
+        within(self.timeout, self.connect_inner(target_addr)).await
+    }
+
+    async fn connect_inner(self, target_addr: TargetAddr) -> Result<Socks5Stream<M>> {
+        self.config.check_target(&target_addr)?;
+        let (socket, permit) = connect_proxy(self.proxy, &self.config).await?;
+        let mut stream =
+            Socks5Stream::connect_with_socket_and_config(socket, target_addr, self.config).await?;
+        stream.hold(permit);
+        Ok(stream)
+    }
+}
+
+/// Collects the options of a bind through a proxy, created by `Socks5Listener::builder`.
+///
+/// Options not covered by a method of the builder can be set on a `Socks5Config` passed to
+/// `config`, which the other methods then adjust.
+pub struct Socks5ListenerBuilder<M> {
+    proxy: Option<TargetAddr>,
+    timeout: Option<Duration>,
+    config: Socks5Config,
+    _method: PhantomData<fn() -> M>,
+}
+
+impl<M> Socks5ListenerBuilder<M>
+where
+    M: Method<Stream = TcpStream>,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            proxy: None,
+            timeout: None,
+            config: Socks5Config::default(),
+            _method: PhantomData,
+        }
+    }
+
+    /// The proxy to bind through, resolved according to `address_family` if it is a domain.
+    pub fn proxy(mut self, addr: TargetAddr) -> Self {
+        self.proxy = Some(addr);
+        self
+    }
+
+    /// The proxy of `url`, along with its resolution and credentials.
+    pub fn proxy_url(mut self, url: &ProxyUrl) -> Self {
+        self.proxy = Some(url.addr().clone());
+        self.config = url.configure(&self.config);
+        self
+    }
+
+    /// Replace the configuration built so far.
+    pub fn config(mut self, config: Socks5Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The credentials offered when the method authenticates with username/password.
+    pub fn credentials<U: Into<String>, P: Into<String>>(
+        mut self,
+        username: U,
+        password: P,
+    ) -> Self {
+        self.config.credentials = Some(Credentials::new(username, password));
+        self
+    }
+
+    /// Give up on the bind, up to the proxy replying with its bound address, after `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on the handshake with the proxy after `timeout`, see
+    /// `Socks5Config::handshake_timeout`.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout = Some(timeout);
+        self
+    }
+
+    pub fn phase_timeouts(mut self, timeouts: PhaseTimeouts) -> Self {
+        self.config.phase_timeouts = timeouts;
+        self
+    }
+
+    /// Give up on each `accept` of the listener after `timeout`, see
+    /// `Socks5Config::accept_timeout`.
+    pub fn accept_timeout(mut self, timeout: Duration) -> Self {
+        self.config.accept_timeout = Some(timeout);
+        self
+    }
+
+    /// Only accept the incoming connection from `peer`, see `Socks5Config::expected_bind_peer`.
+    pub fn expected_peer<P: Into<ExpectedPeer>>(mut self, peer: P) -> Self {
+        self.config.expected_bind_peer = Some(peer.into());
+        self
+    }
+
+    pub fn unspecified_bind_addr(mut self, unspecified: UnspecifiedBindAddr) -> Self {
+        self.config.unspecified_bind_addr = unspecified;
+        self
+    }
+
+    /// Bind the connection to the proxy to `addr` before connecting.
+    pub fn local_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.local_addr = Some(addr);
+        self
+    }
+
+    /// Bind the connection to the proxy to the network device `device`.
+    #[cfg(target_os = "linux")]
+    pub fn bind_device<D: Into<String>>(mut self, device: D) -> Self {
+        self.config.bind_device = Some(device.into());
+        self
+    }
+
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.config.socket_options = options;
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.socket_options.nodelay = nodelay;
+        self
+    }
+
+    /// Keepalive probes on the connection to the proxy while waiting for the incoming
+    /// connection, see `Socks5Config::control_keepalive`.
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.config.control_keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn resolution(mut self, resolution: Resolution) -> Self {
+        self.config.resolution = resolution;
+        self
+    }
+
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.config.address_family = address_family;
+        self
+    }
+
+    /// Ask the proxy to accept a connection from `target_addr`.
+    pub async fn bind(self, target_addr: TargetAddr) -> Result<Socks5Listener<M>> {
+        within(self.timeout, self.bind_inner(target_addr)).await
+    }
+
+    async fn bind_inner(self, target_addr: TargetAddr) -> Result<Socks5Listener<M>> {
+        self.config.check_target(&target_addr)?;
+        let (socket, permit) = connect_proxy(self.proxy, &self.config).await?;
+        Socks5Listener::bind_over(socket, permit, target_addr, self.config).await
+    }
+}
+
+// Connect to `proxy`, trying each of its addresses if it is a domain.
+async fn connect_proxy(
+    proxy: Option<TargetAddr>,
+    config: &Socks5Config,
+) -> Result<(TcpStream, Option<SessionPermit>)> {
+    let proxy =
+        proxy.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no proxy address"))?;
+    let addrs = match proxy {
+        TargetAddr::Ip(addr) => vec![addr],
+        TargetAddr::Domain(domain, port) => {
+            dns::lookup(&domain, port, config.dns_cache.as_ref()).await?
+        }
+    };
+    limit::connect_addrs(addrs, config).await
+}
+
+async fn within<T, F>(timeout: Option<Duration>, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+        None => fut.await,
+    }
+}
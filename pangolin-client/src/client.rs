@@ -0,0 +1,868 @@
+use std::convert::{TryFrom, TryInto};
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut, Range};
+use std::pin::Pin;
+use std::sync::{Mutex, PoisonError};
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::config;
+use crate::datagram::{move_datagram, AsyncDatagram};
+use crate::fragment::{self, Reassembler};
+use crate::proto::{decode_addr, encode_addr, Request, UdpHeader, Version};
+use crate::{
+    HandshakePhase, Method, PhaseTimeouts, Result, SessionId, SessionPermit, Socks5Config,
+    Socks5Error, TargetAddr, VERSION,
+};
+
+impl<M> Deref for Socks5Client<M> {
+    type Target = M;
+    fn deref(&self) -> &Self::Target {
+        &self.method
+    }
+}
+
+impl<M> DerefMut for Socks5Client<M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.method
+    }
+}
+
+// The largest chunk of stream data encapsulated into a single message.
+const ENCAPSULATION_CHUNK: usize = 16 * 1024;
+
+// Large enough for any packet sent by the relay.
+const RECV_SCRATCH_SIZE: usize = 64 * 1024;
+
+// The largest payload of a UDP datagram over IPv4.
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+// The payload of a datagram received from the relay.
+pub(crate) enum Payload {
+    // Where it lies in the received packet.
+    Range(Range<usize>),
+    // Produced apart from the packet, by reassembly, transformation or decapsulation.
+    Owned(Vec<u8>),
+}
+
+impl Payload {
+    pub(crate) fn get<'a>(&'a self, packet: &'a [u8]) -> &'a [u8] {
+        match self {
+            Payload::Range(range) => &packet[range.clone()],
+            Payload::Owned(payload) => payload,
+        }
+    }
+}
+
+// Replace the contents of `buf` by `data`, truncated to fit.
+fn refill(buf: &mut ReadBuf<'_>, data: &[u8]) {
+    buf.clear();
+    let len = buf.remaining().min(data.len());
+    buf.put_slice(&data[..len]);
+}
+
+pub(crate) struct Socks5Client<M> {
+    method: M,
+    session_id: SessionId,
+    config: Socks5Config,
+
+    // Buffers used when the method encapsulates the traffic: encapsulated bytes not yet written
+    // and raw bytes read but not yet decapsulated.
+    write_buf: BytesMut,
+    read_raw: BytesMut,
+    // Bytes read but not returned yet: decapsulated data, or relayed data read along with a
+    // reply.
+    read_buf: Bytes,
+
+    // The part of a reply read so far, kept when waiting for the rest is cut short so that it can
+    // resume.
+    reply_buf: BytesMut,
+
+    // The slot of the proxy endpoint taken by this session, if it is limited.
+    permit: Option<SessionPermit>,
+
+    // What cuts short the handshake started by `connect`, until the reply to the request.
+    bounds: Option<Bounds>,
+
+    // Receives relayed packets, header included, before their payload is copied out.
+    recv_scratch: Mutex<Vec<u8>>,
+
+    // Fragments of relayed datagrams waiting for the rest of their datagram.
+    reassembler: Mutex<Reassembler>,
+}
+
+impl<M> Socks5Client<M> {
+    pub fn session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    pub fn config(&self) -> &Socks5Config {
+        &self.config
+    }
+
+    /// Keep `permit` for as long as the session lives.
+    pub fn hold(&mut self, permit: Option<SessionPermit>) {
+        self.permit = permit;
+    }
+}
+
+impl<M> Socks5Client<M>
+where
+    M: Method,
+{
+    // Give back the socket to the proxy, and the datagram socket if one was registered. Data
+    // read but not returned yet is lost, as is the slot of the session if it is limited.
+    pub(crate) fn into_parts(self) -> (M::Stream, Option<M::Datagram>) {
+        self.method.into_parts()
+    }
+
+    // Build the packets carrying `data` to `dst`, several of them if it has to be fragmented.
+    pub(crate) fn pack_datagram(&self, dst: &TargetAddr, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let sealed;
+        let data = match &self.config.datagram_transform {
+            Some(transform) => {
+                sealed = transform.seal(data)?;
+                &sealed[..]
+            }
+            None => data,
+        };
+
+        let fragments = match self.config.fragment_size {
+            Some(size) if data.len() > size => fragment::split(data, size)?,
+            _ => vec![(0x00, data)],
+        };
+
+        let mut packets = Vec::with_capacity(fragments.len());
+        for (frag, data) in fragments {
+            let mut buf = Vec::with_capacity(262 + data.len());
+            // The header of `UdpHeader::encode`, without cloning `dst` into a header.
+            buf.extend_from_slice(&[0x00, 0x00, frag]);
+            encode_addr(&mut buf, dst)?;
+            buf.extend_from_slice(data);
+
+            let limit = self.config.max_datagram_size.unwrap_or(MAX_UDP_PAYLOAD);
+            if buf.len() > limit {
+                return Err(Socks5Error::DatagramTooLarge {
+                    limit,
+                    size: buf.len(),
+                });
+            }
+            packets.push(self.method.encapsulate_datagram(buf)?);
+        }
+        Ok(packets)
+    }
+
+    /// Send the packets of a datagram to the relay from `*sent` on, keeping track of the progress
+    /// across calls.
+    pub(crate) fn poll_send_packets(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[Vec<u8>],
+        dst: &TargetAddr,
+        sent: &mut usize,
+    ) -> Poll<Result<()>> {
+        while *sent < packets.len() {
+            let slices: Vec<IoSlice<'_>> = packets[*sent..]
+                .iter()
+                .map(|packet| IoSlice::new(packet))
+                .collect();
+            *sent += ready!(self.method.poll_send_many(cx, &slices, dst))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Parse the header of a packet received from the relay. Returns `None` for fragments until
+    // their datagram is complete, or for every fragment if `drop_fragments` is set.
+    fn unpack_datagram(&self, packet: &mut [u8]) -> Result<Option<(Payload, TargetAddr)>> {
+        if self.config.quirks.nonzero_reserved && packet.len() >= 2 {
+            packet[..2].copy_from_slice(&[0x00, 0x00]);
+        }
+
+        let (header, header_len) = UdpHeader::decode(packet)?;
+
+        let payload = if header.frag == 0x00 {
+            Payload::Range(header_len..packet.len())
+        } else if self.config.drop_fragments {
+            return Ok(None);
+        } else {
+            let mut reassembler = self
+                .reassembler
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match reassembler.push(
+                &header.target,
+                header.frag,
+                &packet[header_len..],
+                Instant::now(),
+            ) {
+                Some(datagram) => Payload::Owned(datagram),
+                None => return Ok(None),
+            }
+        };
+
+        let payload = match &self.config.datagram_transform {
+            Some(transform) => Payload::Owned(transform.open(payload.get(packet))?),
+            None => payload,
+        };
+
+        Ok(Some((payload, header.target)))
+    }
+
+    // Receive the next datagram from the relay into `raw`, which must be large enough for the
+    // whole packet. The payload is left in place when it needs neither decapsulation,
+    // reassembly nor transformation.
+    pub(crate) fn poll_recv_packet(
+        &self,
+        cx: &mut Context<'_>,
+        raw: &mut [u8],
+    ) -> Poll<Result<(Payload, TargetAddr)>> {
+        loop {
+            let mut buf = ReadBuf::new(raw);
+            ready!(self.method.poll_recv_from(cx, &mut buf))?;
+            let len = buf.filled().len();
+
+            let unpacked = if self.method.encapsulates() {
+                let mut packet = self.method.decapsulate_datagram(&raw[..len])?;
+                self.unpack_datagram(&mut packet)?.map(|(payload, target)| {
+                    (Payload::Owned(payload.get(&packet).to_vec()), target)
+                })
+            } else {
+                self.unpack_datagram(&mut raw[..len])?
+            };
+
+            if let Some(unpacked) = unpacked {
+                return Poll::Ready(Ok(unpacked));
+            }
+        }
+    }
+
+    // Receive datagrams from the relay into the first buffers of `bufs`, replacing each packet by
+    // its payload, and return their targets.
+    pub(crate) fn poll_recv_packets(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        loop {
+            let received = ready!(self.method.poll_recv_many(cx, bufs))?.len();
+            let mut targets = Vec::with_capacity(received);
+            for i in 0..received {
+                if let Some(target) = self.unpack_in_place(&mut bufs[i])? {
+                    if targets.len() != i {
+                        move_datagram(bufs, i, targets.len());
+                    }
+                    targets.push(target);
+                }
+            }
+
+            if !targets.is_empty() {
+                return Poll::Ready(Ok(targets));
+            }
+            bufs.iter_mut().for_each(ReadBuf::clear);
+        }
+    }
+
+    // Replace the packet in `buf` by its payload. Returns `None` if it doesn't complete a
+    // datagram.
+    fn unpack_in_place(&self, buf: &mut ReadBuf<'_>) -> Result<Option<TargetAddr>> {
+        if self.method.encapsulates() {
+            let mut packet = self.method.decapsulate_datagram(buf.filled())?;
+            let (payload, target) = match self.unpack_datagram(&mut packet)? {
+                Some(unpacked) => unpacked,
+                None => return Ok(None),
+            };
+            refill(buf, payload.get(&packet));
+            return Ok(Some(target));
+        }
+
+        let (payload, target) = match self.unpack_datagram(buf.filled_mut())? {
+            Some(unpacked) => unpacked,
+            None => return Ok(None),
+        };
+        match payload {
+            Payload::Range(range) => {
+                let len = range.len();
+                buf.filled_mut().copy_within(range, 0);
+                buf.set_filled(len);
+            }
+            Payload::Owned(payload) => refill(buf, &payload),
+        }
+        Ok(Some(target))
+    }
+
+    async fn select_method(socket: &mut M::Stream, config: &Socks5Config) -> Result<u8> {
+        let codes = M::codes(config);
+        socket.write_all(&encode_greeting(&codes)?).await?;
+
+        let mut buf = [0; 2];
+        socket.read_exact(&mut buf).await?;
+        check_selection(buf, &codes, config)
+    }
+
+    pub async fn connect(socket: M::Stream, config: Socks5Config) -> Result<Self> {
+        let session = SessionId::next();
+        Self::negotiate(socket, config, session)
+            .instrument(session.span())
+            .await
+            .map_err(|e| e.in_session(session.as_u64()))
+    }
+
+    async fn negotiate(
+        mut socket: M::Stream,
+        config: Socks5Config,
+        session: SessionId,
+    ) -> Result<Self> {
+        let bounds = Bounds::start(&config);
+        let code = bounds
+            .within(
+                HandshakePhase::MethodSelection,
+                Self::select_method(&mut socket, &config),
+            )
+            .await?;
+
+        let method = bounds
+            .within(HandshakePhase::SubNegotiation, async {
+                let mut method = M::create(socket, code, &config).await?;
+                // Enter method dependent sub-negotiation phase
+                method.handshake(&config).await?;
+                Ok(method)
+            })
+            .await?;
+
+        tracing::debug!(method = code, "method negotiated");
+        let mut client = Self::new(method, config, session);
+        client.bounds = Some(bounds);
+        Ok(client)
+    }
+
+    /// Connect and send `request`, pipelined with the greeting if `config.pipeline` allows it.
+    /// Returns the address in the reply.
+    pub(crate) async fn connect_and_request(
+        socket: M::Stream,
+        config: Socks5Config,
+        request: Request,
+    ) -> Result<(Self, TargetAddr)> {
+        if !config.pipeline || M::codes(&config) != [0x00] {
+            let mut client = Self::connect(socket, config).await?;
+            let addr = client.send_request(request).await?;
+            return Ok((client, addr));
+        }
+
+        let session = SessionId::next();
+        Self::pipeline(socket, config, request, session)
+            .instrument(session.span())
+            .await
+            .map_err(|e| e.in_session(session.as_u64()))
+    }
+
+    // No authentication has no sub-negotiation, so the request can follow the greeting without
+    // waiting for the method selection.
+    async fn pipeline(
+        socket: M::Stream,
+        config: Socks5Config,
+        request: Request,
+        session: SessionId,
+    ) -> Result<(Self, TargetAddr)> {
+        let bounds = Bounds::start(&config);
+        let mut socket = socket;
+        let mut data = encode_greeting(&[0x00])?;
+        data.extend_from_slice(&Vec::<u8>::try_from(request)?);
+        // The reply may arrive along with the method selection, in the same read.
+        let mut buf = [0; 2 + MAX_REPLY_LEN];
+        let mut filled = 0;
+        let code = bounds
+            .within(HandshakePhase::MethodSelection, async {
+                socket.write_all(&data).await?;
+                while filled < 2 {
+                    let n = socket.read(&mut buf[filled..]).await?;
+                    if n == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    filled += n;
+                }
+                check_selection([buf[0], buf[1]], &[0x00], &config)
+            })
+            .await?;
+
+        let method = bounds
+            .within(HandshakePhase::SubNegotiation, async {
+                let mut method = M::create(socket, code, &config).await?;
+                method.handshake(&config).await?;
+                Ok(method)
+            })
+            .await?;
+
+        let mut client = Self::new(method, config, session);
+        client.unread(&buf[2..filled]);
+        let addr = bounds
+            .within(HandshakePhase::Request, client.read_reply())
+            .await?;
+        tracing::debug!(?addr, "request accepted");
+        Ok((client, addr))
+    }
+
+    /// Wrap a method whose sub-negotiation is complete, for the session numbered `session_id`
+    /// when it started.
+    pub fn new(method: M, config: Socks5Config, session_id: SessionId) -> Self {
+        Self {
+            method,
+            session_id,
+            config,
+            write_buf: BytesMut::new(),
+            read_raw: BytesMut::new(),
+            read_buf: Bytes::new(),
+            reply_buf: BytesMut::new(),
+            permit: None,
+            bounds: None,
+            recv_scratch: Mutex::new(Vec::new()),
+            reassembler: Mutex::new(Reassembler::default()),
+        }
+    }
+
+    // +----+-----+-------+------+----------+----------+
+    // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+    // +----+-----+-------+------+----------+----------+
+    // | 1  |  1  | X'00' |  1   | Variable |    2     |
+    // +----+-----+-------+------+----------+----------+
+    pub async fn send_request(&mut self, request: Request) -> Result<TargetAddr> {
+        let bounds = self.bounds.take().unwrap_or_else(|| Bounds {
+            deadline: None,
+            ..Bounds::start(&self.config)
+        });
+        let session = self.session_id;
+        let data: Vec<u8> = request
+            .try_into()
+            .map_err(|e: Socks5Error| e.in_session(session.as_u64()))?;
+        bounds
+            .within(HandshakePhase::Request, async {
+                self.write_all(&data).await?;
+                self.flush().await?;
+                let addr = self.read_reply().await?;
+                tracing::debug!(?addr, "request accepted");
+                Ok(addr)
+            })
+            .instrument(session.span())
+            .await
+            .map_err(|e| e.in_session(session.as_u64()))
+    }
+
+    // +----+-----+-------+------+----------+----------+
+    // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+    // +----+-----+-------+------+----------+----------+
+    // | 1  |  1  | X'00' |  1   | Variable |    2     |
+    // +----+-----+-------+------+----------+----------+
+    /// Wait for the second reply to a BIND request, reporting the incoming connection.
+    pub async fn recv_reply(&mut self) -> Result<TargetAddr> {
+        self.read_reply()
+            .await
+            .map_err(|e| e.during(HandshakePhase::Accept))
+    }
+
+    // Reads as much as is available rather than the exact length of each field, so that the reply
+    // usually takes a single read.
+    async fn read_reply(&mut self) -> Result<TargetAddr> {
+        loop {
+            match parse_reply(&self.reply_buf, &self.config)? {
+                ReplyProgress::Complete(addr, len) => {
+                    // Whatever follows the reply is relayed data.
+                    let rest = self.reply_buf.split_off(len);
+                    self.reply_buf.clear();
+                    self.unread(&rest);
+                    return Ok(addr);
+                }
+                ReplyProgress::Need(_) => {
+                    let mut buf = [0; MAX_REPLY_LEN];
+                    let len = MAX_REPLY_LEN - self.reply_buf.len();
+                    let n = self.read(&mut buf[..len]).await?;
+                    if n == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    self.reply_buf.extend_from_slice(&buf[..n]);
+                }
+            }
+        }
+    }
+
+    // Bytes read from the proxy but not returned yet.
+    pub(crate) fn buffered(&self) -> &[u8] {
+        &self.read_buf
+    }
+
+    // Whether bytes of the proxy were read but not consumed yet, be they part of a reply or data.
+    pub(crate) fn has_unread(&self) -> bool {
+        !self.reply_buf.is_empty() || !self.read_buf.is_empty()
+    }
+
+    // Put back bytes read past the end of a reply, to be returned first by the next reads.
+    fn unread(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut buf = BytesMut::with_capacity(data.len() + self.read_buf.len());
+        buf.extend_from_slice(data);
+        buf.extend_from_slice(&self.read_buf);
+        self.read_buf = buf.freeze();
+    }
+}
+
+// What cuts a handshake short: its deadline, the timeouts of its phases and its cancellation.
+struct Bounds {
+    deadline: Option<Instant>,
+    timeouts: PhaseTimeouts,
+    cancellation: Option<CancellationToken>,
+}
+
+impl Bounds {
+    // The bounds of a handshake starting now.
+    fn start(config: &Socks5Config) -> Self {
+        Self {
+            deadline: config
+                .handshake_timeout
+                .map(|timeout| Instant::now() + timeout),
+            timeouts: config.phase_timeouts,
+            cancellation: config.cancellation.clone(),
+        }
+    }
+
+    // Run a phase of the handshake, failing with `Timeout` if the deadline or the timeout of the
+    // phase passes first, or with `Cancelled` if the handshake is cancelled.
+    async fn within<T, F>(&self, phase: HandshakePhase, phase_fut: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let phase_deadline = self
+            .timeouts
+            .get(phase)
+            .map(|timeout| Instant::now() + timeout);
+        let deadline = match (self.deadline, phase_deadline) {
+            (Some(deadline), Some(phase_deadline)) => Some(deadline.min(phase_deadline)),
+            (deadline, phase_deadline) => deadline.or(phase_deadline),
+        };
+        let timed = async {
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline.into(), phase_fut)
+                    .await
+                    .map_err(|_| Socks5Error::Timeout { phase })?,
+                None => phase_fut.await,
+            }
+        };
+        config::cancellable(self.cancellation.as_ref(), timed)
+            .await
+            .map_err(|e| e.during(phase))
+    }
+}
+
+// +----+----------+----------+
+// |VER | NMETHODS | METHODS  |
+// +----+----------+----------+
+// | 1  |    1     | 1 to 255 |
+// +----+----------+----------+
+pub(crate) fn encode_greeting(codes: &[u8]) -> Result<Vec<u8>> {
+    let nmethods = u8::try_from(codes.len()).map_err(|_| Socks5Error::TooManyMethods)?;
+    if nmethods == 0 {
+        return Err(Socks5Error::NoAcceptableMethod);
+    }
+
+    let mut greeting = Vec::with_capacity(2 + codes.len());
+    greeting.extend_from_slice(&[VERSION, nmethods]);
+    greeting.extend_from_slice(codes);
+    Ok(greeting)
+}
+
+// +----+--------+
+// |VER | METHOD |
+// +----+--------+
+// | 1  |   1    |
+// +----+--------+
+pub(crate) fn check_selection(buf: [u8; 2], codes: &[u8], config: &Socks5Config) -> Result<u8> {
+    Version::SOCKS5.check(buf[0], config.strictness)?;
+
+    if buf[1] == 0xff {
+        return Err(Socks5Error::NoAcceptableMethod);
+    }
+
+    if !codes.contains(&buf[1]) {
+        return Err(Socks5Error::UnofferedMethod(buf[1]));
+    }
+
+    Ok(buf[1])
+}
+
+// VER, REP, RSV, ATYP, a length-prefixed domain of up to 255 bytes and the port.
+pub(crate) const MAX_REPLY_LEN: usize = 4 + 1 + 255 + 2;
+
+pub(crate) enum ReplyProgress {
+    // The bound address and the length of the reply.
+    Complete(TargetAddr, usize),
+    // The length the buffer must reach before the reply can be parsed any further.
+    Need(usize),
+}
+
+/// Parse the reply at the front of `buf`, which may be incomplete. Never asks for bytes past the
+/// end of the reply, so that none of the relayed data is consumed.
+pub(crate) fn parse_reply(buf: &[u8], config: &Socks5Config) -> Result<ReplyProgress> {
+    if buf.len() < 4 {
+        return Ok(ReplyProgress::Need(4));
+    }
+
+    Version::SOCKS5.check(buf[0], config.strictness)?;
+
+    match buf[1] {
+        0x00 => {}
+        0x01 => return Err(Socks5Error::GeneralSocksServerFailure),
+        0x02 => return Err(Socks5Error::ConnectionNotAllowed),
+        0x03 => return Err(Socks5Error::NetworkUnreachable),
+        0x04 => return Err(Socks5Error::HostUnreachable),
+        0x05 => return Err(Socks5Error::ConnectionRefused),
+        0x06 => return Err(Socks5Error::TtlExpired),
+        0x07 => return Err(Socks5Error::CommandNotSupported),
+        0x08 => return Err(Socks5Error::AddressTypeNotSupported),
+        _ => return Err(Socks5Error::Unassigned),
+    }
+
+    let quirks = config.quirks;
+
+    if buf[2] != 0x00 && !quirks.nonzero_reserved {
+        return Err(Socks5Error::InvalidReservedByte {
+            expected: 0x00,
+            actual: buf[2],
+        });
+    }
+
+    if quirks.missing_bound_address {
+        return Ok(ReplyProgress::Complete(
+            TargetAddr::Ip(SocketAddr::from(([0, 0, 0, 0], 0))),
+            4,
+        ));
+    }
+
+    let atyp = match buf[3] {
+        0x00 if quirks.zero_address_type => 0x01,
+        atyp => atyp,
+    };
+    let len = match atyp {
+        0x01 => 4 + 4 + 2,
+        0x03 => match buf.get(4) {
+            Some(&len) => 4 + 1 + len as usize + 2,
+            None => return Ok(ReplyProgress::Need(5)),
+        },
+        0x04 => 4 + 16 + 2,
+        _ => return Err(Socks5Error::InvalidAddressType),
+    };
+    if buf.len() < len {
+        return Ok(ReplyProgress::Need(len));
+    }
+
+    let mut addr = Vec::with_capacity(len - 3);
+    addr.push(atyp);
+    addr.extend_from_slice(&buf[4..len]);
+    let (target_addr, _) = decode_addr(&addr)?;
+    Ok(ReplyProgress::Complete(target_addr, len))
+}
+
+impl<M> Socks5Client<M>
+where
+    M: Method,
+{
+    // Write out the encapsulated bytes left over from previous writes.
+    fn poll_write_encapsulated(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.method).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<M> AsyncDatagram for Socks5Client<M>
+where
+    M: Method,
+{
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.method.poll_send_ready(cx)
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.method.poll_recv_ready(cx)
+    }
+
+    // Packs the datagram again on every call; `Socks5Datagram` packs it once per send instead,
+    // with `pack_datagram` and `poll_send_packets`.
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        let packets = self.pack_datagram(target, buf)?;
+        self.poll_send_packets(cx, &packets, target, &mut 0)
+            .map_ok(|()| buf.len())
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        let mut scratch = self
+            .recv_scratch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        scratch.resize(RECV_SCRATCH_SIZE, 0);
+
+        // Packets are received whole into the scratch buffer, so that `buf` only has to be large
+        // enough for the payload.
+        let (payload, target) = ready!(self.poll_recv_packet(cx, &mut scratch))?;
+        let payload = payload.get(&scratch);
+        let n = buf.remaining().min(payload.len());
+        buf.put_slice(&payload[..n]);
+        if n < payload.len() {
+            return Poll::Ready(Err(Socks5Error::DatagramTruncated {
+                size: payload.len(),
+                capacity: n,
+            }));
+        }
+        Poll::Ready(Ok(target))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.method.local_addr()
+    }
+}
+
+impl<M> AsyncRead for Socks5Client<M>
+where
+    M: Method,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.method.encapsulates() {
+            if !this.read_buf.is_empty() {
+                let n = buf.remaining().min(this.read_buf.len());
+                buf.put_slice(&this.read_buf.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            return Pin::new(&mut this.method).poll_read(cx, buf);
+        }
+
+        loop {
+            if !this.read_buf.is_empty() {
+                let n = buf.remaining().min(this.read_buf.len());
+                buf.put_slice(&this.read_buf.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(data) = this.method.decapsulate(&mut this.read_raw)? {
+                this.read_buf = data.into();
+                continue;
+            }
+
+            let mut chunk = [0; 4096];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.method).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return if this.read_raw.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.read_raw.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<M> AsyncWrite for Socks5Client<M>
+where
+    M: Method,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if !this.method.encapsulates() {
+            return Pin::new(&mut this.method).poll_write(cx, buf);
+        }
+
+        ready!(this.poll_write_encapsulated(cx))?;
+        let n = buf.len().min(ENCAPSULATION_CHUNK);
+        let data = this.method.encapsulate(&buf[..n])?;
+        this.write_buf.extend_from_slice(&data);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if !self.method.encapsulates() {
+            return Pin::new(&mut self.method).poll_write_vectored(cx, bufs);
+        }
+
+        // Encapsulated messages are built from one slice at a time.
+        let buf = bufs
+            .iter()
+            .find(|buf| !buf.is_empty())
+            .map_or(&[][..], |buf| &**buf);
+        self.poll_write(cx, buf)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        !self.method.encapsulates() && self.method.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_encapsulated(cx))?;
+        Pin::new(&mut self.method).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_encapsulated(cx))?;
+        Pin::new(&mut self.method).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::NoAuthentication;
+
+    #[tokio::test]
+    async fn attributes_handshake_failures_to_the_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[VERSION, 0xff]).await.unwrap();
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let e = Socks5Client::<NoAuthentication<TcpStream>>::connect(socket, Default::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(e.session().is_some());
+        assert!(matches!(e.root(), Socks5Error::NoAcceptableMethod));
+    }
+}
@@ -0,0 +1,355 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(feature = "tcp-fastopen")]
+use tokio::net::TcpSocket;
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::proto::{Quirks, Strictness};
+use crate::{
+    AddressFamily, BufferPool, ConcurrencyLimiter, CredentialProvider, Credentials,
+    DatagramTransform, DnsCache, ExpectedPeer, HandshakePhase, Resolution, Result, Socks5Error,
+    TargetAddr, TargetPolicy, UnspecifiedBindAddr,
+};
+
+/// Options applied to the tunnels negotiated by the client.
+#[derive(Debug, Clone, Default)]
+pub struct Socks5Config {
+    /// Where domain targets are resolved.
+    pub resolution: Resolution,
+    /// Caches the local lookups of domain targets and of `ProxyUrl` hosts.
+    pub dns_cache: Option<DnsCache>,
+    /// Which addresses are used when a domain target or a proxy host resolves to several.
+    pub address_family: AddressFamily,
+    /// How strictly the version bytes sent by the proxy are checked.
+    pub strictness: Strictness,
+    /// Deviations from the RFC tolerated when parsing replies.
+    pub quirks: Quirks,
+    /// Credentials for the username/password method.
+    pub credentials: Option<Credentials>,
+    /// Where the username/password method gets its credentials, taking precedence over
+    /// `credentials`.
+    pub credential_provider: Option<Arc<dyn CredentialProvider>>,
+    /// Protects the payloads of UDP datagrams end to end.
+    pub datagram_transform: Option<Arc<dyn DatagramTransform>>,
+    /// Drop fragmented UDP datagrams instead of reassembling them, for proxies that never send
+    /// fragments.
+    pub drop_fragments: bool,
+    /// Split UDP payloads larger than this many bytes into fragments, for relays that reassemble
+    /// them. Off by default, since most relays don't.
+    pub fragment_size: Option<usize>,
+    /// Fail to send packets longer than this many bytes, SOCKS header included, with
+    /// `Socks5Error::DatagramTooLarge` instead of leaving them to be dropped on the way, e.g. to
+    /// stay under the MTU of the path to the relay. Defaults to the largest UDP payload.
+    pub max_datagram_size: Option<usize>,
+    /// Where `recv_from_pooled` takes its buffers, to share them between datagrams. Each
+    /// datagram has its own pool otherwise.
+    pub buffer_pool: Option<BufferPool>,
+    /// Targets refused before anything is sent to the proxy.
+    pub target_policy: Option<TargetPolicy>,
+    /// Write the CONNECT request along with the greeting when no authentication is the only
+    /// method offered, saving a round trip. Off by default, since proxies that discard what is
+    /// sent before their method selection break with it.
+    pub pipeline: bool,
+    /// Fail the handshake with `Socks5Error::Timeout` if it takes longer than this, from the
+    /// greeting to the reply to the request, instead of waiting for a hung proxy forever.
+    pub handshake_timeout: Option<Duration>,
+    /// Limits on each phase of the handshake, within `handshake_timeout`.
+    pub phase_timeouts: PhaseTimeouts,
+    /// Fail `Socks5Listener::accept` with `Socks5Error::Timeout` if no connection arrives within
+    /// this long of the call.
+    pub accept_timeout: Option<Duration>,
+    /// Fail `Socks5Listener::accept` with `Socks5Error::UnexpectedBindPeer` if the incoming
+    /// connection comes from another peer.
+    pub expected_bind_peer: Option<ExpectedPeer>,
+    /// What `Socks5Listener::bind_addr` reports when the proxy replies with an unspecified
+    /// address.
+    pub unspecified_bind_addr: UnspecifiedBindAddr,
+    /// Aborts connecting to the proxy and the handshake with `Socks5Error::Cancelled`, attributed
+    /// to the session, once cancelled, e.g. on shutdown, closing the connection.
+    pub cancellation: Option<CancellationToken>,
+    /// Options of the TCP connection to the proxy, set before the handshake.
+    pub socket_options: SocketOptions,
+    /// Keepalive probes on the control connections of UDP associations and BIND listeners, which
+    /// stay idle while the session lasts and are silently dropped by NATs and firewalls
+    /// otherwise. Overrides `socket_options.keepalive` for them.
+    pub control_keepalive: Option<Keepalive>,
+    /// The local address the connection to the proxy is made from, to pin it to an interface of
+    /// a multi-homed host. `Socks5Datagram::associate` binds its UDP socket to the same address.
+    pub local_addr: Option<SocketAddr>,
+    /// The network device, e.g. `"wg0"`, the connection to the proxy and the UDP sockets of
+    /// datagrams are bound to (`SO_BINDTODEVICE`).
+    #[cfg(target_os = "linux")]
+    pub bind_device: Option<String>,
+    /// Caps the simultaneous sessions to each proxy endpoint.
+    pub limiter: Option<ConcurrencyLimiter>,
+    /// Parameters of custom methods, e.g. the private method code (0x80-0xFE) to offer.
+    pub extensions: Extensions,
+}
+
+impl Socks5Config {
+    /// Whether credentials are configured for the username/password method.
+    pub fn has_credentials(&self) -> bool {
+        self.credentials.is_some() || self.credential_provider.is_some()
+    }
+
+    /// A copy with `handshake_timeout` shortened to end by `deadline`.
+    pub(crate) fn until(&self, deadline: Instant) -> Socks5Config {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        Socks5Config {
+            handshake_timeout: Some(
+                self.handshake_timeout
+                    .map_or(remaining, |timeout| timeout.min(remaining)),
+            ),
+            ..self.clone()
+        }
+    }
+
+    /// Bind `socket` to `bind_device`, if there is one.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn bind_to_device<S: AsRawFd>(&self, socket: &S) -> io::Result<()> {
+        match &self.bind_device {
+            Some(device) => SockRef::from(socket).bind_device(Some(device.as_bytes())),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn bind_to_device<S>(&self, _socket: &S) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Check `target` against the target policy, if there is one.
+    pub(crate) fn check_target(&self, target: &TargetAddr) -> Result<()> {
+        match &self.target_policy {
+            Some(policy) => policy.check(target),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A type-keyed map of values, letting `Method` implementations find their own runtime
+/// parameters in a `Socks5Config`.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `value`, replacing any previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> bool {
+        self.map.remove(&TypeId::of::<T>()).is_some()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+/// The quirks needed by each known proxy endpoint, so that compatibility settings can be kept in
+/// one place and looked up when building the `Socks5Config` of a connection.
+#[derive(Debug, Clone, Default)]
+pub struct QuirksRegistry {
+    endpoints: HashMap<String, Quirks>,
+}
+
+impl QuirksRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the quirks of the proxy at `endpoint`, e.g. `"10.0.0.1:1080"`.
+    pub fn insert<E: Into<String>>(&mut self, endpoint: E, quirks: Quirks) -> Option<Quirks> {
+        self.endpoints.insert(endpoint.into(), quirks)
+    }
+
+    pub fn remove(&mut self, endpoint: &str) -> Option<Quirks> {
+        self.endpoints.remove(endpoint)
+    }
+
+    /// The quirks of `endpoint`, or none if it isn't registered.
+    pub fn get(&self, endpoint: &str) -> Quirks {
+        self.endpoints
+            .get(endpoint)
+            .copied()
+            .unwrap_or(Quirks::NONE)
+    }
+
+    /// A copy of `config` with the quirks of `endpoint` applied.
+    pub fn configure(&self, endpoint: &str, config: &Socks5Config) -> Socks5Config {
+        Socks5Config {
+            quirks: self.get(endpoint),
+            ..config.clone()
+        }
+    }
+}
+
+/// How long each phase of the handshake may take, so that a `Socks5Error::Timeout` tells e.g. a
+/// proxy slow to authenticate from a target slow to connect. Unset phases are only limited by
+/// `Socks5Config::handshake_timeout`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhaseTimeouts {
+    /// The greeting and the proxy's method selection.
+    pub method_selection: Option<Duration>,
+    /// The method-dependent sub-negotiation, e.g. authentication.
+    pub sub_negotiation: Option<Duration>,
+    /// The request and its reply, which waits for the proxy to reach the target.
+    pub request: Option<Duration>,
+}
+
+impl PhaseTimeouts {
+    pub fn get(&self, phase: HandshakePhase) -> Option<Duration> {
+        match phase {
+            HandshakePhase::MethodSelection => self.method_selection,
+            HandshakePhase::SubNegotiation => self.sub_negotiation,
+            HandshakePhase::Request => self.request,
+            // Only limited by the deadline of `connect_with_deadline`.
+            HandshakePhase::Connect => None,
+            // Limited by `Socks5Config::accept_timeout` instead.
+            HandshakePhase::Accept => None,
+        }
+    }
+}
+
+/// Options of the TCP connection to the proxy. Unset options keep the defaults of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm, like `TcpStream::set_nodelay`.
+    pub nodelay: bool,
+    /// The IP time-to-live of outgoing packets.
+    pub ttl: Option<u32>,
+    /// Send keepalive probes once the connection has been idle for this long.
+    pub keepalive: Option<Duration>,
+    /// Send the greeting in the SYN with TCP Fast Open, saving a round trip once the proxy has
+    /// handed out a cookie. Only supported on Linux; elsewhere, or if the kernel doesn't support
+    /// it, the connection is made as usual.
+    #[cfg(feature = "tcp-fastopen")]
+    pub fast_open: bool,
+}
+
+impl SocketOptions {
+    pub(crate) fn apply(&self, socket: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl)?;
+        }
+        if self.keepalive.is_some() {
+            set_keepalive(socket, self.keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run `fut`, failing with `Socks5Error::Cancelled` as soon as `cancellation` is cancelled.
+pub(crate) async fn cancellable<T, F>(cancellation: Option<&CancellationToken>, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match cancellation {
+        Some(cancellation) => tokio::select! {
+            _ = cancellation.cancelled() => Err(Socks5Error::Cancelled),
+            result = fut => result,
+        },
+        None => fut.await,
+    }
+}
+
+/// Have the first write on `socket` sent in its SYN (`TCP_FASTOPEN_CONNECT`), if the kernel
+/// supports it.
+#[cfg(feature = "tcp-fastopen")]
+pub(crate) fn set_fast_open(socket: &TcpSocket) {
+    #[cfg(target_os = "linux")]
+    {
+        let enable: libc::c_int = 1;
+        // Safety: `enable` outlives the call and its size is passed along. Failures are ignored,
+        // leaving a regular connection.
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN_CONNECT,
+                &enable as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = socket;
+}
+
+/// Keepalive probes of a TCP connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// How long the connection stays idle before the first probe.
+    pub time: Duration,
+    /// The delay between unanswered probes.
+    pub interval: Option<Duration>,
+    /// How many unanswered probes drop the connection.
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    pub fn new(time: Duration) -> Self {
+        Self {
+            time,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    /// Send probes on `socket`. `interval` and `retries` are left to the system on platforms that
+    /// can't set them.
+    pub(crate) fn apply(&self, socket: &TcpStream) -> io::Result<()> {
+        let keepalive = TcpKeepalive::new().with_time(self.time);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        let keepalive = match self.interval {
+            Some(interval) => keepalive.with_interval(interval),
+            None => keepalive,
+        };
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        let keepalive = match self.retries {
+            Some(retries) => keepalive.with_retries(retries),
+            None => keepalive,
+        };
+        SockRef::from(socket).set_tcp_keepalive(&keepalive)
+    }
+}
+
+/// Send keepalive probes on `socket` once it has been idle for `time`, or stop sending them.
+pub(crate) fn set_keepalive(socket: &TcpStream, time: Option<Duration>) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    match time {
+        Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time)),
+        None => socket.set_keepalive(false),
+    }
+}
@@ -0,0 +1,937 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::io::{self, IoSlice};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{ready, Context, Poll, Wake, Waker};
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWriteExt, Interest, ReadBuf, Ready};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpSocket, TcpStream, ToSocketAddrs, UdpSocket};
+
+use crate::client::Socks5Client;
+use crate::limit;
+#[cfg(target_os = "linux")]
+use crate::mmsg;
+use crate::proto::{Request, RequestType};
+use crate::{
+    BoxFuture, BufferPool, DnsCache, Method, PooledBuf, ProxyUrl, Resolution, Result, SessionId,
+    SessionPermit, Socks5Config, Socks5Error, TargetAddr, TargetPolicy,
+};
+
+/// End-to-end protection of datagram payloads, e.g. encryption, a MAC or padding, applied before
+/// the SOCKS UDP header is added on send and after it is parsed on receive.
+pub trait DatagramTransform: Send + Sync {
+    /// Transform an outgoing payload.
+    fn seal(&self, payload: &[u8]) -> Result<Vec<u8>>;
+
+    /// Reverse `seal` on an incoming payload, failing with `Socks5Error::DatagramRejected` if it
+    /// doesn't verify.
+    fn open(&self, payload: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl fmt::Debug for dyn DatagramTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DatagramTransform")
+    }
+}
+
+pub trait AsyncDatagram {
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>>;
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>>;
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>>;
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>>;
+
+    /// Send `packets` to `target`, returning how many of them were sent. The default sends only
+    /// the first one.
+    fn poll_send_many(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[IoSlice<'_>],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        match packets.first() {
+            Some(packet) => self.poll_send_to(cx, packet, target).map_ok(|_| 1),
+            None => Poll::Ready(Ok(0)),
+        }
+    }
+
+    /// Receive datagrams into the first buffers of `bufs`, one per buffer, returning their
+    /// sources. The default receives only one.
+    fn poll_recv_many(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        match bufs.first_mut() {
+            Some(buf) => self.poll_recv_from(cx, buf).map_ok(|source| vec![source]),
+            None => Poll::Ready(Ok(Vec::new())),
+        }
+    }
+
+    /// The local address datagrams are sent from, if the datagram has one.
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "no local address").into())
+    }
+}
+
+// Move the datagram in `bufs[from]` to the earlier `bufs[to]`, truncating it if it doesn't fit.
+pub(crate) fn move_datagram(bufs: &mut [ReadBuf<'_>], from: usize, to: usize) {
+    let (front, back) = bufs.split_at_mut(from);
+    let (dst, src) = (&mut front[to], &back[0]);
+    dst.clear();
+    let len = dst.remaining().min(src.filled().len());
+    dst.put_slice(&src.filled()[..len]);
+}
+
+impl AsyncDatagram for UdpSocket {
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send_ready(cx).map_err(|e| e.into())
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_recv_ready(cx).map_err(|e| e.into())
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.poll_send_to(cx, buf, SocketAddr::try_from(target)?)
+            .map_err(|e| e.into())
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        self.poll_recv_from(cx, buf)
+            .map_err(|e| e.into())
+            .map(|x| x.map(TargetAddr::Ip))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_send_many(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[IoSlice<'_>],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        let target = SocketAddr::try_from(target)?;
+        loop {
+            ready!(self.poll_send_ready(cx))?;
+            match self.try_io(Interest::WRITABLE, || mmsg::send(self, packets, target)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result.map_err(|e| e.into())),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_recv_many(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        loop {
+            ready!(self.poll_recv_ready(cx))?;
+            match self.try_io(Interest::READABLE, || mmsg::recv(self, bufs)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => {
+                    let sources = result?;
+                    return Poll::Ready(Ok(sources.into_iter().map(TargetAddr::Ip).collect()));
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(UdpSocket::local_addr(self)?)
+    }
+}
+
+#[pin_project]
+pub struct SendTo<'a> {
+    #[pin]
+    buf: &'a [u8],
+    target: &'a TargetAddr,
+    inner: &'a dyn AsyncDatagram,
+}
+
+pub struct RecvFrom<'a> {
+    buf: ReadBuf<'a>,
+    inner: &'a dyn AsyncDatagram,
+}
+
+impl<'a> Future for SendTo<'a> {
+    type Output = Result<usize>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.poll_send_to(cx, self.buf, self.target)
+    }
+}
+
+impl<'a> Future for RecvFrom<'a> {
+    type Output = Result<(usize, TargetAddr)>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let addr = ready!(this.inner.poll_recv_from(cx, &mut this.buf))?;
+        Poll::Ready(Ok((this.buf.filled().len(), addr)))
+    }
+}
+
+pub trait AsyncDatagramExt {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: &'a TargetAddr) -> SendTo<'a>;
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFrom<'a>;
+}
+
+impl<T> AsyncDatagramExt for T
+where
+    T: AsyncDatagram,
+{
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: &'a TargetAddr) -> SendTo<'a> {
+        SendTo {
+            buf,
+            target,
+            inner: self,
+        }
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFrom<'a> {
+        RecvFrom {
+            buf: ReadBuf::new(buf),
+            inner: self,
+        }
+    }
+}
+
+// Large enough for any UDP payload.
+pub(crate) const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A UDP association through a SOCKS5 proxy.
+///
+/// Like `UdpSocket`, sending and receiving take `&self`, so one task can send while another
+/// receives, e.g. with the datagram shared through an `Arc`.
+pub struct Socks5Datagram<M> {
+    // Only locked for the length of a poll, never across an await.
+    client: Mutex<Socks5Client<M>>,
+    resolution: Resolution,
+    dns_cache: Option<DnsCache>,
+    target_policy: Option<TargetPolicy>,
+    // Reused by `recv_from_bytes`: every datagram is split off the front of it, so a new
+    // allocation is only needed once the returned `Bytes` have used up its capacity.
+    recv_buf: Mutex<BytesMut>,
+    // Where `recv_from_pooled` takes its buffers.
+    pool: BufferPool,
+    association: Arc<Association>,
+    // The relay announced in the reply to UDP ASSOCIATE.
+    relay_addr: TargetAddr,
+    // The default peer set by `connect`, already resolved and checked.
+    peer: Option<TargetAddr>,
+}
+
+// Watches the control connection on behalf of every task receiving on the association. The
+// connection only keeps the waker of the last poll, so it is polled with this one instead, which
+// wakes all the receivers registered since.
+#[derive(Default)]
+struct Association {
+    // Whether the proxy closed the control connection.
+    terminated: AtomicBool,
+    receivers: Mutex<Vec<Waker>>,
+}
+
+impl Association {
+    fn register(&self, waker: &Waker) {
+        let mut receivers = self
+            .receivers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !receivers.iter().any(|receiver| receiver.will_wake(waker)) {
+            receivers.push(waker.clone());
+        }
+    }
+}
+
+impl Wake for Association {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let receivers = std::mem::take(
+            &mut *self
+                .receivers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        for receiver in receivers {
+            receiver.wake();
+        }
+    }
+}
+
+impl<M> Socks5Datagram<M> {
+    pub fn session_id(&self) -> SessionId {
+        self.lock_client().session_id()
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// Choose whether domain targets passed to `send_to` are resolved locally or by the proxy.
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        self.resolution = resolution;
+    }
+
+    /// The address of the relay, as announced by the proxy in its reply to UDP ASSOCIATE. An
+    /// unspecified address is replaced by the address of the proxy the control connection was
+    /// made to, which is where the datagrams are sent. It is kept as replied when associating over
+    /// a socket passed to `bind_with_socket_datagram_and_config` or `bind_with_socket_and_config`.
+    pub fn relay_addr(&self) -> &TargetAddr {
+        &self.relay_addr
+    }
+
+    /// The local address of the socket exchanging datagrams with the relay.
+    pub fn local_addr(&self) -> Result<SocketAddr>
+    where
+        M: Method,
+    {
+        self.lock_client().local_addr()
+    }
+
+    /// The peer set by `connect`, as sent to the proxy.
+    pub fn peer_addr(&self) -> Option<TargetAddr> {
+        self.peer.clone()
+    }
+
+    pub(crate) fn check_target(&self, target: &TargetAddr) -> Result<()> {
+        match &self.target_policy {
+            Some(policy) => policy.check(target),
+            None => Ok(()),
+        }
+    }
+
+    fn lock_client(&self) -> MutexGuard<'_, Socks5Client<M>> {
+        self.client.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<M> Socks5Datagram<M>
+where
+    M: Method,
+{
+    pub async fn bind_with_socket_and_datagram(
+        socket: M::Stream,
+        datagram: M::Datagram,
+    ) -> Result<Self> {
+        Self::bind_with_socket_datagram_and_config(socket, datagram, Socks5Config::default()).await
+    }
+
+    pub async fn bind_with_socket_datagram_and_config(
+        socket: M::Stream,
+        datagram: M::Datagram,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        Self::associate_inner(socket, datagram, config, None).await
+    }
+
+    // Associate over `socket`, connected to the proxy at `proxy_ip` if it is known.
+    async fn associate_inner(
+        socket: M::Stream,
+        datagram: M::Datagram,
+        config: Socks5Config,
+        proxy_ip: Option<IpAddr>,
+    ) -> Result<Self> {
+        let resolution = config.resolution;
+        let dns_cache = config.dns_cache.clone();
+        let target_policy = config.target_policy.clone();
+        let pool = config.buffer_pool.clone().unwrap_or_default();
+        let mut client: Socks5Client<M> = Socks5Client::connect(socket, config).await?;
+
+        let dst = TargetAddr::Ip(SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0));
+
+        let relay_addr = client
+            .send_request(Request::new(RequestType::UdpAssociate, dst))
+            .await?;
+        let relay_addr = relay_at(relay_addr, proxy_ip);
+
+        client
+            .register_endpoints(datagram, relay_addr.clone())
+            .await?;
+
+        Ok(Self {
+            client: Mutex::new(client),
+            resolution,
+            dns_cache,
+            target_policy,
+            recv_buf: Mutex::new(BytesMut::new()),
+            pool,
+            association: Arc::default(),
+            relay_addr,
+            peer: None,
+        })
+    }
+
+    /// Fails with `Socks5Error::AssociationTerminated` once the proxy closed the control
+    /// connection.
+    pub async fn send_to(&self, buf: &[u8], addr: TargetAddr) -> Result<usize> {
+        let addr = self.resolve_target(addr).await?;
+        self.send_resolved(buf, addr).await
+    }
+
+    /// Set the default peer of `send` and `recv`, like `UdpSocket::connect`. The target is
+    /// resolved and checked against the policy once, here.
+    pub async fn connect(&mut self, target: TargetAddr) -> Result<()> {
+        self.peer = Some(self.resolve_target(target).await?);
+        Ok(())
+    }
+
+    /// Send a datagram to the peer set by `connect`.
+    pub async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let peer = self.peer.clone().ok_or(Socks5Error::NotConnected)?;
+        self.send_resolved(buf, peer).await
+    }
+
+    /// Receive a datagram from the peer set by `connect`, dropping datagrams from any other
+    /// source. Returns the length of the payload.
+    ///
+    /// The relay reports the source of a domain peer resolved by the proxy as an IP address, so
+    /// such peers are only matched by port.
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let peer = self.peer.clone().ok_or(Socks5Error::NotConnected)?;
+        loop {
+            let (len, source) = self.recv_from(buf).await?;
+            if from_peer(&source, &peer) {
+                return Ok(len);
+            }
+        }
+    }
+
+    async fn resolve_target(&self, target: TargetAddr) -> Result<TargetAddr> {
+        self.check_target(&target)?;
+        let family = self.lock_client().config().address_family;
+        let target = self
+            .resolution
+            .resolve_with_family(target, self.dns_cache.as_ref(), family)
+            .await?;
+        self.check_target(&target)?;
+        Ok(target)
+    }
+
+    async fn send_resolved(&self, buf: &[u8], addr: TargetAddr) -> Result<usize> {
+        let packets = self.pack(buf, &addr)?;
+        let mut sent = 0;
+        poll_fn(|cx| self.poll_send_packets(cx, &packets, &addr, &mut sent)).await?;
+        Ok(buf.len())
+    }
+
+    /// Send several datagrams, packing all of their headers first and then handing as many
+    /// packets as the socket takes to each system call (`sendmmsg` on Linux). Returns how many
+    /// datagrams were sent.
+    pub async fn send_many<B: AsRef<[u8]>>(&self, datagrams: &[(B, TargetAddr)]) -> Result<usize> {
+        let mut packets = Vec::with_capacity(datagrams.len());
+        for (buf, addr) in datagrams {
+            let addr = self.resolve_target(addr.clone()).await?;
+            packets.extend(self.pack(buf.as_ref(), &addr)?);
+        }
+
+        // The packets all go to the relay, whatever their targets.
+        let mut sent = 0;
+        poll_fn(|cx| self.poll_send_packets(cx, &packets, &self.relay_addr, &mut sent)).await?;
+        Ok(datagrams.len())
+    }
+
+    // Like `resolve_target` once the target passed the policy, without borrowing the datagram.
+    // `None` if the target isn't resolved locally.
+    pub(crate) fn resolve_detached(
+        &self,
+        target: &TargetAddr,
+    ) -> Option<BoxFuture<Result<TargetAddr>>> {
+        if !matches!(
+            (self.resolution, target),
+            (Resolution::Local, TargetAddr::Domain(..))
+        ) {
+            return None;
+        }
+
+        let (resolution, target) = (self.resolution, target.clone());
+        let (cache, policy) = (self.dns_cache.clone(), self.target_policy.clone());
+        let family = self.lock_client().config().address_family;
+        Some(Box::pin(async move {
+            let target = resolution
+                .resolve_with_family(target, cache.as_ref(), family)
+                .await?;
+            if let Some(policy) = &policy {
+                policy.check(&target)?;
+            }
+            Ok(target)
+        }))
+    }
+
+    // Build the packets carrying `buf` to a target already resolved and checked, once per
+    // datagram however many polls sending them takes.
+    pub(crate) fn pack(&self, buf: &[u8], addr: &TargetAddr) -> Result<Vec<Vec<u8>>> {
+        self.poll_association(None)?;
+        self.lock_client().pack_datagram(addr, buf)
+    }
+
+    pub(crate) fn poll_send_packets(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[Vec<u8>],
+        addr: &TargetAddr,
+        sent: &mut usize,
+    ) -> Poll<Result<()>> {
+        self.lock_client()
+            .poll_send_packets(cx, packets, addr, sent)
+    }
+
+    #[cfg(feature = "quinn")]
+    pub(crate) fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.lock_client().poll_send_ready(cx)
+    }
+
+    /// Receive a datagram into `buf`, returning the length of its payload and its source. A
+    /// payload longer than `buf` is truncated and reported as `Socks5Error::DatagramTruncated`.
+    ///
+    /// Fails with `Socks5Error::AssociationTerminated` once the proxy closed the control
+    /// connection, including while waiting for a datagram.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, TargetAddr)> {
+        let mut buf = ReadBuf::new(buf);
+        let addr = poll_fn(|cx| self.poll_recv_from(cx, &mut buf)).await?;
+        Ok((buf.filled().len(), addr))
+    }
+
+    /// Receive datagrams into the first buffers of `bufs`, one per buffer, with as few system
+    /// calls as the socket allows (`recvmmsg` on Linux). Returns the length of the payload and the
+    /// source of each datagram received, waiting for at least one.
+    ///
+    /// Every packet is received whole before its header is stripped, so the buffers need room for
+    /// the SOCKS header as well: payloads are truncated otherwise.
+    pub async fn recv_many<B: AsMut<[u8]>>(
+        &self,
+        bufs: &mut [B],
+    ) -> Result<Vec<(usize, TargetAddr)>> {
+        let mut bufs: Vec<ReadBuf<'_>> = bufs
+            .iter_mut()
+            .map(|buf| ReadBuf::new(buf.as_mut()))
+            .collect();
+        let targets = poll_fn(|cx| {
+            self.poll_association(Some(cx))?;
+            self.lock_client().poll_recv_packets(cx, &mut bufs)
+        })
+        .await?;
+
+        Ok(bufs
+            .iter()
+            .zip(targets)
+            .map(|(buf, target)| (buf.filled().len(), target))
+            .collect())
+    }
+
+    pub(crate) fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        self.poll_association(Some(cx))?;
+        self.lock_client().poll_recv_from(cx, buf)
+    }
+
+    pub async fn send_to_bytes(&self, buf: Bytes, addr: TargetAddr) -> Result<usize> {
+        self.send_to(&buf, addr).await
+    }
+
+    /// Receive a datagram into a buffer owned by the socket, returning it as `Bytes`.
+    pub async fn recv_from_bytes(&self) -> Result<(Bytes, TargetAddr)> {
+        poll_fn(|cx| {
+            let mut recv_buf = self.recv_buf.lock().unwrap_or_else(PoisonError::into_inner);
+            recv_buf.resize(RECV_BUFFER_SIZE, 0);
+            let mut buf = ReadBuf::new(&mut recv_buf);
+            let addr = ready!(self.poll_recv_from(cx, &mut buf))?;
+
+            let len = buf.filled().len();
+            Poll::Ready(Ok((recv_buf.split_to(len).freeze(), addr)))
+        })
+        .await
+    }
+
+    /// Receive a datagram into a buffer taken from the pool of the socket, which gets it back
+    /// once the `PooledBuf` is dropped. The payload is left where it was received rather than
+    /// copied out from behind the SOCKS header.
+    pub async fn recv_from_pooled(&self) -> Result<(PooledBuf, TargetAddr)> {
+        let mut buf = self.pool.take();
+        let (payload, addr) = poll_fn(|cx| {
+            self.poll_association(Some(cx))?;
+            self.lock_client().poll_recv_packet(cx, &mut buf)
+        })
+        .await?;
+        Ok((PooledBuf::new(buf, payload, &self.pool), addr))
+    }
+
+    /// Wait for any of the requested readiness states, like `UdpSocket::ready`.
+    pub async fn ready(&self, interest: Interest) -> Result<Ready> {
+        poll_fn(|cx| {
+            let mut ready = Ready::EMPTY;
+
+            if interest.is_readable() {
+                if let Poll::Ready(result) = self.lock_client().poll_recv_ready(cx) {
+                    result?;
+                    ready |= Ready::READABLE;
+                }
+            }
+
+            if interest.is_writable() {
+                if let Poll::Ready(result) = self.lock_client().poll_send_ready(cx) {
+                    result?;
+                    ready |= Ready::WRITABLE;
+                }
+            }
+
+            if ready.is_empty() {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(ready))
+            }
+        })
+        .await
+    }
+
+    pub async fn readable(&self) -> Result<()> {
+        self.ready(Interest::READABLE).await.map(|_| ())
+    }
+
+    pub async fn writable(&self) -> Result<()> {
+        self.ready(Interest::WRITABLE).await.map(|_| ())
+    }
+
+    /// Give back the control connection and the UDP socket. The association lasts as long as the
+    /// control connection stays open.
+    pub fn into_inner(self) -> (M::Stream, M::Datagram) {
+        let client = self
+            .client
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        match client.into_parts() {
+            (stream, Some(datagram)) => (stream, datagram),
+            (_, None) => unreachable!("the datagram socket is registered with the association"),
+        }
+    }
+
+    /// Shut the control connection down, which ends the association on the proxy, and release
+    /// the UDP socket. Dropping the datagram does the same without reporting the outcome.
+    ///
+    /// Succeeds without doing anything if the proxy already terminated the association.
+    pub async fn close(self) -> Result<()> {
+        if self.association.terminated.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let mut client = self
+            .client
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        client.shutdown().await?;
+        Ok(())
+    }
+
+    // Check the control connection, whose closure by the proxy terminates the association
+    // (RFC 1928). Receivers pass their context to be woken when that happens. Anything else the
+    // proxy sends on it is discarded.
+    fn poll_association(&self, cx: Option<&Context<'_>>) -> Result<()> {
+        if let Some(cx) = cx {
+            self.association.register(cx.waker());
+        }
+        let waker = Waker::from(self.association.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut client = self.lock_client();
+        let mut discard = [0; 64];
+        while !self.association.terminated.load(Ordering::Acquire) {
+            let mut buf = ReadBuf::new(&mut discard);
+            match Pin::new(&mut *client).poll_read(&mut cx, &mut buf) {
+                Poll::Pending => return Ok(()),
+                Poll::Ready(Ok(())) if !buf.filled().is_empty() => {}
+                Poll::Ready(_) => self.association.terminated.store(true, Ordering::Release),
+            }
+        }
+        Err(Socks5Error::AssociationTerminated)
+    }
+}
+
+// The relay announced as `relay`, reached at `proxy_ip` if its address is unspecified.
+fn relay_at(relay: TargetAddr, proxy_ip: Option<IpAddr>) -> TargetAddr {
+    match (relay, proxy_ip) {
+        (TargetAddr::Ip(relay), Some(ip)) if relay.ip().is_unspecified() => {
+            TargetAddr::Ip(SocketAddr::new(ip.to_canonical(), relay.port()))
+        }
+        (relay, _) => relay,
+    }
+}
+
+fn from_peer(source: &TargetAddr, peer: &TargetAddr) -> bool {
+    match (source, peer) {
+        (TargetAddr::Ip(source), TargetAddr::Ip(peer)) => {
+            source.ip().to_canonical() == peer.ip().to_canonical() && source.port() == peer.port()
+        }
+        (TargetAddr::Domain(source, source_port), TargetAddr::Domain(peer, peer_port)) => {
+            source.eq_ignore_ascii_case(peer) && source_port == peer_port
+        }
+        (TargetAddr::Ip(source), TargetAddr::Domain(_, port)) => source.port() == *port,
+        (TargetAddr::Domain(..), TargetAddr::Ip(_)) => false,
+    }
+}
+
+impl<M> Socks5Datagram<M>
+where
+    M: Method<Datagram = UdpSocket>,
+{
+    pub async fn bind_with_socket<A: ToSocketAddrs>(socket: M::Stream, addr: A) -> Result<Self> {
+        Self::bind_with_socket_and_config(socket, addr, Socks5Config::default()).await
+    }
+
+    pub async fn bind_with_socket_and_config<A: ToSocketAddrs>(
+        socket: M::Stream,
+        addr: A,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        Self::bind_inner(socket, addr, config, None).await
+    }
+
+    // Associate over `socket`, connected to the proxy at `proxy_ip` if it is known, from a UDP
+    // socket bound to `addr`.
+    async fn bind_inner<A: ToSocketAddrs>(
+        socket: M::Stream,
+        addr: A,
+        config: Socks5Config,
+        proxy_ip: Option<IpAddr>,
+    ) -> Result<Self> {
+        let udp_socket = UdpSocket::bind(addr).await?;
+        config.bind_to_device(&udp_socket)?;
+        Self::associate_inner(socket, udp_socket, config, proxy_ip).await
+    }
+}
+
+#[cfg(unix)]
+impl<M> Socks5Datagram<M>
+where
+    M: Method<Stream = UnixStream, Datagram = UdpSocket>,
+{
+    pub async fn associate_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::associate_unix_with_config(path, Socks5Config::default()).await
+    }
+
+    /// Associate through the proxy listening on the Unix socket at `path`, from a UDP socket
+    /// bound to the loopback address, where the proxy relays the datagrams of such clients.
+    pub async fn associate_unix_with_config<P: AsRef<Path>>(
+        path: P,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let socket = UnixStream::connect(path).await?;
+        Self::bind_with_socket_and_config(socket, (Ipv4Addr::LOCALHOST, 0), config).await
+    }
+}
+
+impl<M> Socks5Datagram<M>
+where
+    M: Method<Stream = TcpStream, Datagram = UdpSocket>,
+{
+    pub async fn bind<A: ToSocketAddrs, B: ToSocketAddrs>(addr: A, bind: B) -> Result<Self> {
+        Self::bind_with_config(addr, bind, Socks5Config::default()).await
+    }
+
+    pub async fn bind_with_config<A: ToSocketAddrs, B: ToSocketAddrs>(
+        addr: A,
+        bind: B,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let (socket, permit) = limit::connect(addr, &config).await?;
+
+        Self::bind_over(socket, permit, bind, config).await
+    }
+
+    pub async fn bind_with_deadline<A: ToSocketAddrs, B: ToSocketAddrs>(
+        addr: A,
+        bind: B,
+        deadline: Instant,
+    ) -> Result<Self> {
+        Self::bind_with_deadline_and_config(addr, bind, deadline, Socks5Config::default()).await
+    }
+
+    /// Like `bind_with_config`, failing with `Socks5Error::Timeout` if the association isn't
+    /// established by `deadline`.
+    pub async fn bind_with_deadline_and_config<A: ToSocketAddrs, B: ToSocketAddrs>(
+        addr: A,
+        bind: B,
+        deadline: Instant,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let (socket, permit) = limit::connect_until(addr, deadline, &config).await?;
+        let config = config.until(deadline);
+
+        Self::bind_over(socket, permit, bind, config).await
+    }
+
+    pub async fn bind_with_tcp_socket<B: ToSocketAddrs>(
+        socket: TcpSocket,
+        addr: SocketAddr,
+        bind: B,
+    ) -> Result<Self> {
+        Self::bind_with_tcp_socket_and_config(socket, addr, bind, Socks5Config::default()).await
+    }
+
+    /// Like `bind_with_config`, opening the control connection with the preconfigured `socket`.
+    pub async fn bind_with_tcp_socket_and_config<B: ToSocketAddrs>(
+        socket: TcpSocket,
+        addr: SocketAddr,
+        bind: B,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let (socket, permit) = limit::connect_socket(socket, addr, &config).await?;
+
+        Self::bind_over(socket, permit, bind, config).await
+    }
+
+    pub async fn bind_with_url<B: ToSocketAddrs>(url: &ProxyUrl, bind: B) -> Result<Self> {
+        Self::bind_with_url_and_config(url, bind, Socks5Config::default()).await
+    }
+
+    /// Associate through the proxy of `url`, whose resolution and credentials override `config`.
+    pub async fn bind_with_url_and_config<B: ToSocketAddrs>(
+        url: &ProxyUrl,
+        bind: B,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let config = url.configure(&config);
+        let (socket, permit) = url.connect(&config).await?;
+
+        Self::bind_over(socket, permit, bind, config).await
+    }
+
+    pub async fn associate<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Self::associate_with_config(addr, Socks5Config::default()).await
+    }
+
+    /// Like `bind_with_config`, binding the UDP socket to the local address of the control
+    /// connection with an ephemeral port, so that datagrams leave through the interface that
+    /// reaches the proxy.
+    pub async fn associate_with_config<A: ToSocketAddrs>(
+        addr: A,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let (socket, permit) = limit::connect(addr, &config).await?;
+        Self::associate_over(socket, permit, config).await
+    }
+
+    pub async fn associate_with_url(url: &ProxyUrl) -> Result<Self> {
+        Self::associate_with_url_and_config(url, Socks5Config::default()).await
+    }
+
+    /// Like `associate_with_config` through the proxy of `url`, whose resolution and credentials
+    /// override `config`.
+    pub async fn associate_with_url_and_config(
+        url: &ProxyUrl,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let config = url.configure(&config);
+        let (socket, permit) = url.connect(&config).await?;
+        Self::associate_over(socket, permit, config).await
+    }
+
+    async fn associate_over(
+        socket: TcpStream,
+        permit: Option<SessionPermit>,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let bind = SocketAddr::new(socket.local_addr()?.ip(), 0);
+        Self::bind_over(socket, permit, bind, config).await
+    }
+
+    // Associate over a connection to the proxy opened for the datagram, which stays idle for as
+    // long as the association lasts.
+    async fn bind_over<B: ToSocketAddrs>(
+        socket: TcpStream,
+        permit: Option<SessionPermit>,
+        bind: B,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        if let Some(keepalive) = &config.control_keepalive {
+            keepalive.apply(&socket)?;
+        }
+        let proxy_ip = socket.peer_addr()?.ip();
+        let datagram = Self::bind_inner(socket, bind, config, Some(proxy_ip)).await?;
+        Ok(datagram.holding(permit))
+    }
+
+    fn holding(mut self, permit: Option<SessionPermit>) -> Self {
+        self.client
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .hold(permit);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{NoAuthentication, VERSION};
+
+    #[tokio::test]
+    async fn reaches_an_unspecified_relay_at_the_proxy() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = relay.local_addr().unwrap().port().to_be_bytes();
+        tokio::spawn(async move {
+            let (mut socket, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[VERSION, 0x00]).await.unwrap();
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            let reply = [VERSION, 0x00, 0x00, 0x01, 0, 0, 0, 0, port[0], port[1]];
+            socket.write_all(&reply).await.unwrap();
+            let _ = socket.read(&mut [0; 1]).await;
+        });
+
+        let datagram = Socks5Datagram::<NoAuthentication<TcpStream>>::associate(addr)
+            .await
+            .unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        assert_eq!(datagram.relay_addr(), &TargetAddr::Ip(relay_addr));
+
+        let target = TargetAddr::Ip("192.0.2.1:53".parse().unwrap());
+        datagram.send_to(b"ping", target).await.unwrap();
+        let mut buf = [0; 64];
+        let (len, _) = relay.recv_from(&mut buf).await.unwrap();
+        assert!(buf[..len].ends_with(b"ping"));
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use tokio::net::lookup_host;
+
+/// A cache of DNS lookups shared by the sessions of a `Socks5Config`, so that bursts of proxied
+/// dials don't each hit the system resolver.
+///
+/// The system resolver doesn't report record TTLs, so answers are kept for a fixed `ttl` and
+/// failures for `negative_ttl`. Clones share their entries.
+#[derive(Clone)]
+pub struct DnsCache {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+}
+
+struct Entry {
+    // `None` records a failed lookup.
+    addrs: Option<Arc<[IpAddr]>>,
+    expires_at: Instant,
+}
+
+impl DnsCache {
+    pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+    pub const DEFAULT_MAX_ENTRIES: usize = 1024;
+
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Default::default(),
+            ttl,
+            negative_ttl: Self::DEFAULT_NEGATIVE_TTL,
+            max_entries: Self::DEFAULT_MAX_ENTRIES,
+        }
+    }
+
+    /// Keep failed lookups for `ttl`, or not at all if it is zero.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Keep at most `max_entries` hosts, evicting the ones closest to expiry first.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Resolve `host`, answering from the cache while the entry is fresh.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let key = host.to_ascii_lowercase();
+        if let Some(entry) = self.lock().get(&key) {
+            if entry.expires_at > Instant::now() {
+                return match &entry.addrs {
+                    Some(addrs) => Ok(with_port(addrs, port)),
+                    None => Err(not_found(host)),
+                };
+            }
+        }
+
+        match lookup_host((host, port)).await {
+            Ok(addrs) => {
+                let addrs: Arc<[IpAddr]> = addrs.map(|addr| addr.ip()).collect();
+                self.insert(key, Some(addrs.clone()), self.ttl);
+                Ok(with_port(&addrs, port))
+            }
+            Err(e) => {
+                self.insert(key, None, self.negative_ttl);
+                Err(e)
+            }
+        }
+    }
+
+    fn insert(&self, key: String, addrs: Option<Arc<[IpAddr]>>, ttl: Duration) {
+        if ttl.is_zero() || self.max_entries == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.lock();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        while entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                None => break,
+            };
+        }
+
+        entries.insert(
+            key,
+            Entry {
+                addrs,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for DnsCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsCache")
+            .field("len", &self.len())
+            .field("ttl", &self.ttl)
+            .field("negative_ttl", &self.negative_ttl)
+            .field("max_entries", &self.max_entries)
+            .finish()
+    }
+}
+
+/// Resolve `host` through `cache` if there is one, or else straight through the system resolver.
+pub(crate) async fn lookup(
+    host: &str,
+    port: u16,
+    cache: Option<&DnsCache>,
+) -> io::Result<Vec<SocketAddr>> {
+    match cache {
+        Some(cache) => cache.lookup(host, port).await,
+        None => Ok(lookup_host((host, port)).await?.collect()),
+    }
+}
+
+fn with_port(addrs: &[IpAddr], port: u16) -> Vec<SocketAddr> {
+    addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect()
+}
+
+fn not_found(host: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("failed to resolve {} (cached)", host),
+    )
+}
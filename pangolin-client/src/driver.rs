@@ -0,0 +1,322 @@
+use std::convert::TryInto;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::Span;
+
+use crate::client::{
+    check_selection, encode_greeting, parse_reply, ReplyProgress, Socks5Client, MAX_REPLY_LEN,
+};
+use crate::proto::{Request, RequestType};
+use crate::{
+    BoxFuture, HandshakePhase, Method, Resolution, Result, SessionId, Socks5Config, Socks5Error,
+    Socks5Stream, TargetAddr,
+};
+
+/// Drives the negotiation of a `CONNECT` through `poll_handshake`, for custom reactors and
+/// hand-written futures that can't `.await`. It is also a `Future` itself.
+///
+/// The greeting, request and reply are driven directly with the parsers shared with
+/// `Socks5Stream`; only local resolution and the sub-negotiation of the method, which are async,
+/// are polled as boxed futures.
+pub struct HandshakeDriver<M: Method> {
+    target_addr: TargetAddr,
+    session: SessionId,
+    // Entered while polling.
+    span: Span,
+    state: State<M>,
+}
+
+enum State<M: Method> {
+    Resolve {
+        socket: M::Stream,
+        config: Socks5Config,
+        resolve: BoxFuture<Result<TargetAddr>>,
+    },
+    Greeting {
+        socket: M::Stream,
+        config: Socks5Config,
+        codes: Vec<u8>,
+        greeting: Vec<u8>,
+        written: usize,
+        selection: [u8; 2],
+        read: usize,
+    },
+    SubNegotiation(BoxFuture<Result<(M, Socks5Config)>>),
+    Request {
+        client: Socks5Client<M>,
+        request: Vec<u8>,
+        written: usize,
+    },
+    Reply {
+        client: Socks5Client<M>,
+        reply: Vec<u8>,
+        read: usize,
+    },
+    // The target was denied by the policy before anything was sent.
+    Denied(Socks5Error),
+    Done,
+}
+
+impl<M> HandshakeDriver<M>
+where
+    M: Method + 'static,
+    M::Stream: Send + 'static,
+{
+    pub fn new(socket: M::Stream, target_addr: TargetAddr) -> Self {
+        Self::with_config(socket, target_addr, Socks5Config::default())
+    }
+
+    pub fn with_config(socket: M::Stream, target_addr: TargetAddr, config: Socks5Config) -> Self {
+        let session = SessionId::next();
+        if let Err(e) = config.check_target(&target_addr) {
+            return Self {
+                target_addr,
+                session,
+                span: session.span(),
+                state: State::Denied(e),
+            };
+        }
+
+        let state = match (&config.resolution, &target_addr) {
+            (Resolution::Local, TargetAddr::Domain(..)) => {
+                let (resolution, cache, family) = (
+                    config.resolution,
+                    config.dns_cache.clone(),
+                    config.address_family,
+                );
+                let addr = target_addr.clone();
+                State::Resolve {
+                    socket,
+                    config,
+                    resolve: Box::pin(async move {
+                        resolution
+                            .resolve_with_family(addr, cache.as_ref(), family)
+                            .await
+                    }),
+                }
+            }
+            _ => Self::greeting(socket, config),
+        };
+        Self {
+            target_addr,
+            session,
+            span: session.span(),
+            state,
+        }
+    }
+
+    /// The target, resolved once local resolution completed.
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target_addr
+    }
+
+    /// Advance the negotiation as far as the socket allows, returning the established stream once
+    /// the proxy accepted the request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if polled again after it returned `Poll::Ready`.
+    pub fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<Result<Socks5Stream<M>>> {
+        let span = self.span.clone();
+        let result = ready!(span.in_scope(|| self.poll_state(cx)));
+        self.state = State::Done;
+        Poll::Ready(result.map_err(|e| e.in_session(self.session.as_u64())))
+    }
+
+    fn greeting(socket: M::Stream, config: Socks5Config) -> State<M> {
+        let codes = M::codes(&config);
+        State::Greeting {
+            socket,
+            config,
+            greeting: Vec::new(),
+            codes,
+            written: 0,
+            selection: [0; 2],
+            read: 0,
+        }
+    }
+
+    fn poll_state(&mut self, cx: &mut Context<'_>) -> Poll<Result<Socks5Stream<M>>> {
+        loop {
+            match &mut self.state {
+                State::Resolve {
+                    config, resolve, ..
+                } => {
+                    let target_addr = ready!(resolve.as_mut().poll(cx))?;
+                    config.check_target(&target_addr)?;
+                    self.target_addr = target_addr;
+                    if let State::Resolve { socket, config, .. } =
+                        mem::replace(&mut self.state, State::Done)
+                    {
+                        self.state = Self::greeting(socket, config);
+                    }
+                }
+                State::Greeting {
+                    socket,
+                    config,
+                    codes,
+                    greeting,
+                    written,
+                    selection,
+                    read,
+                } => {
+                    let code = ready!(poll_greeting(
+                        cx, socket, config, codes, greeting, written, selection, read
+                    ))
+                    .map_err(|e| e.during(HandshakePhase::MethodSelection))?;
+
+                    if let State::Greeting { socket, config, .. } =
+                        mem::replace(&mut self.state, State::Done)
+                    {
+                        self.state = State::SubNegotiation(Box::pin(async move {
+                            let mut method = M::create(socket, code, &config).await?;
+                            // Enter method dependent sub-negotiation phase
+                            method
+                                .handshake(&config)
+                                .await
+                                .map_err(|e| e.during(HandshakePhase::SubNegotiation))?;
+                            Ok((method, config))
+                        }));
+                    }
+                }
+                State::SubNegotiation(negotiate) => {
+                    let (method, config) = ready!(negotiate.as_mut().poll(cx))?;
+                    let request = Request::new(RequestType::Connect, self.target_addr.clone());
+                    self.state = State::Request {
+                        client: Socks5Client::new(method, config, self.session),
+                        request: request.try_into()?,
+                        written: 0,
+                    };
+                }
+                State::Request {
+                    client,
+                    request,
+                    written,
+                } => {
+                    ready!(poll_write_all(cx, client, request, written))
+                        .map_err(|e| Socks5Error::from(e).during(HandshakePhase::Request))?;
+
+                    if let State::Request { client, .. } =
+                        mem::replace(&mut self.state, State::Done)
+                    {
+                        self.state = State::Reply {
+                            client,
+                            reply: vec![0; MAX_REPLY_LEN],
+                            read: 0,
+                        };
+                    }
+                }
+                State::Reply {
+                    client,
+                    reply,
+                    read,
+                } => {
+                    let bound_addr = ready!(poll_reply(cx, client, reply, read))
+                        .map_err(|e| e.during(HandshakePhase::Request))?;
+
+                    if let State::Reply { client, .. } = mem::replace(&mut self.state, State::Done)
+                    {
+                        return Poll::Ready(Ok(Socks5Stream::new(
+                            client,
+                            self.target_addr.clone(),
+                            bound_addr,
+                        )));
+                    }
+                }
+                State::Denied(_) => {
+                    if let State::Denied(e) = mem::replace(&mut self.state, State::Done) {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+                State::Done => panic!("HandshakeDriver polled after completion"),
+            }
+        }
+    }
+}
+
+impl<M> Future for HandshakeDriver<M>
+where
+    M: Method + 'static,
+    M::Stream: Send + 'static,
+{
+    type Output = Result<Socks5Stream<M>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().poll_handshake(cx)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn poll_greeting<S: AsyncRead + AsyncWrite + Unpin>(
+    cx: &mut Context<'_>,
+    socket: &mut S,
+    config: &Socks5Config,
+    codes: &[u8],
+    greeting: &mut Vec<u8>,
+    written: &mut usize,
+    selection: &mut [u8; 2],
+    read: &mut usize,
+) -> Poll<Result<u8>> {
+    if greeting.is_empty() {
+        *greeting = encode_greeting(codes)?;
+    }
+    ready!(poll_write_all(cx, socket, greeting, written))?;
+    ready!(poll_read_exact(cx, socket, selection, read))?;
+    Poll::Ready(check_selection(*selection, codes, config))
+}
+
+fn poll_reply<M: Method>(
+    cx: &mut Context<'_>,
+    client: &mut Socks5Client<M>,
+    reply: &mut [u8],
+    read: &mut usize,
+) -> Poll<Result<TargetAddr>> {
+    loop {
+        match parse_reply(&reply[..*read], client.config())? {
+            ReplyProgress::Complete(bound_addr, _) => return Poll::Ready(Ok(bound_addr)),
+            ReplyProgress::Need(len) => {
+                ready!(poll_read_exact(cx, client, &mut reply[..len], read))?;
+            }
+        }
+    }
+}
+
+fn poll_write_all<W: AsyncWrite + Unpin>(
+    cx: &mut Context<'_>,
+    writer: &mut W,
+    buf: &[u8],
+    written: &mut usize,
+) -> Poll<io::Result<()>> {
+    while *written < buf.len() {
+        let n = ready!(Pin::new(&mut *writer).poll_write(cx, &buf[*written..]))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        *written += n;
+    }
+    Pin::new(writer).poll_flush(cx)
+}
+
+// Fill `buf` from `*read` on, keeping track of the progress across calls.
+fn poll_read_exact<R: AsyncRead + Unpin>(
+    cx: &mut Context<'_>,
+    reader: &mut R,
+    buf: &mut [u8],
+    read: &mut usize,
+) -> Poll<io::Result<()>> {
+    while *read < buf.len() {
+        let mut read_buf = ReadBuf::new(&mut buf[*read..]);
+        ready!(Pin::new(&mut *reader).poll_read(cx, &mut read_buf))?;
+        let n = read_buf.filled().len();
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        *read += n;
+    }
+    Poll::Ready(Ok(()))
+}
@@ -0,0 +1,247 @@
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::datagram::AsyncDatagram;
+use crate::{Method, NoAuthentication, Result, Socks5Config, TargetAddr, UsernamePassword};
+
+// The object-safe part of `Method`, implemented for every method once it has been created.
+#[async_trait]
+trait ErasedMethod<S, U>: AsyncRead + AsyncWrite + AsyncDatagram + Unpin + Send {
+    async fn handshake(&mut self, config: &Socks5Config) -> Result<()>;
+
+    async fn register_endpoints(&mut self, src: U, dst: TargetAddr) -> Result<()>;
+
+    fn encapsulates(&self) -> bool;
+
+    fn encapsulate(&mut self, data: &[u8]) -> Result<Vec<u8>>;
+
+    fn decapsulate(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>>;
+
+    fn encapsulate_datagram(&self, packet: Vec<u8>) -> Result<Vec<u8>>;
+
+    fn decapsulate_datagram(&self, packet: &[u8]) -> Result<Vec<u8>>;
+
+    fn get_ref(&self) -> &S;
+
+    fn get_mut(&mut self) -> &mut S;
+
+    fn into_parts(self: Box<Self>) -> (S, Option<U>);
+}
+
+#[async_trait]
+impl<M> ErasedMethod<M::Stream, M::Datagram> for M
+where
+    M: Method,
+    M::Datagram: Send + 'static,
+{
+    async fn handshake(&mut self, config: &Socks5Config) -> Result<()> {
+        Method::handshake(self, config).await
+    }
+
+    async fn register_endpoints(&mut self, src: M::Datagram, dst: TargetAddr) -> Result<()> {
+        Method::register_endpoints(self, src, dst).await
+    }
+
+    fn encapsulates(&self) -> bool {
+        Method::encapsulates(self)
+    }
+
+    fn encapsulate(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        Method::encapsulate(self, data)
+    }
+
+    fn decapsulate(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        Method::decapsulate(self, buf)
+    }
+
+    fn encapsulate_datagram(&self, packet: Vec<u8>) -> Result<Vec<u8>> {
+        Method::encapsulate_datagram(self, packet)
+    }
+
+    fn decapsulate_datagram(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        Method::decapsulate_datagram(self, packet)
+    }
+
+    fn get_ref(&self) -> &M::Stream {
+        Method::get_ref(self)
+    }
+
+    fn get_mut(&mut self) -> &mut M::Stream {
+        Method::get_mut(self)
+    }
+
+    fn into_parts(self: Box<Self>) -> (M::Stream, Option<M::Datagram>) {
+        Method::into_parts(*self)
+    }
+}
+
+/// A boxed method chosen at runtime from the configuration, so that the choice between no
+/// authentication and username/password doesn't have to be made at compile time.
+///
+/// Username/password is offered, ahead of no authentication, whenever credentials or a credential
+/// provider are configured.
+pub struct DynMethod<S = TcpStream, U = UdpSocket> {
+    inner: Box<dyn ErasedMethod<S, U>>,
+}
+
+impl<S, U> AsyncDatagram for DynMethod<S, U> {
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_send_ready(cx)
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_recv_ready(cx)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.inner.poll_send_to(cx, buf, target)
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        self.inner.poll_recv_from(cx, buf)
+    }
+
+    fn poll_send_many(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[IoSlice<'_>],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.inner.poll_send_many(cx, packets, target)
+    }
+
+    fn poll_recv_many(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        self.inner.poll_recv_many(cx, bufs)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl<S, U> AsyncRead for DynMethod<S, U> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S, U> AsyncWrite for DynMethod<S, U> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl<S, U> Method for DynMethod<S, U>
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send + 'static,
+    U: AsyncDatagram + Unpin + Send + 'static,
+{
+    type Stream = S;
+    type Datagram = U;
+
+    async fn create(socket: S, code: u8, config: &Socks5Config) -> Result<Self> {
+        let inner: Box<dyn ErasedMethod<S, U>> = match code {
+            0x02 => Box::new(UsernamePassword::<S, U>::create(socket, code, config).await?),
+            _ => Box::new(NoAuthentication::<S, U>::create(socket, code, config).await?),
+        };
+
+        Ok(Self { inner })
+    }
+
+    async fn handshake(&mut self, config: &Socks5Config) -> Result<()> {
+        self.inner.handshake(config).await
+    }
+
+    async fn register_endpoints(&mut self, src: Self::Datagram, dst: TargetAddr) -> Result<()> {
+        self.inner.register_endpoints(src, dst).await
+    }
+
+    fn codes(config: &Socks5Config) -> Vec<u8> {
+        if config.has_credentials() {
+            vec![0x02, 0x00]
+        } else {
+            vec![0x00]
+        }
+    }
+
+    fn encapsulates(&self) -> bool {
+        self.inner.encapsulates()
+    }
+
+    fn encapsulate(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.inner.encapsulate(data)
+    }
+
+    fn decapsulate(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        self.inner.decapsulate(buf)
+    }
+
+    fn encapsulate_datagram(&self, packet: Vec<u8>) -> Result<Vec<u8>> {
+        self.inner.encapsulate_datagram(packet)
+    }
+
+    fn decapsulate_datagram(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        self.inner.decapsulate_datagram(packet)
+    }
+
+    fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+
+    fn into_parts(self) -> (S, Option<U>) {
+        self.inner.into_parts()
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::{Result, Socks5Error, TargetAddr};
+
+/// How long a reassembly queue may wait for its remaining fragments, as recommended by RFC 1928.
+pub(crate) const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The high-order bit of FRAG marks the last fragment of a datagram, the others its position.
+const END_OF_SEQUENCE: u8 = 0x80;
+const MAX_FRAGMENTS: usize = 0x7f;
+
+// Bounds the memory held by sources that never complete their datagrams.
+const MAX_QUEUES: usize = 64;
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Split `data` into chunks of at most `size` bytes, each paired with its FRAG value.
+pub(crate) fn split(data: &[u8], size: usize) -> Result<Vec<(u8, &[u8])>> {
+    let chunks: Vec<&[u8]> = data.chunks(size.max(1)).collect();
+    if chunks.len() > MAX_FRAGMENTS {
+        return Err(Socks5Error::DatagramTooLarge {
+            limit: MAX_FRAGMENTS * size.max(1),
+            size: data.len(),
+        });
+    }
+
+    let last = chunks.len();
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let position = i as u8 + 1;
+            if i + 1 == last {
+                (position | END_OF_SEQUENCE, chunk)
+            } else {
+                (position, chunk)
+            }
+        })
+        .collect())
+}
+
+/// Reassembles fragmented datagrams relayed by the proxy, with a queue per source.
+///
+/// Following RFC 1928, fragments must arrive in order: one whose position is not past the last
+/// one processed restarts the queue, and a queue is dropped if it isn't complete within
+/// `REASSEMBLY_TIMEOUT`. A missing fragment drops the whole datagram.
+#[derive(Default)]
+pub(crate) struct Reassembler {
+    queues: HashMap<TargetAddr, Queue>,
+}
+
+struct Queue {
+    data: Vec<u8>,
+    position: u8,
+    expires_at: Instant,
+}
+
+impl Reassembler {
+    /// Queue the payload of fragment `frag` from `source`, returning the whole datagram once its
+    /// last fragment arrived.
+    pub fn push(
+        &mut self,
+        source: &TargetAddr,
+        frag: u8,
+        payload: &[u8],
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        let position = frag & !END_OF_SEQUENCE;
+
+        if let Some(queue) = self.queues.get(source) {
+            if queue.expires_at <= now || position != queue.position + 1 {
+                self.queues.remove(source);
+            }
+        }
+
+        if !self.queues.contains_key(source) {
+            // Only the first fragment can start a datagram.
+            if position != 1 {
+                return None;
+            }
+            if self.queues.len() >= MAX_QUEUES {
+                self.queues.retain(|_, queue| queue.expires_at > now);
+                if self.queues.len() >= MAX_QUEUES {
+                    return None;
+                }
+            }
+            self.queues.insert(
+                source.clone(),
+                Queue {
+                    data: Vec::new(),
+                    position: 0,
+                    expires_at: now + REASSEMBLY_TIMEOUT,
+                },
+            );
+        }
+
+        let queue = self.queues.get_mut(source)?;
+        if queue.data.len() + payload.len() > MAX_DATAGRAM_SIZE {
+            self.queues.remove(source);
+            return None;
+        }
+        queue.data.extend_from_slice(payload);
+        queue.position = position;
+
+        if frag & END_OF_SEQUENCE != 0 {
+            return self.queues.remove(source).map(|queue| queue.data);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(port: u16) -> TargetAddr {
+        TargetAddr::Ip(([127, 0, 0, 1], port).into())
+    }
+
+    #[test]
+    fn splits_into_numbered_fragments() {
+        let fragments = split(b"abcde", 2).unwrap();
+        assert_eq!(
+            fragments,
+            vec![
+                (1, &b"ab"[..]),
+                (2, &b"cd"[..]),
+                (3 | END_OF_SEQUENCE, &b"e"[..])
+            ]
+        );
+
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let reassembled = fragments
+            .into_iter()
+            .find_map(|(frag, chunk)| reassembler.push(&source(1), frag, chunk, now));
+        assert_eq!(reassembled, Some(b"abcde".to_vec()));
+    }
+
+    #[test]
+    fn refuses_to_split_into_too_many_fragments() {
+        assert_eq!(split(&[0; MAX_FRAGMENTS], 1).unwrap().len(), MAX_FRAGMENTS);
+        assert!(matches!(
+            split(&[0; MAX_FRAGMENTS + 1], 1),
+            Err(Socks5Error::DatagramTooLarge {
+                limit: MAX_FRAGMENTS,
+                size,
+            }) if size == MAX_FRAGMENTS + 1
+        ));
+    }
+
+    #[test]
+    fn reassembles_fragments_in_order() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert_eq!(reassembler.push(&source(1), 1, b"ab", now), None);
+        assert_eq!(reassembler.push(&source(1), 2, b"cd", now), None);
+        assert_eq!(
+            reassembler.push(&source(1), 3 | END_OF_SEQUENCE, b"e", now),
+            Some(b"abcde".to_vec())
+        );
+        assert!(reassembler.queues.is_empty());
+    }
+
+    #[test]
+    fn drops_datagrams_missing_a_fragment() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert_eq!(reassembler.push(&source(1), 2, b"cd", now), None);
+        assert_eq!(reassembler.push(&source(1), 1, b"ab", now), None);
+        assert_eq!(
+            reassembler.push(&source(1), 3 | END_OF_SEQUENCE, b"e", now),
+            None
+        );
+        assert!(reassembler.queues.is_empty());
+
+        // Restarting the sequence starts a new datagram.
+        assert_eq!(reassembler.push(&source(1), 1, b"ab", now), None);
+        assert_eq!(
+            reassembler.push(&source(1), 1 | END_OF_SEQUENCE, b"x", now),
+            Some(b"x".to_vec())
+        );
+    }
+
+    #[test]
+    fn drops_queues_past_the_timeout() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert_eq!(reassembler.push(&source(1), 1, b"ab", now), None);
+        let late = now + REASSEMBLY_TIMEOUT;
+        assert_eq!(
+            reassembler.push(&source(1), 2 | END_OF_SEQUENCE, b"cd", late),
+            None
+        );
+    }
+
+    #[test]
+    fn bounds_the_queues_and_their_size() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        for port in 0..MAX_QUEUES as u16 {
+            reassembler.push(&source(port), 1, b"a", now);
+        }
+        assert_eq!(reassembler.push(&source(u16::MAX), 1, b"a", now), None);
+        assert!(!reassembler.queues.contains_key(&source(u16::MAX)));
+
+        // Expired queues make room for new ones.
+        let late = now + REASSEMBLY_TIMEOUT;
+        reassembler.push(&source(u16::MAX), 1, b"a", late);
+        assert_eq!(reassembler.queues.len(), 1);
+
+        let chunk = vec![0; MAX_DATAGRAM_SIZE / 2 + 1];
+        assert_eq!(reassembler.push(&source(1), 1, &chunk, late), None);
+        assert_eq!(
+            reassembler.push(&source(1), 2 | END_OF_SEQUENCE, &chunk, late),
+            None
+        );
+        assert!(!reassembler.queues.contains_key(&source(1)));
+    }
+}
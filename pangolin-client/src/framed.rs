@@ -0,0 +1,140 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::io::ReadBuf;
+
+use crate::datagram::RECV_BUFFER_SIZE;
+use crate::{BoxFuture, Method, Result, Socks5Datagram, Socks5Error, TargetAddr};
+
+/// A `Stream` of the datagrams received and a `Sink` of the datagrams to send through a
+/// `Socks5Datagram`, like `tokio_util::udp::UdpFramed` for a `UdpSocket`.
+///
+/// The stream ends once the proxy terminated the association. A single datagram is buffered for
+/// sending: `poll_ready` flushes it before another is accepted.
+pub struct Socks5UdpFramed<M> {
+    datagram: Socks5Datagram<M>,
+    recv_buf: BytesMut,
+    outgoing: Option<Outgoing>,
+}
+
+enum Outgoing {
+    Resolving(Bytes, BoxFuture<Result<TargetAddr>>),
+    // Packed once, then sent over as many polls as needed.
+    Sending {
+        target: TargetAddr,
+        packets: Vec<Vec<u8>>,
+        sent: usize,
+    },
+}
+
+impl<M> Socks5UdpFramed<M> {
+    pub fn new(datagram: Socks5Datagram<M>) -> Self {
+        Self {
+            datagram,
+            recv_buf: BytesMut::new(),
+            outgoing: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &Socks5Datagram<M> {
+        &self.datagram
+    }
+
+    /// The datagram, dropping the datagram buffered for sending if it wasn't flushed.
+    pub fn into_inner(self) -> Socks5Datagram<M> {
+        self.datagram
+    }
+}
+
+impl<M> Stream for Socks5UdpFramed<M>
+where
+    M: Method,
+{
+    type Item = Result<(BytesMut, TargetAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.recv_buf.resize(RECV_BUFFER_SIZE, 0);
+
+        let mut buf = ReadBuf::new(&mut this.recv_buf);
+        let source = match ready!(this.datagram.poll_recv_from(cx, &mut buf)) {
+            Ok(source) => source,
+            Err(Socks5Error::AssociationTerminated) => return Poll::Ready(None),
+            Err(e) => return Poll::Ready(Some(Err(e))),
+        };
+
+        let len = buf.filled().len();
+        Poll::Ready(Some(Ok((this.recv_buf.split_to(len), source))))
+    }
+}
+
+impl<M> Sink<(Bytes, TargetAddr)> for Socks5UdpFramed<M>
+where
+    M: Method,
+{
+    type Error = Socks5Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.outgoing.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, (data, target): (Bytes, TargetAddr)) -> Result<()> {
+        let this = self.get_mut();
+        this.datagram.check_target(&target)?;
+
+        this.outgoing = Some(match this.datagram.resolve_detached(&target) {
+            Some(resolve) => Outgoing::Resolving(data, resolve),
+            None => Outgoing::Sending {
+                packets: this.datagram.pack(&data, &target)?,
+                target,
+                sent: 0,
+            },
+        });
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let (datagram, outgoing) = (&this.datagram, &mut this.outgoing);
+        let result = loop {
+            match outgoing {
+                None => return Poll::Ready(Ok(())),
+                Some(Outgoing::Resolving(data, resolve)) => {
+                    let packed = ready!(resolve.as_mut().poll(cx)).and_then(|target| {
+                        let packets = datagram.pack(data, &target)?;
+                        Ok((target, packets))
+                    });
+                    match packed {
+                        Ok((target, packets)) => {
+                            *outgoing = Some(Outgoing::Sending {
+                                target,
+                                packets,
+                                sent: 0,
+                            })
+                        }
+                        Err(e) => break Err(e),
+                    }
+                }
+                Some(Outgoing::Sending {
+                    target,
+                    packets,
+                    sent,
+                }) => {
+                    break ready!(datagram.poll_send_packets(cx, packets, target, sent));
+                }
+            }
+        };
+        *outgoing = None;
+        Poll::Ready(result)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
@@ -0,0 +1,383 @@
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use byteorder::{ByteOrder, NetworkEndian};
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
+
+use crate::datagram::AsyncDatagram;
+use crate::proto::{Strictness, Version};
+use crate::{Method, NoAuthentication, Result, Socks5Config, Socks5Error, TargetAddr};
+
+const GSSAPI_VERSION: Version = Version::new(0x01);
+
+const MTYP_AUTHENTICATION: u8 = 0x01;
+const MTYP_PROTECTION: u8 = 0x02;
+const MTYP_ENCAPSULATION: u8 = 0x03;
+const MTYP_ABORT: u8 = 0xff;
+
+/// The per-message protection negotiated after the security context is established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectionLevel {
+    /// Required per-message integrity.
+    Integrity = 0x01,
+    /// Required per-message integrity and confidentiality.
+    Confidentiality = 0x02,
+    /// Selective per-message integrity or confidentiality.
+    Selective = 0x03,
+}
+
+impl ProtectionLevel {
+    // Selective protection is left to the implementation; pangolin only asks for integrity then.
+    fn confidential(self) -> bool {
+        self == ProtectionLevel::Confidentiality
+    }
+
+    fn from_u8(level: u8) -> Result<Self> {
+        match level {
+            0x01 => Ok(ProtectionLevel::Integrity),
+            0x02 => Ok(ProtectionLevel::Confidentiality),
+            0x03 => Ok(ProtectionLevel::Selective),
+            _ => Err(Socks5Error::AuthenticationFailed),
+        }
+    }
+}
+
+/// The client side of a GSS-API security context, e.g. a Kerberos context for the proxy's
+/// service principal. Pangolin only implements the RFC 1961 message exchange around it.
+pub trait SecurityContext: Default + Send {
+    /// Process the token received from the proxy (`None` on the first call) and return the
+    /// token to send back, like `gss_init_sec_context`.
+    fn step(&mut self, input: Option<&[u8]>) -> Result<Option<Vec<u8>>>;
+
+    /// Whether the context is fully established.
+    fn is_complete(&self) -> bool;
+
+    /// Protect a message, like `gss_wrap`.
+    fn wrap(&mut self, data: &[u8], confidential: bool) -> Result<Vec<u8>>;
+
+    /// Verify and unprotect a message, like `gss_unwrap`.
+    fn unwrap(&mut self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// The protection level proposed to the proxy.
+    fn protection_level(&self) -> ProtectionLevel {
+        ProtectionLevel::Integrity
+    }
+}
+
+/// The GSS-API method (RFC 1961).
+pub struct Gssapi<S, C, U = UdpSocket> {
+    inner: NoAuthentication<S, U>,
+    // Locked to wrap and unwrap datagrams, which are sent and received through `&self`.
+    context: Mutex<C>,
+    protection_level: Option<ProtectionLevel>,
+    strictness: Strictness,
+}
+
+impl<S, C, U> Gssapi<S, C, U> {
+    /// The protection level the proxy chose, once the sub-negotiation is done.
+    pub fn protection_level(&self) -> Option<ProtectionLevel> {
+        self.protection_level
+    }
+
+    pub fn context(&self) -> MutexGuard<'_, C> {
+        self.context.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn context_mut(&mut self) -> &mut C {
+        self.context
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// +------+------+------+.......................+
+// + ver  | mtyp | len  |       token           |
+// +------+------+------+.......................+
+// + 0x01 | 0x01 | 0x02 | up to 2^16 - 1 octets |
+// +------+------+------+.......................+
+fn encode_message(mtyp: u8, token: &[u8]) -> Result<Vec<u8>> {
+    let len = token.len();
+    if len > u16::MAX as usize {
+        return Err(Socks5Error::AuthenticationFailed);
+    }
+
+    let mut buf = Vec::with_capacity(4 + len);
+    buf.extend_from_slice(&[GSSAPI_VERSION.as_u8(), mtyp]);
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    buf.extend_from_slice(token);
+    Ok(buf)
+}
+
+// Parse the encapsulated message at the front of `buf`, returning its token and its total
+// length, or `None` if it is not complete yet.
+fn decode_encapsulated(buf: &[u8], strictness: Strictness) -> Result<Option<(&[u8], usize)>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+
+    GSSAPI_VERSION.check(buf[0], strictness)?;
+
+    match buf[1] {
+        MTYP_ABORT => return Err(Socks5Error::AuthenticationFailed),
+        MTYP_ENCAPSULATION => {}
+        actual => {
+            return Err(Socks5Error::InvalidMessageType {
+                expected: MTYP_ENCAPSULATION,
+                actual,
+            })
+        }
+    }
+
+    let end = 4 + NetworkEndian::read_u16(&buf[2..4]) as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    Ok(Some((&buf[4..end], end)))
+}
+
+impl<S, C, U> Gssapi<S, C, U>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    C: SecurityContext,
+    U: AsyncDatagram + Unpin + Send,
+{
+    async fn write_message(&mut self, mtyp: u8, token: &[u8]) -> Result<()> {
+        let buf = encode_message(mtyp, token)?;
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    async fn read_message(&mut self, mtyp: u8, config: &Socks5Config) -> Result<Vec<u8>> {
+        let mut header = [0; 2];
+        self.inner.read_exact(&mut header).await?;
+
+        GSSAPI_VERSION.check(header[0], config.strictness)?;
+
+        match header[1] {
+            MTYP_ABORT => return Err(Socks5Error::AuthenticationFailed),
+            actual if actual != mtyp => {
+                return Err(Socks5Error::InvalidMessageType {
+                    expected: mtyp,
+                    actual,
+                })
+            }
+            _ => {}
+        }
+
+        let mut len = [0; 2];
+        self.inner.read_exact(&mut len).await?;
+
+        let mut token = vec![0; NetworkEndian::read_u16(&len) as usize];
+        self.inner.read_exact(&mut token).await?;
+        Ok(token)
+    }
+}
+
+impl<S, C, U> AsyncDatagram for Gssapi<S, C, U>
+where
+    U: AsyncDatagram,
+{
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_send_ready(cx)
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_recv_ready(cx)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.inner.poll_send_to(cx, buf, target)
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        self.inner.poll_recv_from(cx, buf)
+    }
+
+    fn poll_send_many(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[IoSlice<'_>],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.inner.poll_send_many(cx, packets, target)
+    }
+
+    fn poll_recv_many(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        self.inner.poll_recv_many(cx, bufs)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl<S, C, U> AsyncRead for Gssapi<S, C, U>
+where
+    S: AsyncRead + Unpin,
+    C: Unpin,
+    U: Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S, C, U> AsyncWrite for Gssapi<S, C, U>
+where
+    S: AsyncWrite + Unpin,
+    C: Unpin,
+    U: Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl<S, C, U> Method for Gssapi<S, C, U>
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send,
+    C: SecurityContext + Unpin,
+    U: AsyncDatagram + Unpin + Send,
+{
+    type Stream = S;
+    type Datagram = U;
+
+    async fn create(socket: S, code: u8, config: &Socks5Config) -> Result<Self> {
+        Ok(Self {
+            inner: NoAuthentication::create(socket, code, config).await?,
+            context: Mutex::new(C::default()),
+            protection_level: None,
+            strictness: config.strictness,
+        })
+    }
+
+    async fn handshake(&mut self, config: &Socks5Config) -> Result<()> {
+        // Security context establishment
+        let mut input = None;
+        loop {
+            let output = self.context_mut().step(input.as_deref())?;
+            if let Some(token) = output.filter(|token| !token.is_empty()) {
+                self.write_message(MTYP_AUTHENTICATION, &token).await?;
+            }
+
+            if self.context_mut().is_complete() {
+                break;
+            }
+
+            input = Some(self.read_message(MTYP_AUTHENTICATION, config).await?);
+        }
+
+        // Message protection sub-negotiation
+        let proposed = self.context_mut().protection_level();
+        let token = self.context_mut().wrap(&[proposed as u8], false)?;
+        self.write_message(MTYP_PROTECTION, &token).await?;
+
+        let token = self.read_message(MTYP_PROTECTION, config).await?;
+        let level = self.context_mut().unwrap(&token)?;
+        let level = *level.first().ok_or(Socks5Error::AuthenticationFailed)?;
+        self.protection_level = Some(ProtectionLevel::from_u8(level)?);
+
+        Ok(())
+    }
+
+    async fn register_endpoints(&mut self, src: Self::Datagram, dst: TargetAddr) -> Result<()> {
+        self.inner.register_endpoints(src, dst).await
+    }
+
+    fn codes(_: &Socks5Config) -> Vec<u8> {
+        vec![0x01]
+    }
+
+    fn encapsulates(&self) -> bool {
+        self.protection_level.is_some()
+    }
+
+    fn encapsulate(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.encapsulate_datagram(data.to_vec())
+    }
+
+    fn decapsulate(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        let strictness = self.strictness;
+        let (data, len) = match decode_encapsulated(buf, strictness)? {
+            Some((token, len)) => (self.context_mut().unwrap(token)?, len),
+            None => return Ok(None),
+        };
+        let _ = buf.split_to(len);
+        Ok(Some(data))
+    }
+
+    fn encapsulate_datagram(&self, packet: Vec<u8>) -> Result<Vec<u8>> {
+        let confidential = self
+            .protection_level
+            .is_some_and(ProtectionLevel::confidential);
+        let token = self.context().wrap(&packet, confidential)?;
+        encode_message(MTYP_ENCAPSULATION, &token)
+    }
+
+    fn decapsulate_datagram(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        match decode_encapsulated(packet, self.strictness)? {
+            Some((token, _)) => self.context().unwrap(token),
+            None => Err(Socks5Error::IncompleteHeader),
+        }
+    }
+
+    fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+
+    fn into_parts(self) -> (S, Option<U>) {
+        self.inner.into_parts()
+    }
+}
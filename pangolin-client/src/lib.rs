@@ -0,0 +1,167 @@
+//! The tokio SOCKS5 client of pangolin, built on the codec of `pangolin-proto`.
+
+mod builder;
+mod client;
+mod config;
+mod datagram;
+mod dns;
+mod driver;
+mod dynamic;
+mod fragment;
+mod framed;
+#[cfg(feature = "gssapi")]
+mod gssapi;
+mod limit;
+mod listener;
+mod method;
+#[cfg(target_os = "linux")]
+mod mmsg;
+mod policy;
+mod pool;
+#[cfg(feature = "quinn")]
+mod quic;
+mod relay;
+mod resumption;
+mod retry;
+mod reverse;
+mod session;
+mod stream;
+mod tor;
+mod url;
+mod userpass;
+
+pub use self::builder::{Socks5ListenerBuilder, Socks5StreamBuilder};
+pub use self::config::{
+    Extensions, Keepalive, PhaseTimeouts, QuirksRegistry, SocketOptions, Socks5Config,
+};
+pub use self::datagram::{AsyncDatagram, AsyncDatagramExt, DatagramTransform, Socks5Datagram};
+pub use self::dns::DnsCache;
+pub use self::driver::HandshakeDriver;
+pub use self::dynamic::DynMethod;
+pub use self::framed::Socks5UdpFramed;
+#[cfg(feature = "gssapi")]
+pub use self::gssapi::{Gssapi, ProtectionLevel, SecurityContext};
+pub use self::limit::{ConcurrencyLimiter, SessionPermit};
+pub use self::listener::{Socks5Listener, UnspecifiedBindAddr};
+pub use self::method::{Either, Method, NoAuthentication};
+pub use self::policy::{ExpectedPeer, IpNet, TargetPolicy};
+pub use self::pool::{BufferPool, PooledBuf};
+#[cfg(feature = "quinn")]
+pub use self::quic::QuicSocket;
+pub use self::relay::{relay, RelayConfig, DEFAULT_BUFFER_SIZE};
+pub use self::resumption::{ResumptionToken, SessionResumption};
+pub use self::retry::RetryPolicy;
+pub use self::reverse::ReverseTunnel;
+pub use self::session::SessionId;
+pub use self::stream::Socks5Stream;
+pub use self::tor::TorIsolation;
+pub use self::url::ProxyUrl;
+pub use self::userpass::{CachedCredentials, CredentialProvider, Credentials, UsernamePassword};
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+pub use pangolin_proto as proto;
+pub use pangolin_proto::{
+    default_port, BindReply, HandshakePhase, Result, Socks5Error, TargetAddr, VERSION,
+};
+pub use tokio_util::sync::CancellationToken;
+
+pub(crate) type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Where domain targets are resolved, following the `socks5://` vs `socks5h://` convention used
+/// by curl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Resolution {
+    /// Resolve domains locally and send the proxy an IP address (`socks5://`).
+    Local,
+    /// Send domains to the proxy as-is and let it resolve them (`socks5h://`).
+    #[default]
+    Remote,
+}
+
+impl Resolution {
+    pub fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme.to_ascii_lowercase().as_str() {
+            "socks5" => Some(Resolution::Local),
+            "socks5h" => Some(Resolution::Remote),
+            _ => None,
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            Resolution::Local => "socks5",
+            Resolution::Remote => "socks5h",
+        }
+    }
+
+    /// Turn `addr` into the address that should be sent to the proxy.
+    pub async fn resolve(&self, addr: TargetAddr) -> Result<TargetAddr> {
+        self.resolve_with(addr, None).await
+    }
+
+    /// Like `resolve`, answering local lookups from `cache` when there is one.
+    pub async fn resolve_with(
+        &self,
+        addr: TargetAddr,
+        cache: Option<&DnsCache>,
+    ) -> Result<TargetAddr> {
+        self.resolve_with_family(addr, cache, AddressFamily::Any)
+            .await
+    }
+
+    /// Like `resolve_with`, picking the address of a local lookup according to `family`.
+    pub async fn resolve_with_family(
+        &self,
+        addr: TargetAddr,
+        cache: Option<&DnsCache>,
+        family: AddressFamily,
+    ) -> Result<TargetAddr> {
+        match (self, addr) {
+            (Resolution::Local, TargetAddr::Domain(domain, port)) => {
+                let addrs = dns::lookup(&domain, port, cache).await?;
+                let addr = family
+                    .apply(addrs)
+                    .into_iter()
+                    .next()
+                    .ok_or(Socks5Error::InvalidTargetAddress)?;
+                Ok(TargetAddr::Ip(addr))
+            }
+            (_, addr) => Ok(addr),
+        }
+    }
+}
+
+/// Which address families are used, and in which order, when a host resolves to several
+/// addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressFamily {
+    /// Keep the order of the resolver.
+    #[default]
+    Any,
+    /// Try IPv4 addresses first, falling back to IPv6.
+    PreferIpv4,
+    /// Try IPv6 addresses first, falling back to IPv4.
+    PreferIpv6,
+    /// Never use IPv6 addresses.
+    Ipv4Only,
+    /// Never use IPv4 addresses.
+    Ipv6Only,
+}
+
+impl AddressFamily {
+    /// Filter and reorder `addrs`, keeping the order of the resolver within a family.
+    pub fn apply<I: IntoIterator<Item = SocketAddr>>(&self, addrs: I) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = addrs.into_iter().collect();
+        match self {
+            AddressFamily::Any => {}
+            AddressFamily::PreferIpv4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+            AddressFamily::PreferIpv6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+            AddressFamily::Ipv4Only => addrs.retain(SocketAddr::is_ipv4),
+            AddressFamily::Ipv6Only => addrs.retain(SocketAddr::is_ipv6),
+        }
+        addrs
+    }
+}
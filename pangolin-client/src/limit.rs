@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+use tokio::net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config;
+use crate::{HandshakePhase, Result, Socks5Config, Socks5Error};
+
+/// Caps the number of simultaneous sessions opened to each proxy endpoint, keyed by the proxy's
+/// socket address, e.g. `"10.0.0.1:1080"`.
+///
+/// Clones share their limits, so one limiter placed in the `Socks5Config` of every stream,
+/// datagram and listener keeps the application within the plan of its provider. Sessions wait
+/// for a free slot before connecting to the proxy.
+#[derive(Clone, Default)]
+pub struct ConcurrencyLimiter {
+    endpoints: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+/// A slot of a proxy endpoint, held by a session until it is dropped.
+#[derive(Debug)]
+pub struct SessionPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow at most `limit` simultaneous sessions to `endpoint`. Sessions already holding a
+    /// slot under a previous limit are not affected.
+    pub fn set_limit<E: Into<String>>(&self, endpoint: E, limit: usize) {
+        self.lock()
+            .insert(endpoint.into(), Arc::new(Semaphore::new(limit)));
+    }
+
+    pub fn remove_limit(&self, endpoint: &str) -> bool {
+        self.lock().remove(endpoint).is_some()
+    }
+
+    /// The number of free slots of `endpoint`, or `None` if it isn't limited.
+    pub fn available(&self, endpoint: &str) -> Option<usize> {
+        self.lock()
+            .get(endpoint)
+            .map(|semaphore| semaphore.available_permits())
+    }
+
+    /// Wait for a slot of `endpoint`, or return `None` at once if it isn't limited.
+    pub async fn acquire(&self, endpoint: &str) -> Option<SessionPermit> {
+        let semaphore = self.lock().get(endpoint).cloned()?;
+        // The semaphores are never closed.
+        let permit = semaphore.acquire_owned().await.ok()?;
+        Some(SessionPermit { _permit: permit })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<Semaphore>>> {
+        self.endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for ConcurrencyLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConcurrencyLimiter")
+            .field("endpoints", &self.lock().len())
+            .finish()
+    }
+}
+
+/// Connect to the proxy at `addr`, waiting for a slot of the endpoint first if
+/// `config.limiter` limits it.
+pub(crate) async fn connect<A: ToSocketAddrs>(
+    addr: A,
+    config: &Socks5Config,
+) -> Result<(TcpStream, Option<SessionPermit>)> {
+    connect_addrs(lookup_host(addr).await?, config).await
+}
+
+/// Like `connect`, failing with `Socks5Error::Timeout` if `deadline` passes first.
+pub(crate) async fn connect_until<A: ToSocketAddrs>(
+    addr: A,
+    deadline: Instant,
+    config: &Socks5Config,
+) -> Result<(TcpStream, Option<SessionPermit>)> {
+    tokio::time::timeout_at(deadline.into(), connect(addr, config))
+        .await
+        .map_err(|_| Socks5Error::Timeout {
+            phase: HandshakePhase::Connect,
+        })?
+}
+
+/// Like `connect`, trying each of the already resolved `addrs` in turn, in the order preferred by
+/// `config.address_family`.
+pub(crate) async fn connect_addrs<I: IntoIterator<Item = SocketAddr>>(
+    addrs: I,
+    config: &Socks5Config,
+) -> Result<(TcpStream, Option<SessionPermit>)> {
+    config::cancellable(config.cancellation.as_ref(), connect_each(addrs, config)).await
+}
+
+async fn connect_each<I: IntoIterator<Item = SocketAddr>>(
+    addrs: I,
+    config: &Socks5Config,
+) -> Result<(TcpStream, Option<SessionPermit>)> {
+    let mut last_err = None;
+    for addr in config.address_family.apply(addrs) {
+        let permit = match &config.limiter {
+            Some(limiter) => limiter.acquire(&addr.to_string()).await,
+            None => None,
+        };
+
+        match connect_from(addr, config).await {
+            Ok(socket) => {
+                config.socket_options.apply(&socket)?;
+                return Ok((socket, permit));
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err
+        .unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            )
+        })
+        .into())
+}
+
+// Connect to `addr` from the local address and device of `config`.
+async fn connect_from(addr: SocketAddr, config: &Socks5Config) -> io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(local_addr) = config.local_addr {
+        socket.bind(local_addr)?;
+    }
+    config.bind_to_device(&socket)?;
+    #[cfg(feature = "tcp-fastopen")]
+    if config.socket_options.fast_open {
+        config::set_fast_open(&socket);
+    }
+    socket.connect(addr).await
+}
+
+/// Like `connect`, reaching `addr` with a `socket` the caller has configured.
+pub(crate) async fn connect_socket(
+    socket: TcpSocket,
+    addr: SocketAddr,
+    config: &Socks5Config,
+) -> Result<(TcpStream, Option<SessionPermit>)> {
+    config::cancellable(config.cancellation.as_ref(), async {
+        let permit = match &config.limiter {
+            Some(limiter) => limiter.acquire(&addr.to_string()).await,
+            None => None,
+        };
+
+        let socket = socket.connect(addr).await?;
+        config.socket_options.apply(&socket)?;
+        Ok((socket, permit))
+    })
+    .await
+}
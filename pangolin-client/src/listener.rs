@@ -0,0 +1,447 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpSocket, TcpStream, ToSocketAddrs};
+
+use crate::client::Socks5Client;
+use crate::proto::{Request, RequestType};
+use crate::{config, limit};
+use crate::{
+    BindReply, HandshakePhase, Method, ProxyUrl, Result, SessionId, SessionPermit, Socks5Config,
+    Socks5Error, Socks5ListenerBuilder, Socks5Stream, TargetAddr,
+};
+
+/// What `Socks5Listener::bind_addr` reports when the proxy replies to BIND with an unspecified
+/// address, which some proxies do, expecting the port to be combined with their own address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnspecifiedBindAddr {
+    /// The address as replied.
+    #[default]
+    Keep,
+    /// The address of the proxy the listener connected to. Kept as replied when binding over a
+    /// socket passed to `bind_with_socket`.
+    ProxyAddr,
+    /// This address, e.g. the public address of a proxy behind a NAT.
+    External(IpAddr),
+}
+
+impl UnspecifiedBindAddr {
+    fn apply(&self, bind_addr: TargetAddr, proxy_ip: Option<IpAddr>) -> TargetAddr {
+        let port = match &bind_addr {
+            TargetAddr::Ip(addr) if addr.ip().is_unspecified() => addr.port(),
+            _ => return bind_addr,
+        };
+        let ip = match self {
+            UnspecifiedBindAddr::Keep => None,
+            UnspecifiedBindAddr::ProxyAddr => proxy_ip.map(|ip| ip.to_canonical()),
+            UnspecifiedBindAddr::External(ip) => Some(*ip),
+        };
+        match ip {
+            Some(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+            None => bind_addr,
+        }
+    }
+}
+
+pub struct Socks5Listener<M> {
+    client: Socks5Client<M>,
+    bind_addr: TargetAddr,
+    // The address of the incoming connection, once the proxy reported it.
+    remote_addr: Option<TargetAddr>,
+}
+
+impl<M> Socks5Listener<M> {
+    pub fn bind_addr(&self) -> TargetAddr {
+        self.bind_addr.clone()
+    }
+
+    pub fn session_id(&self) -> SessionId {
+        self.client.session_id()
+    }
+}
+
+impl<M> Socks5Listener<M>
+where
+    M: Method,
+{
+    pub async fn bind_with_socket(
+        socket: M::Stream,
+        target_addr: TargetAddr,
+    ) -> Result<Socks5Listener<M>> {
+        Self::bind_with_socket_and_config(socket, target_addr, Socks5Config::default()).await
+    }
+
+    pub async fn bind_with_socket_and_config(
+        socket: M::Stream,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Socks5Listener<M>> {
+        Self::bind_inner(socket, target_addr, config, None).await
+    }
+
+    // Bind over `socket`, connected to the proxy at `proxy_ip` if it is known.
+    async fn bind_inner(
+        socket: M::Stream,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+        proxy_ip: Option<IpAddr>,
+    ) -> Result<Socks5Listener<M>> {
+        config.check_target(&target_addr)?;
+        let target_addr = config
+            .resolution
+            .resolve_with_family(
+                target_addr,
+                config.dns_cache.as_ref(),
+                config.address_family,
+            )
+            .await?;
+        config.check_target(&target_addr)?;
+        let mut client = Socks5Client::<M>::connect(socket, config).await?;
+        let bind_addr = client
+            .send_request(Request::new(RequestType::Bind, target_addr))
+            .await
+            .map_err(|e| e.in_bind_reply(BindReply::First, None))?;
+        let bind_addr = client
+            .config()
+            .unspecified_bind_addr
+            .apply(bind_addr, proxy_ip);
+
+        Ok(Self {
+            client,
+            bind_addr,
+            remote_addr: None,
+        })
+    }
+
+    /// Wait for the proxy to report the incoming connection, and return its address, within the
+    /// `accept_timeout` of the configuration. Cut short, e.g. by a timeout or the cancellation of
+    /// the configuration, it can be called again without losing the bind; once it returned,
+    /// `into_stream` gives the connection.
+    pub async fn accept(&mut self) -> Result<TargetAddr> {
+        match self.client.config().accept_timeout {
+            Some(timeout) => self.accept_with_deadline(Instant::now() + timeout).await,
+            None => self.accept_inner().await,
+        }
+    }
+
+    pub async fn accept_timeout(&mut self, timeout: Duration) -> Result<TargetAddr> {
+        self.accept_with_deadline(Instant::now() + timeout).await
+    }
+
+    /// Like `accept`, failing with `Socks5Error::Timeout` in the `Accept` phase if no connection
+    /// arrives by `deadline`.
+    pub async fn accept_with_deadline(&mut self, deadline: Instant) -> Result<TargetAddr> {
+        let deadline = match self.client.config().accept_timeout {
+            Some(timeout) => deadline.min(Instant::now() + timeout),
+            None => deadline,
+        };
+        let session = self.client.session_id().as_u64();
+        tokio::time::timeout_at(deadline.into(), self.accept_inner())
+            .await
+            .map_err(|_| {
+                Socks5Error::Timeout {
+                    phase: HandshakePhase::Accept,
+                }
+                .in_session(session)
+            })?
+    }
+
+    async fn accept_inner(&mut self) -> Result<TargetAddr> {
+        let remote_addr = match &self.remote_addr {
+            Some(remote_addr) => remote_addr.clone(),
+            None => {
+                let cancellation = self.client.config().cancellation.clone();
+                let reply =
+                    config::cancellable(cancellation.as_ref(), self.client.recv_reply()).await;
+                let session = self.client.session_id().as_u64();
+                let remote_addr = reply.map_err(|e| {
+                    e.in_bind_reply(BindReply::Second, Some(self.bind_addr.clone()))
+                        .in_session(session)
+                })?;
+                self.remote_addr = Some(remote_addr.clone());
+                remote_addr
+            }
+        };
+
+        if !self.is_expected(&remote_addr) {
+            let session = self.client.session_id().as_u64();
+            return Err(Socks5Error::UnexpectedBindPeer { peer: remote_addr }.in_session(session));
+        }
+        Ok(remote_addr)
+    }
+
+    fn is_expected(&self, peer: &TargetAddr) -> bool {
+        match &self.client.config().expected_bind_peer {
+            Some(expected) => expected.matches(peer),
+            None => true,
+        }
+    }
+
+    /// The address of the incoming connection, once `accept` returned it.
+    pub fn remote_addr(&self) -> Option<TargetAddr> {
+        self.remote_addr.clone()
+    }
+
+    /// The incoming connection once `accept` returned, or the listener back if it hasn't yet or
+    /// the connection came from an unexpected peer.
+    #[allow(clippy::result_large_err)]
+    pub fn into_stream(self) -> std::result::Result<Socks5Stream<M>, Self> {
+        match self.remote_addr.clone() {
+            Some(remote_addr) if self.is_expected(&remote_addr) => {
+                Ok(Socks5Stream::new(self.client, remote_addr, self.bind_addr))
+            }
+            _ => Err(self),
+        }
+    }
+
+    /// The socket to the proxy.
+    pub fn get_ref(&self) -> &M::Stream {
+        self.client.get_ref()
+    }
+
+    /// Give back the socket to the proxy. Unless `accept` has begun reading it, the reply
+    /// reporting the incoming connection is left to be read from it.
+    pub fn into_inner(self) -> M::Stream {
+        self.client.into_parts().0
+    }
+}
+
+impl<M> Socks5Listener<M>
+where
+    M: Method<Stream = TcpStream>,
+{
+    /// Collect the options of a bind before binding, instead of picking one of the constructors
+    /// below.
+    pub fn builder() -> Socks5ListenerBuilder<M> {
+        Socks5ListenerBuilder::new()
+    }
+
+    /// Disable Nagle's algorithm on the connection to the proxy, like `TcpStream::set_nodelay`.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        Ok(self.get_ref().set_nodelay(nodelay)?)
+    }
+
+    pub fn nodelay(&self) -> Result<bool> {
+        Ok(self.get_ref().nodelay()?)
+    }
+
+    /// Set the IP time-to-live of the packets sent to the proxy.
+    pub fn set_ttl(&self, ttl: u32) -> Result<()> {
+        Ok(self.get_ref().set_ttl(ttl)?)
+    }
+
+    pub fn ttl(&self) -> Result<u32> {
+        Ok(self.get_ref().ttl()?)
+    }
+
+    /// Send TCP keepalive probes once the connection has been idle for `time`, or stop sending
+    /// them if `None`.
+    pub fn set_keepalive(&self, time: Option<Duration>) -> Result<()> {
+        Ok(config::set_keepalive(self.get_ref(), time)?)
+    }
+
+    /// Resolve once the proxy closes the connection before reporting the incoming connection,
+    /// e.g. to bind again rather than wait for a peer that can't arrive anymore, or with the
+    /// error that broke it. Stays pending once the report begins to arrive, leaving it to
+    /// `accept`, including when it was already read along with the reply to the BIND request.
+    pub async fn closed(&self) -> Result<()> {
+        if self.remote_addr.is_none() && !self.client.has_unread() {
+            let mut buf = [0; 1];
+            if self.get_ref().peek(&mut buf).await? == 0 {
+                return Ok(());
+            }
+        }
+        std::future::pending().await
+    }
+
+    pub async fn bind<A: ToSocketAddrs>(proxy: A, target_addr: TargetAddr) -> Result<Self> {
+        Self::bind_with_config(proxy, target_addr, Socks5Config::default()).await
+    }
+
+    pub async fn bind_with_config<A: ToSocketAddrs>(
+        proxy: A,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        config.check_target(&target_addr)?;
+        let (socket, permit) = limit::connect(proxy, &config).await?;
+        Self::bind_over(socket, permit, target_addr, config).await
+    }
+
+    pub async fn bind_with_deadline<A: ToSocketAddrs>(
+        proxy: A,
+        target_addr: TargetAddr,
+        deadline: Instant,
+    ) -> Result<Self> {
+        Self::bind_with_deadline_and_config(proxy, target_addr, deadline, Socks5Config::default())
+            .await
+    }
+
+    /// Like `bind_with_config`, failing with `Socks5Error::Timeout` if the proxy hasn't replied
+    /// with its bound address by `deadline`. `accept` isn't limited by it.
+    pub async fn bind_with_deadline_and_config<A: ToSocketAddrs>(
+        proxy: A,
+        target_addr: TargetAddr,
+        deadline: Instant,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        config.check_target(&target_addr)?;
+        let (socket, permit) = limit::connect_until(proxy, deadline, &config).await?;
+        let config = config.until(deadline);
+        Self::bind_over(socket, permit, target_addr, config).await
+    }
+
+    pub async fn bind_with_tcp_socket(
+        socket: TcpSocket,
+        proxy: SocketAddr,
+        target_addr: TargetAddr,
+    ) -> Result<Self> {
+        Self::bind_with_tcp_socket_and_config(socket, proxy, target_addr, Socks5Config::default())
+            .await
+    }
+
+    /// Like `bind_with_config`, reaching the proxy with the preconfigured `socket`.
+    pub async fn bind_with_tcp_socket_and_config(
+        socket: TcpSocket,
+        proxy: SocketAddr,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        config.check_target(&target_addr)?;
+        let (socket, permit) = limit::connect_socket(socket, proxy, &config).await?;
+        Self::bind_over(socket, permit, target_addr, config).await
+    }
+
+    pub async fn bind_with_url(url: &ProxyUrl, target_addr: TargetAddr) -> Result<Self> {
+        Self::bind_with_url_and_config(url, target_addr, Socks5Config::default()).await
+    }
+
+    /// Bind through the proxy of `url`, whose resolution and credentials override `config`.
+    pub async fn bind_with_url_and_config(
+        url: &ProxyUrl,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let config = url.configure(&config);
+        config.check_target(&target_addr)?;
+        let (socket, permit) = url.connect(&config).await?;
+        Self::bind_over(socket, permit, target_addr, config).await
+    }
+
+    // Bind over a connection to the proxy opened for the listener, which stays idle until the
+    // incoming connection arrives.
+    pub(crate) async fn bind_over(
+        socket: TcpStream,
+        permit: Option<SessionPermit>,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        if let Some(keepalive) = &config.control_keepalive {
+            keepalive.apply(&socket)?;
+        }
+        let proxy_ip = socket.peer_addr().ok().map(|addr| addr.ip());
+        let mut listener = Self::bind_inner(socket, target_addr, config, proxy_ip).await?;
+        listener.client.hold(permit);
+        Ok(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{ExpectedPeer, NoAuthentication, VERSION};
+
+    #[tokio::test]
+    async fn leaves_a_buffered_report_to_accept() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[VERSION, 0x00]).await.unwrap();
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+
+            // Both replies at once, then the proxy hangs up.
+            let mut replies = vec![VERSION, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90];
+            replies.extend_from_slice(&[VERSION, 0x00, 0x00, 0x01, 127, 0, 0, 2, 0x1f, 0x91]);
+            socket.write_all(&replies).await.unwrap();
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut listener = Socks5Listener::<NoAuthentication<TcpStream>>::bind_over(
+            socket,
+            None,
+            TargetAddr::Ip("127.0.0.1:0".parse().unwrap()),
+            Socks5Config::default(),
+        )
+        .await
+        .unwrap();
+
+        let closed = tokio::time::timeout(Duration::from_millis(100), listener.closed()).await;
+        assert!(closed.is_err());
+        assert_eq!(
+            listener.accept().await.unwrap(),
+            TargetAddr::Ip("127.0.0.2:8081".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn attributes_accept_failures_to_the_session() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move {
+            for second in [None, Some([127, 0, 0, 2])] {
+                let (mut socket, _) = proxy.accept().await.unwrap();
+                let mut greeting = [0; 3];
+                socket.read_exact(&mut greeting).await.unwrap();
+                socket.write_all(&[VERSION, 0x00]).await.unwrap();
+                let mut request = [0; 10];
+                socket.read_exact(&mut request).await.unwrap();
+                let mut replies = vec![VERSION, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90];
+                if let Some(ip) = second {
+                    replies.extend_from_slice(&[VERSION, 0x00, 0x00, 0x01]);
+                    replies.extend_from_slice(&ip);
+                    replies.extend_from_slice(&[0x1f, 0x91]);
+                }
+                socket.write_all(&replies).await.unwrap();
+                tokio::spawn(async move { socket.read(&mut [0; 1]).await });
+            }
+        });
+        let bind = |config| async move {
+            let socket = TcpStream::connect(addr).await.unwrap();
+            let target = TargetAddr::Ip("127.0.0.1:0".parse().unwrap());
+            Socks5Listener::<NoAuthentication<TcpStream>>::bind_over(socket, None, target, config)
+                .await
+                .unwrap()
+        };
+
+        // No connection comes in.
+        let mut listener = bind(Socks5Config::default()).await;
+        let e = listener
+            .accept_timeout(Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert_eq!(e.session(), Some(listener.session_id().as_u64()));
+        assert!(matches!(
+            e.root(),
+            Socks5Error::Timeout {
+                phase: HandshakePhase::Accept
+            }
+        ));
+
+        // One does, from someone else.
+        let config = Socks5Config {
+            expected_bind_peer: Some(ExpectedPeer::Addr("127.0.0.3:8081".parse().unwrap())),
+            ..Socks5Config::default()
+        };
+        let mut listener = bind(config).await;
+        let e = listener.accept().await.unwrap_err();
+        assert_eq!(e.session(), Some(listener.session_id().as_u64()));
+        assert!(matches!(e.root(), Socks5Error::UnexpectedBindPeer { .. }));
+    }
+}
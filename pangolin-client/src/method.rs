@@ -0,0 +1,542 @@
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::ops::DerefMut;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+
+use crate::datagram::{move_datagram, AsyncDatagram};
+use crate::{Result, Socks5Config, Socks5Error, TargetAddr};
+
+#[async_trait]
+/// A trait for objects that implement the logic of socks5's method-dependent sub-negotiation.
+pub trait Method: AsyncRead + AsyncWrite + AsyncDatagram + Unpin + Send + Sized {
+    type Stream: AsyncRead + AsyncWrite + Unpin;
+    type Datagram: AsyncDatagram;
+
+    /// Create the method once the proxy selected `code`, one of `Self::codes(config)`.
+    async fn create(socket: Self::Stream, code: u8, config: &Socks5Config) -> Result<Self>;
+
+    // Establish the method-dependent sub-negotiation context.
+    async fn handshake(&mut self, config: &Socks5Config) -> Result<()>;
+
+    // UDP-related methods
+    async fn register_endpoints(&mut self, src: Self::Datagram, dst: TargetAddr) -> Result<()>;
+
+    /// The method codes offered to the proxy, in order of preference.
+    ///
+    /// The codes may depend on `config`, so that a single type can implement a private method
+    /// whose code is only known at runtime, typically read from `config.extensions`.
+    fn codes(config: &Socks5Config) -> Vec<u8>;
+
+    /// Whether the method encapsulates the traffic that follows the sub-negotiation, enabling the
+    /// hooks below. The SOCKS request and reply are encapsulated as well.
+    fn encapsulates(&self) -> bool {
+        false
+    }
+
+    /// Encapsulate a chunk of stream data before it is written to the proxy.
+    fn encapsulate(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    /// Decapsulate the message at the front of `buf`, which holds the bytes read from the proxy
+    /// so far, or return `None` if it is not complete yet.
+    fn decapsulate(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        Ok(Some(buf.split().to_vec()))
+    }
+
+    /// Encapsulate a datagram, SOCKS UDP header included, before it is sent to the relay.
+    fn encapsulate_datagram(&self, packet: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(packet)
+    }
+
+    /// Decapsulate a datagram received from the relay.
+    fn decapsulate_datagram(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        Ok(packet.to_vec())
+    }
+
+    /// The socket to the proxy.
+    fn get_ref(&self) -> &Self::Stream;
+
+    /// The socket to the proxy. Reading or writing it directly corrupts the tunnel.
+    fn get_mut(&mut self) -> &mut Self::Stream;
+
+    /// Give back the socket to the proxy, and the datagram socket if one was registered.
+    fn into_parts(self) -> (Self::Stream, Option<Self::Datagram>);
+}
+
+#[derive(Default)]
+pub struct NoAuthentication<S, U = UdpSocket> {
+    socket: S,
+
+    // Optional UDP socket address.
+    endpoints: Option<(U, TargetAddr)>,
+
+    // Accept datagrams from any source, not only from the relay.
+    accept_foreign: bool,
+}
+
+impl<S, U> AsyncDatagram for NoAuthentication<S, U>
+where
+    U: AsyncDatagram,
+{
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.endpoints.as_ref().map_or_else(
+            || Poll::Ready(Err(Socks5Error::DatagramSocketNotRegistered)),
+            |(src, _)| src.poll_send_ready(cx),
+        )
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.endpoints.as_ref().map_or_else(
+            || Poll::Ready(Err(Socks5Error::DatagramSocketNotRegistered)),
+            |(src, _)| src.poll_recv_ready(cx),
+        )
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        _: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.endpoints.as_ref().map_or_else(
+            || Poll::Ready(Err(Socks5Error::DatagramSocketNotRegistered)),
+            |(src, dst)| src.poll_send_to(cx, buf, dst),
+        )
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        let (src, dst) = match &self.endpoints {
+            Some(endpoints) => endpoints,
+            None => return Poll::Ready(Err(Socks5Error::DatagramSocketNotRegistered)),
+        };
+
+        // Drop datagrams injected by other hosts into the bound socket.
+        let filled = buf.filled().len();
+        loop {
+            let source = ready!(src.poll_recv_from(cx, buf))?;
+            if self.accept_foreign || from_relay(&source, dst) {
+                return Poll::Ready(Ok(source));
+            }
+            buf.set_filled(filled);
+        }
+    }
+
+    fn poll_send_many(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[IoSlice<'_>],
+        _: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.endpoints.as_ref().map_or_else(
+            || Poll::Ready(Err(Socks5Error::DatagramSocketNotRegistered)),
+            |(src, dst)| src.poll_send_many(cx, packets, dst),
+        )
+    }
+
+    fn poll_recv_many(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        let (src, dst) = match &self.endpoints {
+            Some(endpoints) => endpoints,
+            None => return Poll::Ready(Err(Socks5Error::DatagramSocketNotRegistered)),
+        };
+
+        loop {
+            let mut sources = ready!(src.poll_recv_many(cx, bufs))?;
+            if !self.accept_foreign {
+                // Drop datagrams injected by other hosts, moving the rest to the front.
+                let mut kept = 0;
+                for i in 0..sources.len() {
+                    if from_relay(&sources[i], dst) {
+                        if kept != i {
+                            move_datagram(bufs, i, kept);
+                            sources.swap(i, kept);
+                        }
+                        kept += 1;
+                    }
+                }
+                sources.truncate(kept);
+            }
+
+            if !sources.is_empty() {
+                return Poll::Ready(Ok(sources));
+            }
+            bufs.iter_mut().for_each(ReadBuf::clear);
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.endpoints.as_ref().map_or_else(
+            || Err(Socks5Error::DatagramSocketNotRegistered),
+            |(src, _)| src.local_addr(),
+        )
+    }
+}
+
+// Whether a datagram from `source` may have been sent by the relay announced as `relay`. An
+// unspecified address or port in the reply matches any.
+fn from_relay(source: &TargetAddr, relay: &TargetAddr) -> bool {
+    let source = match source {
+        TargetAddr::Ip(source) => source,
+        TargetAddr::Domain(..) => return true,
+    };
+    let (ip, port) = match relay {
+        TargetAddr::Ip(relay) => (Some(relay.ip()), relay.port()),
+        // The addresses of a domain aren't known without resolving it.
+        TargetAddr::Domain(_, port) => (None, *port),
+    };
+
+    let ip_matches =
+        ip.is_none_or(|ip| ip.is_unspecified() || ip.to_canonical() == source.ip().to_canonical());
+    ip_matches && (port == 0 || port == source.port())
+}
+
+impl<S, U> AsyncRead for NoAuthentication<S, U>
+where
+    S: AsyncRead + Unpin,
+    U: Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.deref_mut();
+        Pin::new(&mut self.socket).poll_read(cx, buf)
+    }
+}
+
+impl<S, U> AsyncWrite for NoAuthentication<S, U>
+where
+    S: AsyncWrite + Unpin,
+    U: Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.socket).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.socket).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.socket.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl<S, U> Method for NoAuthentication<S, U>
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send,
+    U: AsyncDatagram + Unpin + Send,
+{
+    type Stream = S;
+    type Datagram = U;
+    async fn create(socket: S, _: u8, config: &Socks5Config) -> Result<Self> {
+        Ok(Self {
+            socket,
+            endpoints: None,
+            accept_foreign: config.quirks.foreign_relay_source,
+        })
+    }
+
+    async fn handshake(&mut self, _: &Socks5Config) -> Result<()> {
+        Ok(())
+    }
+
+    async fn register_endpoints(&mut self, src: Self::Datagram, dst: TargetAddr) -> Result<()> {
+        self.endpoints = Some((src, dst));
+        Ok(())
+    }
+
+    fn codes(_: &Socks5Config) -> Vec<u8> {
+        vec![0x00]
+    }
+
+    fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    fn into_parts(self) -> (S, Option<U>) {
+        (self.socket, self.endpoints.map(|(src, _)| src))
+    }
+}
+
+/// Offer the methods of both `L` and `R` in one greeting, preferring `L`, and run whichever the
+/// proxy selects.
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+impl<L, R> AsyncDatagram for Either<L, R>
+where
+    L: AsyncDatagram,
+    R: AsyncDatagram,
+{
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self {
+            Either::Left(method) => method.poll_send_ready(cx),
+            Either::Right(method) => method.poll_send_ready(cx),
+        }
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self {
+            Either::Left(method) => method.poll_recv_ready(cx),
+            Either::Right(method) => method.poll_recv_ready(cx),
+        }
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        match self {
+            Either::Left(method) => method.poll_send_to(cx, buf, target),
+            Either::Right(method) => method.poll_send_to(cx, buf, target),
+        }
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        match self {
+            Either::Left(method) => method.poll_recv_from(cx, buf),
+            Either::Right(method) => method.poll_recv_from(cx, buf),
+        }
+    }
+
+    fn poll_send_many(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[IoSlice<'_>],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        match self {
+            Either::Left(method) => method.poll_send_many(cx, packets, target),
+            Either::Right(method) => method.poll_send_many(cx, packets, target),
+        }
+    }
+
+    fn poll_recv_many(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        match self {
+            Either::Left(method) => method.poll_recv_many(cx, bufs),
+            Either::Right(method) => method.poll_recv_many(cx, bufs),
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Either::Left(method) => method.local_addr(),
+            Either::Right(method) => method.local_addr(),
+        }
+    }
+}
+
+impl<L, R> AsyncRead for Either<L, R>
+where
+    L: AsyncRead + Unpin,
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Either::Left(method) => Pin::new(method).poll_read(cx, buf),
+            Either::Right(method) => Pin::new(method).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<L, R> AsyncWrite for Either<L, R>
+where
+    L: AsyncWrite + Unpin,
+    R: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            Either::Left(method) => Pin::new(method).poll_write(cx, buf),
+            Either::Right(method) => Pin::new(method).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut *self {
+            Either::Left(method) => Pin::new(method).poll_write_vectored(cx, bufs),
+            Either::Right(method) => Pin::new(method).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Either::Left(method) => method.is_write_vectored(),
+            Either::Right(method) => method.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Either::Left(method) => Pin::new(method).poll_flush(cx),
+            Either::Right(method) => Pin::new(method).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut *self {
+            Either::Left(method) => Pin::new(method).poll_shutdown(cx),
+            Either::Right(method) => Pin::new(method).poll_shutdown(cx),
+        }
+    }
+}
+
+#[async_trait]
+impl<L, R> Method for Either<L, R>
+where
+    L: Method,
+    L::Stream: Send,
+    L::Datagram: Send,
+    R: Method<Stream = L::Stream, Datagram = L::Datagram>,
+{
+    type Stream = L::Stream;
+    type Datagram = L::Datagram;
+
+    async fn create(socket: Self::Stream, code: u8, config: &Socks5Config) -> Result<Self> {
+        if L::codes(config).contains(&code) {
+            Ok(Either::Left(L::create(socket, code, config).await?))
+        } else {
+            Ok(Either::Right(R::create(socket, code, config).await?))
+        }
+    }
+
+    async fn handshake(&mut self, config: &Socks5Config) -> Result<()> {
+        match self {
+            Either::Left(method) => method.handshake(config).await,
+            Either::Right(method) => method.handshake(config).await,
+        }
+    }
+
+    async fn register_endpoints(&mut self, src: Self::Datagram, dst: TargetAddr) -> Result<()> {
+        match self {
+            Either::Left(method) => method.register_endpoints(src, dst).await,
+            Either::Right(method) => method.register_endpoints(src, dst).await,
+        }
+    }
+
+    fn codes(config: &Socks5Config) -> Vec<u8> {
+        let mut codes = L::codes(config);
+        for code in R::codes(config) {
+            if !codes.contains(&code) {
+                codes.push(code);
+            }
+        }
+        codes
+    }
+
+    fn encapsulates(&self) -> bool {
+        match self {
+            Either::Left(method) => method.encapsulates(),
+            Either::Right(method) => method.encapsulates(),
+        }
+    }
+
+    fn encapsulate(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Either::Left(method) => method.encapsulate(data),
+            Either::Right(method) => method.encapsulate(data),
+        }
+    }
+
+    fn decapsulate(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        match self {
+            Either::Left(method) => method.decapsulate(buf),
+            Either::Right(method) => method.decapsulate(buf),
+        }
+    }
+
+    fn encapsulate_datagram(&self, packet: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Either::Left(method) => method.encapsulate_datagram(packet),
+            Either::Right(method) => method.encapsulate_datagram(packet),
+        }
+    }
+
+    fn decapsulate_datagram(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Either::Left(method) => method.decapsulate_datagram(packet),
+            Either::Right(method) => method.decapsulate_datagram(packet),
+        }
+    }
+
+    fn get_ref(&self) -> &Self::Stream {
+        match self {
+            Either::Left(method) => method.get_ref(),
+            Either::Right(method) => method.get_ref(),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut Self::Stream {
+        match self {
+            Either::Left(method) => method.get_mut(),
+            Either::Right(method) => method.get_mut(),
+        }
+    }
+
+    fn into_parts(self) -> (Self::Stream, Option<Self::Datagram>) {
+        match self {
+            Either::Left(method) => method.into_parts(),
+            Either::Right(method) => method.into_parts(),
+        }
+    }
+}
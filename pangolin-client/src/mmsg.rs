@@ -0,0 +1,105 @@
+//! `sendmmsg`/`recvmmsg` for `UdpSocket`, moving several datagrams per system call.
+
+use std::io::{self, IoSlice};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use socket2::SockAddr;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+
+// The most datagrams moved by one system call.
+const MAX_BATCH: usize = 64;
+
+pub(crate) fn send(
+    socket: &UdpSocket,
+    packets: &[IoSlice<'_>],
+    target: SocketAddr,
+) -> io::Result<usize> {
+    let target = SockAddr::from(target);
+    let mut msgs: Vec<libc::mmsghdr> = packets
+        .iter()
+        .take(MAX_BATCH)
+        .map(|packet| {
+            // Safety: all-zero is a valid `mmsghdr`.
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = target.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = target.len();
+            // `IoSlice` has the layout of `iovec` on Unix, and `sendmmsg` doesn't write through it.
+            msg.msg_hdr.msg_iov = packet as *const IoSlice<'_> as *mut libc::iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    // Safety: every message points at a live packet and at `target`.
+    let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+pub(crate) fn recv(socket: &UdpSocket, bufs: &mut [ReadBuf<'_>]) -> io::Result<Vec<SocketAddr>> {
+    let batch = bufs.len().min(MAX_BATCH);
+    let mut iovecs: Vec<libc::iovec> = bufs[..batch]
+        .iter_mut()
+        .map(|buf| {
+            // Safety: `recvmmsg` only writes initialized bytes into the unfilled part.
+            let unfilled = unsafe { buf.unfilled_mut() };
+            libc::iovec {
+                iov_base: unfilled.as_mut_ptr() as *mut libc::c_void,
+                iov_len: unfilled.len(),
+            }
+        })
+        .collect();
+    // Safety: all-zero is a valid `sockaddr_storage`.
+    let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; batch];
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(names.iter_mut())
+        .map(|(iovec, name)| {
+            // Safety: all-zero is a valid `mmsghdr`.
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    // Safety: every message points at the unfilled part of a buffer and at a `sockaddr_storage`.
+    let received = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as _,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    msgs[..received as usize]
+        .iter()
+        .zip(bufs.iter_mut())
+        .zip(names)
+        .map(|((msg, buf), name)| {
+            let len = msg.msg_len as usize;
+            // Safety: the kernel wrote `len` bytes into the unfilled part.
+            unsafe { buf.assume_init(len) };
+            buf.advance(len);
+
+            // Safety: the kernel wrote a socket address of `msg_namelen` bytes into `name`.
+            let source = unsafe { SockAddr::new(name, msg.msg_hdr.msg_namelen) };
+            source
+                .as_socket()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP source"))
+        })
+        .collect()
+}
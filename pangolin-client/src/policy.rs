@@ -0,0 +1,217 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::{Result, Socks5Error, TargetAddr};
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8` or `fe80::/10`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(Socks5Error::InvalidTargetAddress);
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Match IPv4-mapped IPv6 addresses against IPv4 networks.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Socks5Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| Socks5Error::InvalidTargetAddress)?;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .map_err(|_| Socks5Error::InvalidTargetAddress)?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// The peer a BIND listener expects to connect, e.g. the data host negotiated by FTP, checked
+/// against the address the proxy reports for the incoming connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExpectedPeer {
+    /// Exactly this address, port included.
+    Addr(SocketAddr),
+    /// Any address of this network, on any port.
+    Net(IpNet),
+}
+
+impl ExpectedPeer {
+    /// Whether `peer` is expected. A domain, which proxies don't report for incoming
+    /// connections, never is.
+    pub fn matches(&self, peer: &TargetAddr) -> bool {
+        match (self, peer) {
+            (ExpectedPeer::Addr(addr), TargetAddr::Ip(peer)) => addr == peer,
+            (ExpectedPeer::Net(net), TargetAddr::Ip(peer)) => net.contains(peer.ip()),
+            (_, TargetAddr::Domain(..)) => false,
+        }
+    }
+}
+
+impl From<SocketAddr> for ExpectedPeer {
+    fn from(addr: SocketAddr) -> Self {
+        ExpectedPeer::Addr(addr)
+    }
+}
+
+impl From<IpNet> for ExpectedPeer {
+    fn from(net: IpNet) -> Self {
+        ExpectedPeer::Net(net)
+    }
+}
+
+/// Client-side guardrails on the targets reached through a proxy, checked on every `connect` and
+/// `send_to` before anything is sent, for applications that pass user-controlled hosts to
+/// pangolin.
+///
+/// A target is refused if it matches a deny rule, or if there are allow rules and it matches none
+/// of them. Domain rules match the domain and its subdomains. Domains resolved by the proxy can
+/// only be checked against the domain rules, so IP rules are best combined with
+/// `Resolution::Local`.
+#[derive(Debug, Clone, Default)]
+pub struct TargetPolicy {
+    allowed_nets: Vec<IpNet>,
+    allowed_domains: Vec<String>,
+    denied_nets: Vec<IpNet>,
+    denied_domains: Vec<String>,
+}
+
+impl TargetPolicy {
+    /// A policy allowing every target.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy denying loopback, private, link-local (including cloud metadata endpoints),
+    /// shared and unspecified addresses, as well as `localhost`.
+    pub fn deny_private() -> Self {
+        let mut policy = Self::new();
+        for net in &[
+            "0.0.0.0/8",
+            "10.0.0.0/8",
+            "100.64.0.0/10",
+            "127.0.0.0/8",
+            "169.254.0.0/16",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "::/128",
+            "::1/128",
+            "fc00::/7",
+            "fe80::/10",
+        ] {
+            policy.deny_net(net.parse().expect("valid network"));
+        }
+        policy.deny_domain("localhost");
+        policy.deny_domain("metadata.google.internal");
+        policy
+    }
+
+    pub fn allow_net(&mut self, net: IpNet) -> &mut Self {
+        self.allowed_nets.push(net);
+        self
+    }
+
+    pub fn allow_domain(&mut self, domain: &str) -> &mut Self {
+        self.allowed_domains.push(normalize(domain));
+        self
+    }
+
+    pub fn deny_net(&mut self, net: IpNet) -> &mut Self {
+        self.denied_nets.push(net);
+        self
+    }
+
+    pub fn deny_domain(&mut self, domain: &str) -> &mut Self {
+        self.denied_domains.push(normalize(domain));
+        self
+    }
+
+    pub fn is_allowed(&self, target: &TargetAddr) -> bool {
+        let (denied, allowed) = match target {
+            TargetAddr::Ip(addr) => self.match_ip(addr.ip()),
+            TargetAddr::Domain(domain, _) => match domain.parse::<IpAddr>() {
+                Ok(ip) => self.match_ip(ip),
+                Err(_) => self.match_domain(domain),
+            },
+        };
+        let has_allow_rules = !self.allowed_nets.is_empty() || !self.allowed_domains.is_empty();
+        !denied && (allowed || !has_allow_rules)
+    }
+
+    /// Fail with `Socks5Error::TargetDenied` if `target` isn't allowed.
+    pub fn check(&self, target: &TargetAddr) -> Result<()> {
+        if self.is_allowed(target) {
+            Ok(())
+        } else {
+            Err(Socks5Error::TargetDenied)
+        }
+    }
+
+    fn match_ip(&self, ip: IpAddr) -> (bool, bool) {
+        (
+            self.denied_nets.iter().any(|net| net.contains(ip)),
+            self.allowed_nets.iter().any(|net| net.contains(ip)),
+        )
+    }
+
+    fn match_domain(&self, domain: &str) -> (bool, bool) {
+        let domain = normalize(domain);
+        let matches = |rule: &String| {
+            domain == *rule
+                || domain
+                    .strip_suffix(rule.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        };
+        (
+            self.denied_domains.iter().any(matches),
+            self.allowed_domains.iter().any(matches),
+        )
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
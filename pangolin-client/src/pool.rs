@@ -0,0 +1,139 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::client::Payload;
+
+// How many idle buffers a pool keeps by default.
+const DEFAULT_MAX_IDLE: usize = 64;
+
+/// Receive buffers reused by `Socks5Datagram::recv_from_pooled`, so that receiving a datagram
+/// doesn't allocate once the pool is warm. Clones share the same buffers.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    idle: Mutex<Vec<Vec<u8>>>,
+    max_idle: usize,
+    buffer_size: usize,
+}
+
+impl BufferPool {
+    /// A pool keeping at most `max_idle` buffers of `buffer_size` bytes while they're unused.
+    pub fn new(max_idle: usize, buffer_size: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                idle: Mutex::new(Vec::new()),
+                max_idle,
+                buffer_size,
+            }),
+        }
+    }
+
+    /// How many buffers are waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.lock_idle().len()
+    }
+
+    pub(crate) fn take(&self) -> Vec<u8> {
+        match self.lock_idle().pop() {
+            Some(buf) => buf,
+            None => vec![0; self.inner.buffer_size],
+        }
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        let mut idle = self.lock_idle();
+        if idle.len() < self.inner.max_idle {
+            idle.push(buf);
+        }
+    }
+
+    fn lock_idle(&self) -> MutexGuard<'_, Vec<Vec<u8>>> {
+        self.inner
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for BufferPool {
+    /// Buffers large enough for any UDP datagram.
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_IDLE, crate::datagram::RECV_BUFFER_SIZE)
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("idle", &self.idle())
+            .field("max_idle", &self.inner.max_idle)
+            .field("buffer_size", &self.inner.buffer_size)
+            .finish()
+    }
+}
+
+/// The payload of a datagram received with `Socks5Datagram::recv_from_pooled`. Its buffer goes
+/// back to the pool when it is dropped.
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    range: Range<usize>,
+    pool: Option<BufferPool>,
+}
+
+impl PooledBuf {
+    pub(crate) fn new(buf: Vec<u8>, payload: Payload, pool: &BufferPool) -> Self {
+        match payload {
+            Payload::Range(range) => Self {
+                buf,
+                range,
+                pool: Some(pool.clone()),
+            },
+            // The payload was built elsewhere, so the receive buffer can be reused at once.
+            Payload::Owned(payload) => {
+                pool.put(buf);
+                Self {
+                    range: 0..payload.len(),
+                    buf: payload,
+                    pool: None,
+                }
+            }
+        }
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.buf[self.range.clone()]
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for PooledBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PooledBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PooledBuf").field(&&**self).finish()
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(std::mem::take(&mut self.buf));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{AsyncUdpSocket, Endpoint, EndpointConfig, ServerConfig, TokioRuntime, UdpPoller};
+use tokio::io::ReadBuf;
+
+use crate::{Method, Socks5Datagram, Socks5Error, TargetAddr};
+
+/// Runs a quinn `Endpoint` over a UDP association, so that QUIC, e.g. HTTP/3, goes through the
+/// proxy: every QUIC packet travels as the payload of a SOCKS datagram.
+///
+/// QUIC addresses its peers by IP, so packets the relay reports coming from a domain are dropped,
+/// as are the ones truncated on receive. Packets the proxy would need to fragment are sent as
+/// far as the socket takes them without waiting, like any UDP packet may be lost.
+pub struct QuicSocket<M> {
+    datagram: Socks5Datagram<M>,
+}
+
+impl<M> QuicSocket<M>
+where
+    M: Method + 'static,
+{
+    pub fn new(datagram: Socks5Datagram<M>) -> Self {
+        Self { datagram }
+    }
+
+    /// Create an endpoint over the association, accepting connections too with a
+    /// `server_config`.
+    pub fn endpoint(
+        self,
+        config: EndpointConfig,
+        server_config: Option<ServerConfig>,
+    ) -> io::Result<Endpoint> {
+        Endpoint::new_with_abstract_socket(
+            config,
+            server_config,
+            Arc::new(self),
+            Arc::new(TokioRuntime),
+        )
+    }
+
+    pub fn get_ref(&self) -> &Socks5Datagram<M> {
+        &self.datagram
+    }
+
+    pub fn into_inner(self) -> Socks5Datagram<M> {
+        self.datagram
+    }
+}
+
+impl<M> fmt::Debug for QuicSocket<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicSocket")
+            .field("relay_addr", self.datagram.relay_addr())
+            .finish()
+    }
+}
+
+impl<M> AsyncUdpSocket for QuicSocket<M>
+where
+    M: Method + 'static,
+{
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(Writable { socket: self })
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        let target = TargetAddr::Ip(transmit.destination);
+        self.datagram.check_target(&target)?;
+        let packets = self.datagram.pack(transmit.contents, &target)?;
+
+        // Polled once, without a task to wake: `Writable` waits for the socket instead.
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut sent = 0;
+        match self
+            .datagram
+            .poll_send_packets(&mut cx, &packets, &target, &mut sent)
+        {
+            Poll::Ready(result) => Ok(result?),
+            Poll::Pending if sent == 0 => Err(io::ErrorKind::WouldBlock.into()),
+            Poll::Pending => Ok(()),
+        }
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut buf = ReadBuf::new(&mut bufs[0]);
+            let source = match self.datagram.poll_recv_from(cx, &mut buf) {
+                Poll::Ready(Ok(TargetAddr::Ip(source))) => source,
+                Poll::Ready(Ok(TargetAddr::Domain(..)))
+                | Poll::Ready(Err(Socks5Error::DatagramTruncated { .. })) => continue,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => return Poll::Pending,
+            };
+            let len = buf.filled().len();
+            meta[0] = RecvMeta {
+                addr: source,
+                len,
+                stride: len,
+                ecn: None,
+                dst_ip: None,
+            };
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.datagram.local_addr()?)
+    }
+}
+
+// Waits for the socket under the association to be writable again.
+struct Writable<M> {
+    socket: Arc<QuicSocket<M>>,
+}
+
+impl<M> fmt::Debug for Writable<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Writable")
+            .field("socket", &self.socket)
+            .finish()
+    }
+}
+
+impl<M> UdpPoller for Writable<M>
+where
+    M: Method + 'static,
+{
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.socket
+            .datagram
+            .poll_send_ready(cx)
+            .map_err(io::Error::from)
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+
+use socket2::SockRef;
+use tokio::io::{
+    copy_buf, split, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+use tokio::net::TcpStream;
+
+/// Size of each direction's copy buffer when nothing better is known.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+const MIN_BUFFER_SIZE: usize = 4 * 1024;
+const MAX_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Options of a bidirectional relay between two streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayConfig {
+    buffer_size: usize,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUFFER_SIZE)
+    }
+}
+
+impl RelayConfig {
+    pub fn new(buffer_size: usize) -> Self {
+        Self {
+            buffer_size: buffer_size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE),
+        }
+    }
+
+    /// Size the copy buffers after the kernel receive buffer of `socket`, so that a single read
+    /// can drain whatever the kernel has queued.
+    pub fn tuned_for(socket: &TcpStream) -> io::Result<Self> {
+        Ok(Self::new(SockRef::from(socket).recv_buffer_size()?))
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+}
+
+/// Copy data in both directions between `a` and `b` until both sides reach EOF, returning the
+/// number of bytes copied from `a` to `b` and from `b` to `a`.
+///
+/// The write side of each stream is shut down once its peer has no more data to send.
+pub async fn relay<A, B>(a: A, b: B, config: RelayConfig) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (a_read, a_write) = split(a);
+    let (b_read, b_write) = split(b);
+
+    tokio::try_join!(
+        copy_half(a_read, b_write, config),
+        copy_half(b_read, a_write, config)
+    )
+}
+
+async fn copy_half<R, W>(
+    reader: ReadHalf<R>,
+    mut writer: WriteHalf<W>,
+    config: RelayConfig,
+) -> io::Result<u64>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    let mut reader = BufReader::with_capacity(config.buffer_size, reader);
+    let n = copy_buf(&mut reader, &mut writer).await?;
+    writer.shutdown().await?;
+    Ok(n)
+}
@@ -0,0 +1,254 @@
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
+
+use crate::datagram::AsyncDatagram;
+use crate::proto::Version;
+use crate::{Method, NoAuthentication, Result, Socks5Config, Socks5Error, TargetAddr};
+
+const RESUMPTION_VERSION: Version = Version::new(0x01);
+
+/// The session-resumption token a pangolin server issued, shared by the successive connections of
+/// a client so that its UDP association outlives a brief drop of the control connection.
+///
+/// Stored in `Socks5Config::extensions`, it makes `SessionResumption` offer the private method
+/// `code` (0x80-0xFE), which the server has to be configured with as well. Clones share the
+/// token.
+#[derive(Debug, Clone)]
+pub struct ResumptionToken {
+    code: u8,
+    state: Arc<Mutex<TokenState>>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    token: Option<Vec<u8>>,
+    resumed: bool,
+}
+
+impl ResumptionToken {
+    pub fn new(code: u8) -> Self {
+        Self {
+            code,
+            state: Arc::default(),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    /// Whether the last handshake re-attached the association of the token, instead of getting
+    /// a new token.
+    pub fn resumed(&self) -> bool {
+        self.lock().resumed
+    }
+
+    /// Forget the token, so that the next handshake starts a new session.
+    pub fn clear(&self) {
+        *self.lock() = TokenState::default();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TokenState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A private method resuming the sessions of a pangolin server, with the `ResumptionToken` of
+/// `Socks5Config::extensions`. It offers nothing without one.
+///
+/// The client presents its token, if it has one, and the server replies with the token of the
+/// session, which re-attached the association of the presented token if it was still parked. The
+/// tunnel is then the same as with no authentication.
+pub struct SessionResumption<S, U = UdpSocket> {
+    inner: NoAuthentication<S, U>,
+}
+
+impl<S, U> AsyncDatagram for SessionResumption<S, U>
+where
+    U: AsyncDatagram,
+{
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_send_ready(cx)
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_recv_ready(cx)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.inner.poll_send_to(cx, buf, target)
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        self.inner.poll_recv_from(cx, buf)
+    }
+
+    fn poll_send_many(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[IoSlice<'_>],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.inner.poll_send_many(cx, packets, target)
+    }
+
+    fn poll_recv_many(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        self.inner.poll_recv_many(cx, bufs)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl<S, U> AsyncRead for SessionResumption<S, U>
+where
+    S: AsyncRead + Unpin,
+    U: Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S, U> AsyncWrite for SessionResumption<S, U>
+where
+    S: AsyncWrite + Unpin,
+    U: Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl<S, U> Method for SessionResumption<S, U>
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send,
+    U: AsyncDatagram + Unpin + Send,
+{
+    type Stream = S;
+    type Datagram = U;
+
+    async fn create(socket: S, code: u8, config: &Socks5Config) -> Result<Self> {
+        Ok(Self {
+            inner: NoAuthentication::create(socket, code, config).await?,
+        })
+    }
+
+    async fn handshake(&mut self, config: &Socks5Config) -> Result<()> {
+        let token = config
+            .extensions
+            .get::<ResumptionToken>()
+            .ok_or(Socks5Error::NoAcceptableMethod)?;
+
+        // +----+------+----------+
+        // |VER | TLEN |  TOKEN   |
+        // +----+------+----------+
+        // | 1  |  1   | 0 to 255 |
+        // +----+------+----------+
+        let presented = token.lock().token.clone().unwrap_or_default();
+        let mut buf = Vec::with_capacity(2 + presented.len());
+        buf.push(RESUMPTION_VERSION.as_u8());
+        buf.push(presented.len() as u8);
+        buf.extend_from_slice(&presented);
+        self.inner.write_all(&buf).await?;
+
+        // +----+--------+------+----------+
+        // |VER | STATUS | TLEN |  TOKEN   |
+        // +----+--------+------+----------+
+        // | 1  |   1    |  1   | 1 to 255 |
+        // +----+--------+------+----------+
+        let mut buf = [0; 3];
+        self.inner.read_exact(&mut buf).await?;
+        RESUMPTION_VERSION.check(buf[0], config.strictness)?;
+        let mut issued = vec![0; buf[2] as usize];
+        self.inner.read_exact(&mut issued).await?;
+        let resumed = match buf[1] {
+            0x00 => true,
+            0x01 => false,
+            _ => return Err(Socks5Error::AuthenticationFailed),
+        };
+        if issued.is_empty() {
+            return Err(Socks5Error::AuthenticationFailed);
+        }
+
+        *token.lock() = TokenState {
+            token: Some(issued),
+            resumed,
+        };
+        Ok(())
+    }
+
+    async fn register_endpoints(&mut self, src: Self::Datagram, dst: TargetAddr) -> Result<()> {
+        self.inner.register_endpoints(src, dst).await
+    }
+
+    fn codes(config: &Socks5Config) -> Vec<u8> {
+        config
+            .extensions
+            .get::<ResumptionToken>()
+            .map(|token| vec![token.code])
+            .unwrap_or_default()
+    }
+
+    fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+
+    fn into_parts(self) -> (S, Option<U>) {
+        self.inner.into_parts()
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::time::Duration;
+
+use crate::{HandshakePhase, Result, Socks5Error};
+
+/// How `Socks5Stream::connect_with_retry` retries transient failures, with exponential backoff.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included.
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled after each retry.
+    pub initial_backoff: Duration,
+    /// The cap on the delay between attempts.
+    pub max_backoff: Duration,
+    /// Wait a random delay between half and all of the backoff, so that clients failing together
+    /// don't retry together.
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry`, counting from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .checked_mul(1 << retry.min(31))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+
+        let fraction = RandomState::new().hash_one(retry) as f64 / u64::MAX as f64;
+        backoff.mul_f64(0.5 + fraction / 2.0)
+    }
+
+    /// Whether an attempt that failed with `error` may be retried.
+    ///
+    /// Only failures that happened before the request reached the proxy, or that the proxy
+    /// reported as a failure to carry it out, are retried: once a request is sent, a lost reply
+    /// doesn't tell whether the proxy acted on it.
+    pub fn is_transient(error: &Socks5Error) -> bool {
+        match error {
+            // Not tagged with a phase, e.g. failing to connect to the proxy.
+            Socks5Error::Io(_) => true,
+            Socks5Error::ProxyClosedDuringHandshake { phase }
+            | Socks5Error::IoDuring { phase, .. }
+            | Socks5Error::Timeout { phase } => {
+                !matches!(phase, HandshakePhase::Request | HandshakePhase::Accept)
+            }
+            Socks5Error::GeneralSocksServerFailure | Socks5Error::TtlExpired => true,
+            Socks5Error::BindFailed { reason, .. } | Socks5Error::Session { reason, .. } => {
+                Self::is_transient(reason)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts, 100ms apart at first.
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+/// Run `attempt` until it succeeds, fails for good, or `policy` runs out of attempts.
+pub(crate) async fn retry<T, F, Fut>(policy: &RetryPolicy, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retry = 0;
+    loop {
+        match attempt().await {
+            Err(e) if retry + 1 < policy.max_attempts && RetryPolicy::is_transient(&e) => {
+                tokio::time::sleep(policy.backoff(retry)).await;
+                retry += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn retries_io_errors_only_before_the_request() {
+        let timed_out = || Socks5Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(RetryPolicy::is_transient(&timed_out()));
+        assert!(RetryPolicy::is_transient(
+            &timed_out().during(HandshakePhase::MethodSelection)
+        ));
+        assert!(!RetryPolicy::is_transient(
+            &timed_out().during(HandshakePhase::Request).in_session(1)
+        ));
+    }
+}
@@ -0,0 +1,71 @@
+use std::marker::PhantomData;
+
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::{Method, Result, Socks5Config, Socks5Error, Socks5Listener, Socks5Stream, TargetAddr};
+
+/// Accepts connections through a proxy one BIND at a time, e.g. for FTP active mode or reverse
+/// connections: each round binds, advertises the address the proxy listens on, waits for the
+/// incoming connection, hands it over and binds again.
+pub struct ReverseTunnel<M, A> {
+    proxy: A,
+    target_addr: TargetAddr,
+    config: Socks5Config,
+    _method: PhantomData<fn() -> M>,
+}
+
+impl<M, A> ReverseTunnel<M, A>
+where
+    M: Method<Stream = TcpStream>,
+    A: ToSocketAddrs + Clone,
+{
+    /// Bind through the proxy at `proxy`, expecting connections from `target_addr`.
+    pub fn new(proxy: A, target_addr: TargetAddr) -> Self {
+        Self::with_config(proxy, target_addr, Socks5Config::default())
+    }
+
+    pub fn with_config(proxy: A, target_addr: TargetAddr, config: Socks5Config) -> Self {
+        Self {
+            proxy,
+            target_addr,
+            config,
+            _method: PhantomData,
+        }
+    }
+
+    /// Call `on_bind` with the address of each bind, to be advertised to the peer, and `handler`
+    /// with each connection it receives, which should spawn a task rather than block the next
+    /// bind. Runs until the cancellation of the configuration, or the first failure.
+    pub async fn run<B, H>(&self, mut on_bind: B, mut handler: H) -> Result<()>
+    where
+        B: FnMut(&TargetAddr),
+        H: FnMut(Socks5Stream<M>),
+    {
+        loop {
+            match self.accept_one(&mut on_bind).await {
+                Ok(stream) => handler(stream),
+                Err(e) if matches!(e.root(), Socks5Error::Cancelled) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn accept_one<B>(&self, on_bind: &mut B) -> Result<Socks5Stream<M>>
+    where
+        B: FnMut(&TargetAddr),
+    {
+        let mut listener = Socks5Listener::<M>::bind_with_config(
+            self.proxy.clone(),
+            self.target_addr.clone(),
+            self.config.clone(),
+        )
+        .await?;
+        on_bind(&listener.bind_addr());
+
+        listener.accept().await?;
+        match listener.into_stream() {
+            Ok(stream) => Ok(stream),
+            Err(_) => unreachable!("accepted listener"),
+        }
+    }
+}
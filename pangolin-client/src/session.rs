@@ -0,0 +1,35 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A crate-generated identifier of a tunnel.
+///
+/// Every tunnel gets a fresh id before its handshake starts, and the id is shared by all the
+/// higher level types (`Socks5Stream`, `Socks5Listener`, `Socks5Datagram`) built on top of it, so
+/// logs from different layers can be correlated. The handshake runs in a `socks5` tracing span
+/// recording it as `session`, and fails with errors attributed to it, as
+/// `Socks5Error::Session`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SessionId(u64);
+
+impl SessionId {
+    /// A fresh id, never handed out before.
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        SessionId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    // The span of the handshake of the session.
+    pub(crate) fn span(&self) -> tracing::Span {
+        tracing::debug_span!("socks5", session = self.0)
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "socks5-{}", self.0)
+    }
+}
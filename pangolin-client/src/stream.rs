@@ -0,0 +1,355 @@
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpSocket, TcpStream, ToSocketAddrs};
+
+use crate::{config, limit, retry};
+
+use crate::client::Socks5Client;
+use crate::proto::{Request, RequestType};
+use crate::{
+    Method, ProxyUrl, Result, RetryPolicy, SessionId, SessionPermit, Socks5Config,
+    Socks5StreamBuilder, TargetAddr,
+};
+
+pub struct Socks5Stream<M> {
+    client: Socks5Client<M>,
+    peer_addr: TargetAddr,
+    bound_addr: TargetAddr,
+}
+
+impl<M> Socks5Stream<M> {
+    pub(crate) fn new(
+        client: Socks5Client<M>,
+        peer_addr: TargetAddr,
+        bound_addr: TargetAddr,
+    ) -> Self {
+        Socks5Stream {
+            client,
+            peer_addr,
+            bound_addr,
+        }
+    }
+
+    pub fn peer_addr(&self) -> TargetAddr {
+        self.peer_addr.clone()
+    }
+
+    /// The address the proxy bound for the tunnel (BND.ADDR), as reported in its reply, e.g. its
+    /// egress address.
+    pub fn proxy_bound_addr(&self) -> TargetAddr {
+        self.bound_addr.clone()
+    }
+
+    pub fn session_id(&self) -> SessionId {
+        self.client.session_id()
+    }
+
+    pub(crate) fn hold(&mut self, permit: Option<SessionPermit>) {
+        self.client.hold(permit);
+    }
+}
+
+impl<M> AsyncRead for Socks5Stream<M>
+where
+    M: Method,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.client).poll_read(cx, buf)
+    }
+}
+
+impl<M: Method> AsyncWrite for Socks5Stream<M>
+where
+    M: Method,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.client).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.client).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.client.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.client).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.client).poll_shutdown(cx)
+    }
+}
+
+impl<M> Socks5Stream<M>
+where
+    M: Method,
+{
+    pub async fn connect_with_socket(socket: M::Stream, target_addr: TargetAddr) -> Result<Self> {
+        Self::connect_with_socket_and_config(socket, target_addr, Socks5Config::default()).await
+    }
+
+    pub async fn connect_with_socket_and_config(
+        socket: M::Stream,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        config.check_target(&target_addr)?;
+        let target_addr = config
+            .resolution
+            .resolve_with_family(
+                target_addr,
+                config.dns_cache.as_ref(),
+                config.address_family,
+            )
+            .await?;
+        config.check_target(&target_addr)?;
+        let request = Request::new(RequestType::Connect, target_addr.clone());
+        let (client, bound_addr) =
+            Socks5Client::<M>::connect_and_request(socket, config, request).await?;
+        Ok(Self::new(client, target_addr, bound_addr))
+    }
+
+    /// The socket to the proxy, e.g. to query `TcpStream::peer_addr`.
+    pub fn get_ref(&self) -> &M::Stream {
+        self.client.get_ref()
+    }
+
+    /// The socket to the proxy, e.g. to set `TcpStream::set_nodelay`. Reading or writing it
+    /// directly corrupts the tunnel.
+    pub fn get_mut(&mut self) -> &mut M::Stream {
+        self.client.get_mut()
+    }
+
+    /// Relayed data already read from the socket but not returned yet, e.g. because it arrived
+    /// along with the reply of the proxy.
+    pub fn buffered(&self) -> &[u8] {
+        self.client.buffered()
+    }
+
+    /// Give back the socket to the proxy, e.g. to hand the tunnel to a library that needs a plain
+    /// `TcpStream`. If the method encapsulates the traffic, it stays encapsulated on the socket.
+    /// The data in `buffered` isn't on the socket anymore and is lost.
+    pub fn into_inner(self) -> M::Stream {
+        self.client.into_parts().0
+    }
+}
+
+impl<M> Socks5Stream<M>
+where
+    M: Method<Stream = TcpStream>,
+{
+    /// Receive relayed data without removing it from the stream, like `TcpStream::peek`. Fails if
+    /// the method encapsulates the traffic, since the bytes on the socket aren't the data then.
+    pub async fn peek(&self, buf: &mut [u8]) -> Result<usize> {
+        if self.client.encapsulates() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "peek on an encapsulated stream",
+            )
+            .into());
+        }
+
+        let buffered = self.client.buffered();
+        if !buffered.is_empty() {
+            let n = buf.len().min(buffered.len());
+            buf[..n].copy_from_slice(&buffered[..n]);
+            return Ok(n);
+        }
+        Ok(self.get_ref().peek(buf).await?)
+    }
+
+    /// Disable Nagle's algorithm on the connection to the proxy, like `TcpStream::set_nodelay`.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        Ok(self.get_ref().set_nodelay(nodelay)?)
+    }
+
+    pub fn nodelay(&self) -> Result<bool> {
+        Ok(self.get_ref().nodelay()?)
+    }
+
+    /// Set the IP time-to-live of the packets sent to the proxy.
+    pub fn set_ttl(&self, ttl: u32) -> Result<()> {
+        Ok(self.get_ref().set_ttl(ttl)?)
+    }
+
+    pub fn ttl(&self) -> Result<u32> {
+        Ok(self.get_ref().ttl()?)
+    }
+
+    /// Send TCP keepalive probes once the connection has been idle for `time`, or stop sending
+    /// them if `None`.
+    pub fn set_keepalive(&self, time: Option<Duration>) -> Result<()> {
+        Ok(config::set_keepalive(self.get_ref(), time)?)
+    }
+
+    /// Collect the options of a connection before connecting, instead of picking one of the
+    /// constructors below.
+    pub fn builder() -> Socks5StreamBuilder<M> {
+        Socks5StreamBuilder::new()
+    }
+
+    pub async fn connect<A: ToSocketAddrs>(proxy_addr: A, target_addr: TargetAddr) -> Result<Self> {
+        Self::connect_with_config(proxy_addr, target_addr, Socks5Config::default()).await
+    }
+
+    pub async fn connect_with_config<A: ToSocketAddrs>(
+        proxy_addr: A,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        config.check_target(&target_addr)?;
+        let (socket, permit) = limit::connect(proxy_addr, &config).await?;
+        let mut stream = Self::connect_with_socket_and_config(socket, target_addr, config).await?;
+        stream.client.hold(permit);
+        Ok(stream)
+    }
+
+    pub async fn connect_with_retry<A: ToSocketAddrs + Clone>(
+        policy: &RetryPolicy,
+        proxy_addr: A,
+        target_addr: TargetAddr,
+    ) -> Result<Self> {
+        Self::connect_with_retry_and_config(
+            policy,
+            proxy_addr,
+            target_addr,
+            Socks5Config::default(),
+        )
+        .await
+    }
+
+    /// Like `connect_with_config`, retrying transient failures as `policy` allows.
+    pub async fn connect_with_retry_and_config<A: ToSocketAddrs + Clone>(
+        policy: &RetryPolicy,
+        proxy_addr: A,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        retry::retry(policy, || {
+            Self::connect_with_config(proxy_addr.clone(), target_addr.clone(), config.clone())
+        })
+        .await
+    }
+
+    pub async fn connect_with_deadline<A: ToSocketAddrs>(
+        proxy_addr: A,
+        target_addr: TargetAddr,
+        deadline: Instant,
+    ) -> Result<Self> {
+        Self::connect_with_deadline_and_config(
+            proxy_addr,
+            target_addr,
+            deadline,
+            Socks5Config::default(),
+        )
+        .await
+    }
+
+    /// Like `connect_with_config`, failing with `Socks5Error::Timeout` and the phase it was in if
+    /// the connection to the proxy, the handshake and the request aren't done by `deadline`.
+    pub async fn connect_with_deadline_and_config<A: ToSocketAddrs>(
+        proxy_addr: A,
+        target_addr: TargetAddr,
+        deadline: Instant,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        config.check_target(&target_addr)?;
+        let (socket, permit) = limit::connect_until(proxy_addr, deadline, &config).await?;
+        let config = config.until(deadline);
+        let mut stream = Self::connect_with_socket_and_config(socket, target_addr, config).await?;
+        stream.client.hold(permit);
+        Ok(stream)
+    }
+
+    pub async fn connect_with_tcp_socket(
+        socket: TcpSocket,
+        proxy_addr: SocketAddr,
+        target_addr: TargetAddr,
+    ) -> Result<Self> {
+        Self::connect_with_tcp_socket_and_config(
+            socket,
+            proxy_addr,
+            target_addr,
+            Socks5Config::default(),
+        )
+        .await
+    }
+
+    /// Like `connect_with_config`, reaching the proxy with `socket`, so that options such as
+    /// `SO_REUSEADDR`, buffer sizes or the bound device can be set before connecting.
+    pub async fn connect_with_tcp_socket_and_config(
+        socket: TcpSocket,
+        proxy_addr: SocketAddr,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        config.check_target(&target_addr)?;
+        let (socket, permit) = limit::connect_socket(socket, proxy_addr, &config).await?;
+        let mut stream = Self::connect_with_socket_and_config(socket, target_addr, config).await?;
+        stream.client.hold(permit);
+        Ok(stream)
+    }
+
+    pub async fn connect_with_url(url: &ProxyUrl, target_addr: TargetAddr) -> Result<Self> {
+        Self::connect_with_url_and_config(url, target_addr, Socks5Config::default()).await
+    }
+
+    /// Connect through the proxy of `url`, whose resolution and credentials override `config`.
+    pub async fn connect_with_url_and_config(
+        url: &ProxyUrl,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        let config = url.configure(&config);
+        config.check_target(&target_addr)?;
+        let (socket, permit) = url.connect(&config).await?;
+        let mut stream = Self::connect_with_socket_and_config(socket, target_addr, config).await?;
+        stream.client.hold(permit);
+        Ok(stream)
+    }
+}
+
+#[cfg(unix)]
+impl<M> Socks5Stream<M>
+where
+    M: Method<Stream = UnixStream>,
+{
+    pub async fn connect_unix<P: AsRef<Path>>(path: P, target_addr: TargetAddr) -> Result<Self> {
+        Self::connect_unix_with_config(path, target_addr, Socks5Config::default()).await
+    }
+
+    /// Connect through the proxy listening on the Unix socket at `path`.
+    pub async fn connect_unix_with_config<P: AsRef<Path>>(
+        path: P,
+        target_addr: TargetAddr,
+        config: Socks5Config,
+    ) -> Result<Self> {
+        config.check_target(&target_addr)?;
+        let socket = UnixStream::connect(path).await?;
+        Self::connect_with_socket_and_config(socket, target_addr, config).await
+    }
+}
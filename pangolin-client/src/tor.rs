@@ -0,0 +1,68 @@
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{CredentialProvider, Credentials, Result, Socks5Config};
+
+/// Derives username/password pairs that put streams on separate Tor circuits, following Tor's
+/// `IsolateSOCKSAuth` behavior: streams are only ever shared by circuits when they authenticated
+/// with the same credentials.
+///
+/// Used as a `CredentialProvider`, every handshake gets a circuit of its own. Clones share their
+/// counter, so they never hand out the same pair twice.
+#[derive(Debug, Clone)]
+pub struct TorIsolation {
+    prefix: String,
+    next: Arc<AtomicU64>,
+}
+
+impl TorIsolation {
+    /// Derive credentials prefixed with the process id, so that other processes using the same
+    /// Tor client are isolated as well.
+    pub fn new() -> Self {
+        Self::with_prefix(format!("pangolin-{}", process::id()))
+    }
+
+    pub fn with_prefix<P: Into<String>>(prefix: P) -> Self {
+        Self {
+            prefix: prefix.into(),
+            next: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The credentials of the logical session `key`: streams opened with the same key may share
+    /// a circuit, streams with different keys never do.
+    pub fn credentials_for(&self, key: &str) -> Credentials {
+        Credentials::new(self.prefix.as_str(), key)
+    }
+
+    /// The credentials of a new isolation group.
+    pub fn next_credentials(&self) -> Credentials {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        Credentials::new(self.prefix.as_str(), format!("isolated-{}", n))
+    }
+
+    /// A copy of `config` confined to the logical session `key`.
+    pub fn isolate(&self, key: &str, config: &Socks5Config) -> Socks5Config {
+        Socks5Config {
+            credentials: Some(self.credentials_for(key)),
+            credential_provider: None,
+            ..config.clone()
+        }
+    }
+}
+
+impl Default for TorIsolation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for TorIsolation {
+    async fn fetch(&self) -> Result<Credentials> {
+        Ok(self.next_credentials())
+    }
+}
@@ -0,0 +1,316 @@
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UdpSocket;
+
+use crate::datagram::AsyncDatagram;
+use crate::proto::Version;
+use crate::{Method, NoAuthentication, Result, Socks5Config, Socks5Error, TargetAddr};
+
+const USERPASS_VERSION: Version = Version::new(0x01);
+
+/// A username and password for the username/password method.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl Credentials {
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// A source of credentials consulted on every username/password handshake, so that they can be
+/// rotated without rebuilding the configuration.
+#[async_trait]
+pub trait CredentialProvider: Send + Sync {
+    /// The credentials to authenticate the next handshake with.
+    async fn fetch(&self) -> Result<Credentials>;
+
+    /// Called when the proxy rejected `credentials`, as returned by `fetch`.
+    fn invalidate(&self, _credentials: &Credentials) {}
+}
+
+impl fmt::Debug for dyn CredentialProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CredentialProvider")
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for Credentials {
+    async fn fetch(&self) -> Result<Credentials> {
+        Ok(self.clone())
+    }
+}
+
+/// Cache the credentials of another provider for `ttl`, or until the proxy rejects them.
+pub struct CachedCredentials<P> {
+    provider: P,
+    ttl: Duration,
+    cached: Mutex<Option<(Credentials, Instant)>>,
+}
+
+impl<P> CachedCredentials<P> {
+    pub fn new(provider: P, ttl: Duration) -> Self {
+        Self {
+            provider,
+            ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Drop the cached credentials, so that the next handshake fetches new ones.
+    pub fn clear(&self) {
+        *self.cached.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+#[async_trait]
+impl<P> CredentialProvider for CachedCredentials<P>
+where
+    P: CredentialProvider,
+{
+    async fn fetch(&self) -> Result<Credentials> {
+        if let Some((credentials, fetched_at)) =
+            &*self.cached.lock().unwrap_or_else(PoisonError::into_inner)
+        {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(credentials.clone());
+            }
+        }
+
+        let credentials = self.provider.fetch().await?;
+        *self.cached.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some((credentials.clone(), Instant::now()));
+        Ok(credentials)
+    }
+
+    fn invalidate(&self, credentials: &Credentials) {
+        {
+            let mut cached = self.cached.lock().unwrap_or_else(PoisonError::into_inner);
+            if matches!(&*cached, Some((c, _)) if c == credentials) {
+                *cached = None;
+            }
+        }
+        self.provider.invalidate(credentials);
+    }
+}
+
+/// The username/password method (RFC 1929), authenticating with the credentials of
+/// `Socks5Config::credential_provider` if set, or else with `Socks5Config::credentials`.
+pub struct UsernamePassword<S, U = UdpSocket> {
+    inner: NoAuthentication<S, U>,
+}
+
+impl<S, U> AsyncDatagram for UsernamePassword<S, U>
+where
+    U: AsyncDatagram,
+{
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_send_ready(cx)
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_recv_ready(cx)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.inner.poll_send_to(cx, buf, target)
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        self.inner.poll_recv_from(cx, buf)
+    }
+
+    fn poll_send_many(
+        &self,
+        cx: &mut Context<'_>,
+        packets: &[IoSlice<'_>],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        self.inner.poll_send_many(cx, packets, target)
+    }
+
+    fn poll_recv_many(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &mut [ReadBuf<'_>],
+    ) -> Poll<Result<Vec<TargetAddr>>> {
+        self.inner.poll_recv_many(cx, bufs)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+impl<S, U> AsyncRead for UsernamePassword<S, U>
+where
+    S: AsyncRead + Unpin,
+    U: Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S, U> AsyncWrite for UsernamePassword<S, U>
+where
+    S: AsyncWrite + Unpin,
+    U: Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl<S, U> Method for UsernamePassword<S, U>
+where
+    S: AsyncWrite + AsyncRead + Unpin + Send,
+    U: AsyncDatagram + Unpin + Send,
+{
+    type Stream = S;
+    type Datagram = U;
+
+    async fn create(socket: S, code: u8, config: &Socks5Config) -> Result<Self> {
+        Ok(Self {
+            inner: NoAuthentication::create(socket, code, config).await?,
+        })
+    }
+
+    async fn handshake(&mut self, config: &Socks5Config) -> Result<()> {
+        let credentials = match &config.credential_provider {
+            Some(provider) => provider.fetch().await?,
+            None => config
+                .credentials
+                .clone()
+                .ok_or(Socks5Error::MissingCredentials)?,
+        };
+
+        let username = credentials.username().as_bytes();
+        let password = credentials.password().as_bytes();
+        if username.len() > 255 || password.len() > 255 {
+            return Err(Socks5Error::CredentialsTooLong);
+        }
+
+        // +----+------+----------+------+----------+
+        // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+        // +----+------+----------+------+----------+
+        // | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+        // +----+------+----------+------+----------+
+        let mut buf = Vec::with_capacity(3 + username.len() + password.len());
+        buf.push(USERPASS_VERSION.as_u8());
+        buf.push(username.len() as u8);
+        buf.extend_from_slice(username);
+        buf.push(password.len() as u8);
+        buf.extend_from_slice(password);
+        self.inner.write_all(&buf).await?;
+
+        // +----+--------+
+        // |VER | STATUS |
+        // +----+--------+
+        // | 1  |   1    |
+        // +----+--------+
+        let mut buf = [0; 2];
+        self.inner.read_exact(&mut buf).await?;
+
+        USERPASS_VERSION.check(buf[0], config.strictness)?;
+
+        if buf[1] != 0x00 {
+            if let Some(provider) = &config.credential_provider {
+                provider.invalidate(&credentials);
+            }
+            return Err(Socks5Error::AuthenticationFailed);
+        }
+
+        Ok(())
+    }
+
+    async fn register_endpoints(&mut self, src: Self::Datagram, dst: TargetAddr) -> Result<()> {
+        self.inner.register_endpoints(src, dst).await
+    }
+
+    fn codes(_: &Socks5Config) -> Vec<u8> {
+        vec![0x02]
+    }
+
+    fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+
+    fn into_parts(self) -> (S, Option<U>) {
+        self.inner.into_parts()
+    }
+}
@@ -0,0 +1,106 @@
+use std::convert::{TryFrom, TryInto};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+
+use crate::{Result, Socks5Error};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    // Shared so that cloning a target, which happens on every datagram sent, doesn't allocate.
+    Domain(Arc<str>, u16),
+}
+
+impl TargetAddr {
+    pub fn domain<D: Into<Arc<str>>>(domain: D, port: u16) -> Self {
+        TargetAddr::Domain(domain.into(), port)
+    }
+}
+
+impl TryFrom<TargetAddr> for SocketAddr {
+    type Error = Socks5Error;
+    fn try_from(addr: TargetAddr) -> Result<Self> {
+        SocketAddr::try_from(&addr)
+    }
+}
+
+impl TryFrom<&TargetAddr> for SocketAddr {
+    type Error = Socks5Error;
+    fn try_from(addr: &TargetAddr) -> Result<Self> {
+        Ok(match addr {
+            TargetAddr::Ip(addr) => *addr,
+            TargetAddr::Domain(domain, port) => (&**domain, *port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or(Socks5Error::InvalidTargetAddress)?,
+        })
+    }
+}
+
+// +------+----------+----------+
+// | ATYP | DST.ADDR | DST.PORT |
+// +------+----------+----------+
+// |  1   | Variable |    2     |
+// +------+----------+----------+
+/// Append the wire encoding of `addr` to `buf`.
+pub fn encode_addr(buf: &mut Vec<u8>, addr: &TargetAddr) -> Result<()> {
+    use TargetAddr::*;
+    match addr {
+        Ip(SocketAddr::V4(socket)) => {
+            buf.push(0x01);
+            buf.extend_from_slice(&socket.ip().octets());
+            WriteBytesExt::write_u16::<NetworkEndian>(buf, socket.port()).unwrap();
+        }
+        Domain(domain, port) => {
+            buf.push(0x03);
+            buf.push(
+                domain
+                    .len()
+                    .try_into()
+                    .map_err(|_| Socks5Error::DomainTooLong)?,
+            );
+            buf.extend_from_slice(domain.as_bytes());
+            WriteBytesExt::write_u16::<NetworkEndian>(buf, *port).unwrap();
+        }
+        Ip(SocketAddr::V6(socket)) => {
+            buf.push(0x04);
+            buf.extend_from_slice(&socket.ip().octets());
+            WriteBytesExt::write_u16::<NetworkEndian>(buf, socket.port()).unwrap();
+        }
+    }
+    Ok(())
+}
+
+/// Decode an address from the front of `buf`, returning it with the number of bytes consumed.
+pub fn decode_addr(buf: &[u8]) -> Result<(TargetAddr, usize)> {
+    use TargetAddr::*;
+
+    let atyp = *buf.first().ok_or(Socks5Error::IncompleteHeader)?;
+    let buf = &buf[1..];
+    match atyp {
+        0x01 => {
+            let buf = buf.get(..4 + 2).ok_or(Socks5Error::IncompleteHeader)?;
+            let ip: [u8; 4] = buf[..4].try_into().unwrap();
+            let port = NetworkEndian::read_u16(&buf[4..]);
+            Ok((Ip(SocketAddr::from((ip, port))), 1 + 4 + 2))
+        }
+        0x03 => {
+            let len = *buf.first().ok_or(Socks5Error::IncompleteHeader)? as usize;
+            let buf = buf
+                .get(1..1 + len + 2)
+                .ok_or(Socks5Error::IncompleteHeader)?;
+            let domain = String::from_utf8_lossy(&buf[..len]).into();
+            let port = NetworkEndian::read_u16(&buf[len..]);
+            Ok((Domain(domain, port), 1 + 1 + len + 2))
+        }
+        0x04 => {
+            let buf = buf.get(..16 + 2).ok_or(Socks5Error::IncompleteHeader)?;
+            let ip: [u8; 16] = buf[..16].try_into().unwrap();
+            let port = NetworkEndian::read_u16(&buf[16..]);
+            Ok((Ip(SocketAddr::from((ip, port))), 1 + 16 + 2))
+        }
+        _ => Err(Socks5Error::InvalidAddressType),
+    }
+}
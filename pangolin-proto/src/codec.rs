@@ -0,0 +1,57 @@
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Quirks, Result, Socks5Error, UdpHeader};
+
+/// A `tokio_util` codec of the datagrams relayed through a UDP ASSOCIATE: the `UdpHeader` and the
+/// payload that follows it, for custom transports and servers.
+///
+/// As with `UdpFramed`, every buffer passed to `decode` must hold exactly one datagram.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Socks5UdpCodec {
+    quirks: Quirks,
+}
+
+impl Socks5UdpCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tolerate the deviations of `quirks` when decoding, e.g. `nonzero_reserved`.
+    pub fn with_quirks(quirks: Quirks) -> Self {
+        Self { quirks }
+    }
+}
+
+impl Decoder for Socks5UdpCodec {
+    type Item = (UdpHeader, BytesMut);
+    type Error = Socks5Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        if self.quirks.nonzero_reserved && src.len() >= 2 {
+            src[..2].copy_from_slice(&[0x00, 0x00]);
+        }
+
+        let (header, len) = UdpHeader::decode(src)?;
+        src.advance(len);
+        Ok(Some((header, src.split())))
+    }
+}
+
+impl<B: AsRef<[u8]>> Encoder<(UdpHeader, B)> for Socks5UdpCodec {
+    type Error = Socks5Error;
+
+    fn encode(&mut self, (header, payload): (UdpHeader, B), dst: &mut BytesMut) -> Result<()> {
+        let mut buf = Vec::with_capacity(262);
+        header.encode(&mut buf)?;
+
+        let payload = payload.as_ref();
+        dst.reserve(buf.len() + payload.len());
+        dst.extend_from_slice(&buf);
+        dst.extend_from_slice(payload);
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+use std::io;
+
+use thiserror::Error;
+
+use crate::TargetAddr;
+
+pub type Result<T> = std::result::Result<T, Socks5Error>;
+
+/// The steps of the negotiation with a proxy, used to tell where it went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakePhase {
+    /// The TCP connection to the proxy.
+    Connect,
+    /// The greeting and the proxy's method selection.
+    MethodSelection,
+    /// The method-dependent sub-negotiation, e.g. authentication.
+    SubNegotiation,
+    /// The request and its reply.
+    Request,
+    /// Waiting for the second reply to a BIND request, reporting the incoming connection.
+    Accept,
+}
+
+/// The two replies to a BIND request: the address the proxy listens on, then the incoming
+/// connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BindReply {
+    First,
+    Second,
+}
+
+impl fmt::Display for BindReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BindReply::First => "first",
+            BindReply::Second => "second",
+        })
+    }
+}
+
+impl fmt::Display for HandshakePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HandshakePhase::Connect => "connect",
+            HandshakePhase::MethodSelection => "method selection",
+            HandshakePhase::SubNegotiation => "sub-negotiation",
+            HandshakePhase::Request => "request",
+            HandshakePhase::Accept => "accept",
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Socks5Error {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("domain address is longer than 255 bytes")]
+    DomainTooLong,
+    #[error("invalid response version: expected {expected}, actual: {actual}")]
+    InvalidResponseVersion { expected: u8, actual: u8 },
+    #[error("invalid request version: expected {expected}, actual: {actual}")]
+    InvalidRequestVersion { expected: u8, actual: u8 },
+
+    #[error("no acceptable method")]
+    NoAcceptableMethod,
+
+    #[error("more than 255 methods offered")]
+    TooManyMethods,
+
+    #[error("proxy selected a method that was not offered: {0}")]
+    UnofferedMethod(u8),
+
+    #[error("no credentials configured for the username/password method")]
+    MissingCredentials,
+
+    #[error("username or password is longer than 255 bytes")]
+    CredentialsTooLong,
+
+    #[error("authentication failed")]
+    AuthenticationFailed,
+
+    #[error("invalid message type: expected: {expected}, actual: {actual}")]
+    InvalidMessageType { expected: u8, actual: u8 },
+
+    #[error("proxy closed the connection during {phase}")]
+    ProxyClosedDuringHandshake { phase: HandshakePhase },
+
+    /// An I/O error other than the proxy going away, during `phase` of the handshake.
+    #[error("io error during {phase}: {source}")]
+    IoDuring {
+        phase: HandshakePhase,
+        source: io::Error,
+    },
+
+    #[error("handshake timed out during {phase}")]
+    Timeout { phase: HandshakePhase },
+
+    #[error("cancelled before the proxy replied")]
+    Cancelled,
+
+    // Reply related error
+    #[error("general socks server failure")]
+    GeneralSocksServerFailure,
+    #[error("connection not allowed")]
+    ConnectionNotAllowed,
+    #[error("network unreachable")]
+    NetworkUnreachable,
+    #[error("host unreachable")]
+    HostUnreachable,
+    #[error("connection refused")]
+    ConnectionRefused,
+    #[error("ttl expired")]
+    TtlExpired,
+    #[error("command not supported")]
+    CommandNotSupported,
+    #[error("address type not supported")]
+    AddressTypeNotSupported,
+    #[error("unassigned")]
+    Unassigned,
+
+    #[error("invalid reserved byte: expected: {expected}, actual: {actual}")]
+    InvalidReservedByte { expected: u8, actual: u8 },
+
+    #[error("invalid address type")]
+    InvalidAddressType,
+
+    #[error("incomplete header")]
+    IncompleteHeader,
+
+    #[error("invalid target address")]
+    InvalidTargetAddress,
+
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+
+    #[error("datagram socket not registered")]
+    DatagramSocketNotRegistered,
+
+    #[error("datagram of {size} bytes exceeds the limit of {limit} bytes")]
+    DatagramTooLarge { limit: usize, size: usize },
+
+    #[error("datagram of {size} bytes truncated to a buffer of {capacity} bytes")]
+    DatagramTruncated { size: usize, capacity: usize },
+
+    #[error("datagram rejected by its transform")]
+    DatagramRejected,
+
+    #[error("target denied by policy")]
+    TargetDenied,
+
+    #[error("udp association terminated by the proxy")]
+    AssociationTerminated,
+
+    #[error("datagram not connected to a peer")]
+    NotConnected,
+
+    #[error("incoming connection from unexpected peer {peer:?}")]
+    UnexpectedBindPeer { peer: TargetAddr },
+
+    /// The proxy reported a failure in a reply to a BIND request. `reason` is the error of its
+    /// reply code, and `bind_addr` the address advertised to the peer, known by the second reply.
+    #[error("bind failed on the {reply} reply: {reason}")]
+    BindFailed {
+        reply: BindReply,
+        reason: Box<Socks5Error>,
+        bind_addr: Option<TargetAddr>,
+    },
+
+    /// The handshake of the client session numbered `session` failed with `reason`.
+    #[error("session {session}: {reason}")]
+    Session {
+        session: u64,
+        reason: Box<Socks5Error>,
+    },
+}
+
+impl Socks5Error {
+    /// Report an I/O error caused by the proxy going away as `ProxyClosedDuringHandshake`, and
+    /// any other I/O error as `IoDuring`, leaving the other errors untouched.
+    pub fn during(self, phase: HandshakePhase) -> Self {
+        match self {
+            Socks5Error::Io(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe
+                ) =>
+            {
+                Socks5Error::ProxyClosedDuringHandshake { phase }
+            }
+            Socks5Error::Io(source) => Socks5Error::IoDuring { phase, source },
+            e => e,
+        }
+    }
+}
+
+impl Socks5Error {
+    /// The reply code of a failure reported by the proxy, if this is one with an assigned code.
+    pub fn reply_code(&self) -> Option<u8> {
+        match self.root() {
+            Socks5Error::GeneralSocksServerFailure => Some(0x01),
+            Socks5Error::ConnectionNotAllowed => Some(0x02),
+            Socks5Error::NetworkUnreachable => Some(0x03),
+            Socks5Error::HostUnreachable => Some(0x04),
+            Socks5Error::ConnectionRefused => Some(0x05),
+            Socks5Error::TtlExpired => Some(0x06),
+            Socks5Error::CommandNotSupported => Some(0x07),
+            Socks5Error::AddressTypeNotSupported => Some(0x08),
+            _ => None,
+        }
+    }
+
+    /// Attribute the error to the client session numbered `session`, unless it already is to one.
+    pub fn in_session(self, session: u64) -> Self {
+        match self {
+            e @ Socks5Error::Session { .. } => e,
+            e => Socks5Error::Session {
+                session,
+                reason: Box::new(e),
+            },
+        }
+    }
+
+    /// The client session the error is attributed to, if any.
+    pub fn session(&self) -> Option<u64> {
+        match self {
+            Socks5Error::Session { session, .. } => Some(*session),
+            _ => None,
+        }
+    }
+
+    /// The error itself, without the session it is attributed to.
+    pub fn root(&self) -> &Socks5Error {
+        match self {
+            Socks5Error::Session { reason, .. } => reason,
+            e => e,
+        }
+    }
+
+    /// Report a failure reported by the proxy in `reply` to a BIND request as `BindFailed`,
+    /// leaving any other error untouched.
+    pub fn in_bind_reply(self, reply: BindReply, bind_addr: Option<TargetAddr>) -> Self {
+        match self {
+            Socks5Error::Session { session, reason } => Socks5Error::Session {
+                session,
+                reason: Box::new(reason.in_bind_reply(reply, bind_addr)),
+            },
+            e if e.reply_code().is_some() || matches!(e, Socks5Error::Unassigned) => {
+                Socks5Error::BindFailed {
+                    reply,
+                    reason: Box::new(e),
+                    bind_addr,
+                }
+            }
+            e => e,
+        }
+    }
+}
+
+impl From<Socks5Error> for io::Error {
+    fn from(e: Socks5Error) -> Self {
+        match e {
+            Socks5Error::Io(e) => e,
+            Socks5Error::IoDuring { phase, source } => io::Error::new(
+                source.kind(),
+                format!("io error during {}: {}", phase, source),
+            ),
+            e @ Socks5Error::Timeout { .. } => io::Error::new(io::ErrorKind::TimedOut, e),
+            Socks5Error::Session { session, reason } => {
+                let e = io::Error::from(*reason);
+                io::Error::new(e.kind(), format!("session {}: {}", session, e))
+            }
+            e => io::Error::other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_errors_to_one_session() {
+        let e = Socks5Error::ConnectionRefused.in_session(1).in_session(2);
+        assert_eq!(e.session(), Some(1));
+        assert!(matches!(e.root(), Socks5Error::ConnectionRefused));
+        assert_eq!(e.reply_code(), Some(0x05));
+        assert_eq!(e.to_string(), format!("session 1: {}", e.root()));
+        assert_eq!(
+            io::Error::from(Socks5Error::Cancelled.in_session(1)).kind(),
+            io::Error::from(Socks5Error::Cancelled).kind()
+        );
+    }
+
+    #[test]
+    fn tags_io_errors_with_their_phase() {
+        let eof = Socks5Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(
+            eof.during(HandshakePhase::Request),
+            Socks5Error::ProxyClosedDuringHandshake {
+                phase: HandshakePhase::Request
+            }
+        ));
+
+        let timed_out = Socks5Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        let e = timed_out.during(HandshakePhase::Request);
+        assert!(matches!(
+            e,
+            Socks5Error::IoDuring {
+                phase: HandshakePhase::Request,
+                ..
+            }
+        ));
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::TimedOut);
+        assert!(matches!(
+            Socks5Error::Cancelled.during(HandshakePhase::Request),
+            Socks5Error::Cancelled
+        ));
+    }
+}
@@ -0,0 +1,23 @@
+//! The sans-IO part of pangolin: the SOCKS5 wire format without any transport attached.
+
+mod addr;
+#[cfg(feature = "codec")]
+mod codec;
+mod error;
+mod quirks;
+mod request;
+mod udp;
+mod url;
+mod version;
+
+pub use self::addr::{decode_addr, encode_addr, TargetAddr};
+#[cfg(feature = "codec")]
+pub use self::codec::Socks5UdpCodec;
+pub use self::error::{BindReply, HandshakePhase, Result, Socks5Error};
+pub use self::quirks::Quirks;
+pub use self::request::{Request, RequestType};
+pub use self::udp::UdpHeader;
+pub use self::url::default_port;
+pub use self::version::{Strictness, Version};
+
+pub const VERSION: u8 = Version::SOCKS5.as_u8();
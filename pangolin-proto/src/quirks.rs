@@ -0,0 +1,26 @@
+/// Known deviations from RFC 1928 that the reply parser can be told to tolerate.
+///
+/// Every quirk is off by default; enable only the ones a given proxy needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Quirks {
+    /// Accept replies whose RSV byte isn't `0x00`, e.g. servers that echo the request's byte.
+    pub nonzero_reserved: bool,
+    /// Accept replies with `ATYP = 0x00`, parsing the bound address as IPv4.
+    pub zero_address_type: bool,
+    /// Accept replies that stop after `ATYP`, without `BND.ADDR` and `BND.PORT`. The bound
+    /// address is then reported as `0.0.0.0:0`.
+    pub missing_bound_address: bool,
+    /// Accept relayed datagrams sent from another address than the relay announced in the reply
+    /// to UDP ASSOCIATE, e.g. by NATed or multi-homed proxies.
+    pub foreign_relay_source: bool,
+}
+
+impl Quirks {
+    /// No quirks: parse replies exactly as the RFC describes.
+    pub const NONE: Quirks = Quirks {
+        nonzero_reserved: false,
+        zero_address_type: false,
+        missing_bound_address: false,
+        foreign_relay_source: false,
+    };
+}
@@ -0,0 +1,121 @@
+use std::convert::TryFrom;
+
+use crate::{decode_addr, encode_addr, Result, Socks5Error, TargetAddr, Version, VERSION};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestType {
+    Connect = 0x01,
+    Bind = 0x02,
+    UdpAssociate = 0x03,
+}
+
+impl TryFrom<u8> for RequestType {
+    type Error = Socks5Error;
+    fn try_from(cmd: u8) -> Result<Self> {
+        match cmd {
+            0x01 => Ok(RequestType::Connect),
+            0x02 => Ok(RequestType::Bind),
+            0x03 => Ok(RequestType::UdpAssociate),
+            _ => Err(Socks5Error::CommandNotSupported),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    request_type: RequestType,
+    target_addr: TargetAddr,
+}
+
+impl TryFrom<Request> for Vec<u8> {
+    type Error = Socks5Error;
+    fn try_from(request: Request) -> Result<Self> {
+        let mut buf = Vec::with_capacity(262);
+
+        // +----+-----+-------+------+----------+----------+
+        // |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+        // +----+-----+-------+------+----------+----------+
+        // | 1  |  1  | X'00' |  1   | Variable |    2     |
+        // +----+-----+-------+------+----------+----------+
+        buf.push(VERSION);
+        buf.push(request.request_type as u8);
+        buf.push(0x00);
+
+        encode_addr(&mut buf, &request.target_addr)?;
+
+        Ok(buf)
+    }
+}
+
+impl Request {
+    pub fn new(request_type: RequestType, target_addr: TargetAddr) -> Self {
+        Self {
+            request_type,
+            target_addr,
+        }
+    }
+
+    pub fn request_type(&self) -> RequestType {
+        self.request_type
+    }
+
+    pub fn target_addr(&self) -> &TargetAddr {
+        &self.target_addr
+    }
+
+    /// The length of the request at the front of `buf`, once enough of it is there to tell: the
+    /// header and the first byte of the address.
+    pub fn encoded_len(buf: &[u8]) -> Option<usize> {
+        let addr_len = match (buf.get(3)?, buf.get(4)?) {
+            (0x01, _) => 4,
+            (0x03, &len) => 1 + len as usize,
+            (0x04, _) => 16,
+            // Let `decode` report the address type.
+            _ => 0,
+        };
+        Some(3 + 1 + addr_len + 2)
+    }
+
+    /// Decode a request from the front of `buf`, returning it with the number of bytes consumed.
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let header = buf.get(..3).ok_or(Socks5Error::IncompleteHeader)?;
+        Version::SOCKS5.check_request(header[0])?;
+        if header[2] != 0x00 {
+            return Err(Socks5Error::InvalidReservedByte {
+                expected: 0x00,
+                actual: header[2],
+            });
+        }
+
+        let request_type = RequestType::try_from(header[1])?;
+        let (target_addr, len) = decode_addr(&buf[3..])?;
+        Ok((Self::new(request_type, target_addr), 3 + len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_requests_of_socks5_only() {
+        let request = [VERSION, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50];
+        let (decoded, len) = Request::decode(&request).unwrap();
+        assert_eq!(len, request.len());
+        assert_eq!(decoded.request_type(), RequestType::Connect);
+        assert_eq!(
+            decoded.target_addr(),
+            &TargetAddr::Ip("127.0.0.1:80".parse().unwrap())
+        );
+
+        let mut socks4 = request;
+        socks4[0] = 0x04;
+        assert!(matches!(
+            Request::decode(&socks4),
+            Err(Socks5Error::InvalidRequestVersion {
+                expected: VERSION,
+                actual: 0x04
+            })
+        ));
+    }
+}
@@ -0,0 +1,43 @@
+use crate::{decode_addr, encode_addr, Result, Socks5Error, TargetAddr};
+
+/// The header prepended to every datagram relayed through a UDP ASSOCIATE.
+///
+/// ```text
+/// +----+------+------+----------+----------+----------+
+/// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+/// +----+------+------+----------+----------+----------+
+/// | 2  |  1   |  1   | Variable |    2     | Variable |
+/// +----+------+------+----------+----------+----------+
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub target: TargetAddr,
+}
+
+impl UdpHeader {
+    pub fn new(frag: u8, target: TargetAddr) -> Self {
+        Self { frag, target }
+    }
+
+    /// Append the encoded header to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(&[0x00, 0x00, self.frag]);
+        encode_addr(buf, &self.target)
+    }
+
+    /// Decode a header from the front of `buf`, returning it with the header length, so that
+    /// `&buf[len..]` is the payload.
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let rsv = buf.get(..3).ok_or(Socks5Error::IncompleteHeader)?;
+        if rsv[0] != 0x00 || rsv[1] != 0x00 {
+            return Err(Socks5Error::InvalidReservedByte {
+                expected: 0x00,
+                actual: if rsv[0] != 0x00 { rsv[0] } else { rsv[1] },
+            });
+        }
+
+        let (target, len) = decode_addr(&buf[3..])?;
+        Ok((Self::new(rsv[2], target), 3 + len))
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{Result, Socks5Error};
+
+/// How strictly version bytes received from the proxy are checked.
+///
+/// Several real-world proxies deviate subtly from the RFCs, e.g. by echoing the SOCKS version
+/// `0x05` in the reply of a sub-negotiation that has its own version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    /// Require the exact version.
+    #[default]
+    Strict,
+    /// Also accept the SOCKS version in place of a sub-negotiation version.
+    Lenient,
+    /// Don't check version bytes at all.
+    Ignore,
+}
+
+/// A version byte of the SOCKS5 protocol or one of its sub-negotiations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Version(u8);
+
+impl Version {
+    pub const SOCKS5: Version = Version(0x05);
+
+    pub const fn new(version: u8) -> Self {
+        Version(version)
+    }
+
+    pub const fn as_u8(self) -> u8 {
+        self.0
+    }
+
+    /// Check a version byte sent by the proxy against this version.
+    pub fn check(self, actual: u8, strictness: Strictness) -> Result<()> {
+        let accepted = match strictness {
+            Strictness::Strict => actual == self.0,
+            Strictness::Lenient => actual == self.0 || actual == Version::SOCKS5.0,
+            Strictness::Ignore => true,
+        };
+
+        if accepted {
+            Ok(())
+        } else {
+            Err(Socks5Error::InvalidResponseVersion {
+                expected: self.0,
+                actual,
+            })
+        }
+    }
+
+    /// Check a version byte sent by a client against this version, which it has to match
+    /// exactly.
+    pub fn check_request(self, actual: u8) -> Result<()> {
+        if actual == self.0 {
+            Ok(())
+        } else {
+            Err(Socks5Error::InvalidRequestVersion {
+                expected: self.0,
+                actual,
+            })
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use pangolin_client::{IpNet, TargetPolicy};
+use pangolin_proto::{Request, Result, TargetAddr};
+
+use crate::{Identity, TargetResolver};
+
+/// Decides which requests the server carries out, once they are read. Refused requests are
+/// answered with `ConnectionNotAllowed`.
+///
+/// UDP associations are checked datagram by datagram instead, with a `UdpAssociate` request to
+/// the target of each, and the datagrams refused are dropped.
+pub trait AccessPolicy: Send + Sync {
+    /// Whether the client authenticated as `identity` may have `request` carried out.
+    fn allows(&self, identity: &Identity, request: &Request) -> bool;
+}
+
+impl fmt::Debug for dyn AccessPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccessPolicy")
+    }
+}
+
+impl<F> AccessPolicy for F
+where
+    F: Fn(&Identity, &Request) -> bool + Send + Sync,
+{
+    fn allows(&self, identity: &Identity, request: &Request) -> bool {
+        self(identity, request)
+    }
+}
+
+impl AccessPolicy for TargetPolicy {
+    fn allows(&self, _: &Identity, request: &Request) -> bool {
+        self.is_allowed(request.target_addr())
+    }
+}
+
+/// Allows a request only if every policy of the list does.
+impl AccessPolicy for Vec<Box<dyn AccessPolicy>> {
+    fn allows(&self, identity: &Identity, request: &Request) -> bool {
+        self.iter().all(|policy| policy.allows(identity, request))
+    }
+}
+
+/// The destinations matched by a rule of `AccessRules`: a network or a domain pattern, on a range
+/// of ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    host: HostPattern,
+    ports: RangeInclusive<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    Net(IpNet),
+    // Lowercase, without the trailing dot.
+    Domain(String),
+    // The parent of the subdomains matched by `*.domain`.
+    Subdomains(String),
+}
+
+impl Destination {
+    /// Every destination, on any port.
+    pub fn any() -> Self {
+        Self {
+            host: HostPattern::Any,
+            ports: 0..=u16::MAX,
+        }
+    }
+
+    /// The addresses of `net`, on any port. Domain targets don't match networks, but the
+    /// `AccessLayer` of the server checks the addresses they resolve to as well.
+    pub fn net(net: IpNet) -> Self {
+        Self {
+            host: HostPattern::Net(net),
+            ..Self::any()
+        }
+    }
+
+    /// The domains matching `pattern`, on any port: a domain, or `*.` and a domain for its
+    /// subdomains.
+    pub fn domain(pattern: &str) -> Self {
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        let host = match pattern.strip_prefix("*.") {
+            Some(parent) => HostPattern::Subdomains(parent.to_owned()),
+            None => HostPattern::Domain(pattern),
+        };
+        Self {
+            host,
+            ..Self::any()
+        }
+    }
+
+    /// Restrict the destination to `ports`.
+    pub fn ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    pub fn port(self, port: u16) -> Self {
+        self.ports(port..=port)
+    }
+
+    pub fn matches(&self, target: &TargetAddr) -> bool {
+        let (host, port) = match target {
+            TargetAddr::Ip(addr) => (Err(addr.ip()), addr.port()),
+            TargetAddr::Domain(domain, port) => match domain.parse::<IpAddr>() {
+                Ok(ip) => (Err(ip), *port),
+                Err(_) => (Ok(domain.trim_end_matches('.').to_ascii_lowercase()), *port),
+            },
+        };
+        if !self.ports.contains(&port) {
+            return false;
+        }
+
+        match (&self.host, host) {
+            (HostPattern::Any, _) => true,
+            (HostPattern::Net(net), Err(ip)) => net.contains(ip),
+            (HostPattern::Domain(pattern), Ok(domain)) => domain == *pattern,
+            (HostPattern::Subdomains(parent), Ok(domain)) => domain
+                .strip_suffix(parent.as_str())
+                .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.')),
+            _ => false,
+        }
+    }
+}
+
+/// An ordered list of rules allowing or denying destinations: the first rule matching the target
+/// of a request decides, and the default applies when none does.
+#[derive(Debug, Clone)]
+pub struct AccessRules {
+    // Each rule, with whether it allows its destination.
+    rules: Vec<(bool, Destination)>,
+    allow_by_default: bool,
+}
+
+impl AccessRules {
+    /// Rules allowing what they don't deny.
+    pub fn allow_by_default() -> Self {
+        Self {
+            rules: Vec::new(),
+            allow_by_default: true,
+        }
+    }
+
+    /// Rules denying what they don't allow.
+    pub fn deny_by_default() -> Self {
+        Self {
+            rules: Vec::new(),
+            allow_by_default: false,
+        }
+    }
+
+    pub fn allow(&mut self, destination: Destination) -> &mut Self {
+        self.rules.push((true, destination));
+        self
+    }
+
+    pub fn deny(&mut self, destination: Destination) -> &mut Self {
+        self.rules.push((false, destination));
+        self
+    }
+
+    pub fn is_allowed(&self, target: &TargetAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|(_, destination)| destination.matches(target))
+            .map_or(self.allow_by_default, |(allowed, _)| *allowed)
+    }
+}
+
+impl AccessPolicy for AccessRules {
+    fn allows(&self, _: &Identity, request: &Request) -> bool {
+        self.is_allowed(request.target_addr())
+    }
+}
+
+/// A policy checked by the server against the target of a request, and then against each of the
+/// addresses a domain target resolves to, so that a name resolving into a denied network is
+/// denied as well.
+#[derive(Debug, Clone)]
+pub(crate) struct AccessCheck {
+    policy: Arc<dyn AccessPolicy>,
+    resolver: TargetResolver,
+}
+
+impl AccessCheck {
+    pub(crate) fn new(policy: Arc<dyn AccessPolicy>, resolver: TargetResolver) -> Self {
+        Self { policy, resolver }
+    }
+
+    /// The target to carry out `request` to if `policy` allows the client authenticated as
+    /// `identity` to, or `None`. A domain target is replaced by the first of its addresses, all
+    /// of them checked, so that nothing resolves it again into one that wasn't. Fails if the
+    /// domain doesn't resolve, since its addresses can't be checked.
+    pub(crate) async fn check(
+        &self,
+        identity: &Identity,
+        request: &Request,
+    ) -> Result<Option<TargetAddr>> {
+        if !self.policy.allows(identity, request) {
+            return Ok(None);
+        }
+        let (domain, port) = match request.target_addr() {
+            TargetAddr::Domain(domain, port) if domain.parse::<IpAddr>().is_err() => {
+                (domain, *port)
+            }
+            target => return Ok(Some(target.clone())),
+        };
+
+        let addrs = self.resolver.resolve(domain, port).await?;
+        let allowed = addrs.iter().all(|addr| {
+            let resolved = Request::new(request.request_type(), TargetAddr::Ip(*addr));
+            self.policy.allows(identity, &resolved)
+        });
+        Ok(addrs
+            .first()
+            .filter(|_| allowed)
+            .map(|addr| TargetAddr::Ip(*addr)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+
+    use async_trait::async_trait;
+    use pangolin_proto::RequestType;
+
+    use super::*;
+    use crate::Resolver;
+
+    // Resolves every domain to its address.
+    struct Fixed(IpAddr);
+
+    #[async_trait]
+    impl Resolver for Fixed {
+        async fn resolve(&self, _: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![SocketAddr::new(self.0, port)])
+        }
+    }
+
+    fn domain(domain: &str, port: u16) -> TargetAddr {
+        TargetAddr::Domain(domain.into(), port)
+    }
+
+    fn ip(addr: &str) -> TargetAddr {
+        TargetAddr::Ip(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn destination_matches() {
+        let net = Destination::net("10.0.0.0/8".parse().unwrap());
+        assert!(net.matches(&ip("10.1.2.3:80")));
+        assert!(net.matches(&domain("10.1.2.3", 80)));
+        assert!(!net.matches(&ip("11.0.0.1:80")));
+        assert!(!net.matches(&domain("example.com", 80)));
+
+        let exact = Destination::domain("Example.COM.").port(443);
+        assert!(exact.matches(&domain("example.com", 443)));
+        assert!(exact.matches(&domain("EXAMPLE.com.", 443)));
+        assert!(!exact.matches(&domain("example.com", 80)));
+        assert!(!exact.matches(&domain("www.example.com", 443)));
+
+        let subdomains = Destination::domain("*.example.com").ports(8000..=8999);
+        assert!(subdomains.matches(&domain("www.example.com", 8080)));
+        assert!(subdomains.matches(&domain("a.b.example.com", 8000)));
+        assert!(!subdomains.matches(&domain("example.com", 8080)));
+        assert!(!subdomains.matches(&domain("badexample.com", 8080)));
+        assert!(!subdomains.matches(&domain("www.example.com", 9000)));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let mut rules = AccessRules::deny_by_default();
+        rules
+            .deny(Destination::domain("blocked.example.com"))
+            .allow(Destination::domain("*.example.com"))
+            .allow(Destination::any().port(53));
+        assert!(!rules.is_allowed(&domain("blocked.example.com", 80)));
+        assert!(rules.is_allowed(&domain("www.example.com", 80)));
+        assert!(rules.is_allowed(&ip("1.1.1.1:53")));
+        assert!(!rules.is_allowed(&ip("1.1.1.1:80")));
+
+        let mut rules = AccessRules::allow_by_default();
+        rules.deny(Destination::net("127.0.0.0/8".parse().unwrap()));
+        assert!(rules.is_allowed(&domain("example.com", 80)));
+        assert!(!rules.is_allowed(&ip("127.0.0.1:80")));
+    }
+
+    fn check(rules: AccessRules, resolved: IpAddr) -> AccessCheck {
+        AccessCheck::new(
+            Arc::new(rules),
+            TargetResolver::new(Arc::new(Fixed(resolved))),
+        )
+    }
+
+    #[tokio::test]
+    async fn domains_resolving_into_denied_networks_are_denied() {
+        let mut rules = AccessRules::allow_by_default();
+        rules.deny(Destination::net("10.0.0.0/8".parse().unwrap()));
+        let request = Request::new(RequestType::Connect, domain("internal.example", 80));
+
+        let check = check(rules.clone(), "10.0.0.1".parse().unwrap());
+        assert_eq!(
+            check.check(&Identity::Anonymous, &request).await.unwrap(),
+            None
+        );
+        let check = self::check(rules, "192.0.2.1".parse().unwrap());
+        assert_eq!(
+            check.check(&Identity::Anonymous, &request).await.unwrap(),
+            Some(ip("192.0.2.1:80"))
+        );
+    }
+
+    #[tokio::test]
+    async fn localhost_is_checked_as_loopback() {
+        let mut rules = AccessRules::allow_by_default();
+        rules
+            .deny(Destination::net("127.0.0.0/8".parse().unwrap()))
+            .deny(Destination::net("::1/128".parse().unwrap()));
+        let check = AccessCheck::new(Arc::new(rules), TargetResolver::default());
+        let request = Request::new(RequestType::Connect, domain("localhost", 80));
+        assert!(!matches!(
+            check.check(&Identity::Anonymous, &request).await,
+            Ok(Some(_))
+        ));
+    }
+}
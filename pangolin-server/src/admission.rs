@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// The number of connections from each client address.
+type Clients = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// The connections of the clients of a `Socks5Server`, kept within the limits of its
+/// `ServerConfig`.
+#[derive(Debug, Clone)]
+pub(crate) struct Admission {
+    slots: Option<Arc<Semaphore>>,
+    per_ip: Option<usize>,
+    clients: Clients,
+}
+
+/// The place of a client among the connections, held until its connection is closed.
+#[derive(Debug)]
+pub(crate) struct Admitted {
+    _slot: Option<OwnedSemaphorePermit>,
+    client: Option<(IpAddr, Clients)>,
+}
+
+impl Admission {
+    pub(crate) fn new(max: Option<usize>, per_ip: Option<usize>) -> Self {
+        Self {
+            slots: max.map(|max| Arc::new(Semaphore::new(max))),
+            per_ip,
+            clients: Arc::default(),
+        }
+    }
+
+    /// Whether all the slots are taken, so accepting has to wait for `slot`.
+    pub(crate) fn is_saturated(&self) -> bool {
+        self.slots
+            .as_ref()
+            .is_some_and(|slots| slots.available_permits() == 0)
+    }
+
+    /// Wait for a free slot, or return `None` at once if the connections aren't limited.
+    pub(crate) async fn slot(&self) -> Option<OwnedSemaphorePermit> {
+        // The semaphore is never closed.
+        self.slots.clone()?.acquire_owned().await.ok()
+    }
+
+    /// Admit a connection from `ip` in `slot`, unless the connections from it are at their
+    /// limit.
+    pub(crate) fn admit(&self, slot: Option<OwnedSemaphorePermit>, ip: IpAddr) -> Option<Admitted> {
+        let per_ip = match self.per_ip {
+            Some(per_ip) => per_ip,
+            None => {
+                return Some(Admitted {
+                    _slot: slot,
+                    client: None,
+                })
+            }
+        };
+
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        let connections = clients.entry(ip).or_default();
+        if *connections >= per_ip {
+            return None;
+        }
+        *connections += 1;
+        Some(Admitted {
+            _slot: slot,
+            client: Some((ip, self.clients.clone())),
+        })
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        if let Some((ip, clients)) = &self.client {
+            let mut clients = clients.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(connections) = clients.get_mut(ip) {
+                *connections -= 1;
+                if *connections == 0 {
+                    clients.remove(ip);
+                }
+            }
+        }
+    }
+}
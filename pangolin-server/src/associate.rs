@@ -0,0 +1,536 @@
+use std::collections::HashSet;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+
+use async_trait::async_trait;
+use pangolin_proto::{Request, RequestType, Result, Socks5Error, TargetAddr, UdpHeader};
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+
+use crate::access::AccessCheck;
+use crate::registry::Traffic;
+use crate::{Identity, TargetResolver};
+
+// Large enough for any UDP datagram.
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+// The datagrams of a client being checked or sent at once.
+const MAX_FORWARDING: usize = 64;
+
+// A datagram of the client being checked or sent.
+type Forwarding<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Where the server exchanges the payloads of the datagrams of a UDP association with their
+/// targets, as opened by `Dialer::associate`.
+#[async_trait]
+pub trait DatagramOutbound: Send + Sync {
+    /// Send `payload` to `target`. Failures only drop the datagram.
+    async fn send_to(&self, payload: &[u8], target: &TargetAddr) -> Result<usize>;
+
+    /// Receive the payload of the next datagram from any of the targets, and its source.
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, TargetAddr)>;
+}
+
+/// Exchanges the datagrams directly from the host of the server, on a socket that is dual-stack
+/// where IPv6 is available, resolving domains locally.
+#[derive(Debug)]
+pub struct DirectOutbound {
+    socket: UdpSocket,
+    resolver: TargetResolver,
+}
+
+impl DirectOutbound {
+    pub fn bind() -> Result<Self> {
+        Self::bind_with_resolver(TargetResolver::default())
+    }
+
+    /// Like `bind`, resolving the domain targets with `resolver`.
+    pub fn bind_with_resolver(resolver: TargetResolver) -> Result<Self> {
+        Ok(Self {
+            socket: bind_outbound()?,
+            resolver,
+        })
+    }
+
+    // `target` as addressed from the socket, which is dual-stack when it is IPv6.
+    fn outbound_addr(&self, target: SocketAddr) -> SocketAddr {
+        match (self.socket.local_addr(), target.ip()) {
+            (Ok(SocketAddr::V6(_)), IpAddr::V4(ip)) => {
+                SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), target.port())
+            }
+            _ => target,
+        }
+    }
+}
+
+#[async_trait]
+impl DatagramOutbound for DirectOutbound {
+    async fn send_to(&self, payload: &[u8], target: &TargetAddr) -> Result<usize> {
+        let target = match target {
+            TargetAddr::Ip(addr) => *addr,
+            TargetAddr::Domain(domain, port) => self.resolver.resolve(domain, *port).await?[0],
+        };
+        let target = self.outbound_addr(target);
+        Ok(self.socket.send_to(payload, target).await?)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, TargetAddr)> {
+        let (len, from) = self.socket.recv_from(buf).await?;
+        let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+        Ok((len, TargetAddr::Ip(from)))
+    }
+}
+
+/// The server side of a UDP ASSOCIATE: a relay socket receiving the encapsulated datagrams of
+/// the client, and an outbound exchanging their payloads with the targets.
+pub(crate) struct Association {
+    relay: UdpSocket,
+    client: Client,
+    targets: Targets,
+}
+
+// The client as seen by the relay.
+struct Client {
+    // Where the client may send from: the address it announced in its request, falling back to
+    // the address of its control connection.
+    allowed_ip: IpAddr,
+    allowed_port: Option<u16>,
+    // The address the client sends from, once it sent its first datagram.
+    addr: Option<SocketAddr>,
+    // The targets the client sent to, when the datagrams of other sources are dropped.
+    sent_to: Option<HashSet<TargetAddr>>,
+}
+
+// Where the payloads of the client go.
+struct Targets {
+    outbound: Box<dyn DatagramOutbound>,
+    policies: Vec<AccessCheck>,
+    identity: Identity,
+    traffic: Arc<Traffic>,
+}
+
+impl Association {
+    /// Bind the relay of the association requested by `client`, connected to the server at
+    /// `local_addr`, which expects to send from `requested`.
+    pub(crate) async fn bind(
+        local_addr: SocketAddr,
+        client: SocketAddr,
+        requested: &TargetAddr,
+        outbound: Box<dyn DatagramOutbound>,
+    ) -> Result<Self> {
+        // Listen where the client reached the server, which it can reach again.
+        let relay = UdpSocket::bind(SocketAddr::new(local_addr.ip(), 0)).await?;
+
+        Ok(Self {
+            relay,
+            client: Client::new(client, requested),
+            targets: Targets {
+                outbound,
+                policies: Vec::new(),
+                identity: Identity::Anonymous,
+                traffic: Arc::default(),
+            },
+        })
+    }
+
+    /// Hand the association over to `client`, which resumed it from a new control connection and
+    /// expects to send from `requested`. The targets it sent to are kept.
+    pub(crate) fn reattach(mut self, client: SocketAddr, requested: &TargetAddr) -> Self {
+        let sent_to = self.client.sent_to.take();
+        self.client = Client {
+            sent_to,
+            ..Client::new(client, requested)
+        };
+        self
+    }
+
+    /// Only relay the datagrams of the client, authenticated as `identity`, whose targets all
+    /// the `policies` allow.
+    pub(crate) fn restrict(mut self, policies: Vec<AccessCheck>, identity: Identity) -> Self {
+        self.targets.policies = policies;
+        self.targets.identity = identity;
+        self
+    }
+
+    /// Count the payloads relayed in `traffic`.
+    pub(crate) fn count(mut self, traffic: Arc<Traffic>) -> Self {
+        self.targets.traffic = traffic;
+        self
+    }
+
+    /// Drop the datagrams from sources the client never sent to, if `filter` is set.
+    pub(crate) fn filter_sources(mut self, filter: bool) -> Self {
+        self.client.sent_to = Some(HashSet::new()).filter(|_| filter);
+        self
+    }
+
+    /// The address of the relay, sent to the client in the reply.
+    pub(crate) fn relay_addr(&self) -> Result<TargetAddr> {
+        Ok(TargetAddr::Ip(self.relay.local_addr()?))
+    }
+
+    /// Relay datagrams until the client closes `control`, which ends the association unless it
+    /// is resumed.
+    pub(crate) async fn run<S>(&mut self, control: &mut S) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        tokio::select! {
+            relayed = self.relay_datagrams() => relayed,
+            _ = closed(control) => Ok(()),
+        }
+    }
+
+    // Checking and sending the datagrams of the client, e.g. resolving their targets, happens
+    // alongside receiving, so that a slow target doesn't hold up the others nor the datagrams
+    // coming back. Once too many are in flight, the next ones wait in the relay socket.
+    async fn relay_datagrams(&mut self) -> Result<()> {
+        let Self {
+            relay,
+            client,
+            targets,
+        } = self;
+        let targets = &*targets;
+        let mut from_client = vec![0; MAX_DATAGRAM_SIZE];
+        let mut from_target = vec![0; MAX_DATAGRAM_SIZE];
+        let mut forwarding: Vec<Forwarding<'_>> = Vec::new();
+        loop {
+            tokio::select! {
+                received = relay.recv_from(&mut from_client), if forwarding.len() < MAX_FORWARDING => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(e) if is_transient(&e) => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    if let Some((target, payload)) = client.unpack(&from_client[..len], from) {
+                        forwarding.push(Box::pin(targets.forward(target, payload)));
+                    }
+                }
+                received = targets.outbound.recv_from(&mut from_target) => {
+                    let (len, from) = match received {
+                        Ok(received) => received,
+                        Err(Socks5Error::Io(e)) if is_transient(&e) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    if let Some(addr) = client.answered_by(&from) {
+                        answer(relay, addr, &from_target[..len], from, &targets.traffic).await?;
+                    }
+                }
+                () = drive(&mut forwarding) => {}
+            }
+        }
+    }
+}
+
+impl Client {
+    // The client connected from `client`, which expects to send from `requested`.
+    fn new(client: SocketAddr, requested: &TargetAddr) -> Self {
+        let (allowed_ip, allowed_port) = match requested {
+            TargetAddr::Ip(addr) if !addr.ip().is_unspecified() => (addr.ip(), addr.port()),
+            TargetAddr::Ip(addr) => (client.ip(), addr.port()),
+            TargetAddr::Domain(_, port) => (client.ip(), *port),
+        };
+        Self {
+            allowed_ip: allowed_ip.to_canonical(),
+            allowed_port: Some(allowed_port).filter(|&port| port != 0),
+            addr: None,
+            sent_to: None,
+        }
+    }
+
+    // The target and payload of `packet`, received from `from`, unless it is to be dropped:
+    // packets from anyone but the client, fragments and malformed packets are.
+    fn unpack(&mut self, packet: &[u8], from: SocketAddr) -> Option<(TargetAddr, Vec<u8>)> {
+        if !self.is(from) {
+            return None;
+        }
+        let (header, len) = UdpHeader::decode(packet).ok()?;
+        if header.frag != 0 {
+            return None;
+        }
+        if let Some(sent_to) = &mut self.sent_to {
+            sent_to.insert(header.target.clone());
+        }
+        Some((header.target, packet[len..].to_vec()))
+    }
+
+    // Where to send what `from` sent back, if anywhere.
+    fn answered_by(&self, from: &TargetAddr) -> Option<SocketAddr> {
+        let sent_to = match &self.sent_to {
+            Some(sent_to) => sent_to,
+            None => return self.addr,
+        };
+        // The outbound reports the addresses the domains resolved to, so domains only match by
+        // port.
+        let sent = sent_to.contains(from)
+            || sent_to.iter().any(|target| match (target, from) {
+                (TargetAddr::Domain(_, port), TargetAddr::Ip(from)) => *port == from.port(),
+                _ => false,
+            });
+        self.addr.filter(|_| sent)
+    }
+
+    // Whether `from` is the client, which is learned from its first datagram.
+    fn is(&mut self, from: SocketAddr) -> bool {
+        if let Some(addr) = self.addr {
+            return from == addr;
+        }
+        if from.ip().to_canonical() != self.allowed_ip
+            || self.allowed_port.is_some_and(|port| port != from.port())
+        {
+            return false;
+        }
+        self.addr = Some(from);
+        true
+    }
+}
+
+impl Targets {
+    // Send `payload` to `target`, dropping it if the target is denied or can't be reached.
+    async fn forward(&self, mut target: TargetAddr, payload: Vec<u8>) {
+        for policy in &self.policies {
+            let request = Request::new(RequestType::UdpAssociate, target);
+            target = match policy.check(&self.identity, &request).await {
+                Ok(Some(target)) => target,
+                _ => return,
+            };
+        }
+
+        if let Ok(n) = self.outbound.send_to(&payload, &target).await {
+            self.traffic.add_up(n);
+        }
+    }
+}
+
+// Encapsulate `payload`, received from the target at `from`, and send it to the client at `to`.
+async fn answer(
+    relay: &UdpSocket,
+    to: SocketAddr,
+    payload: &[u8],
+    from: TargetAddr,
+    traffic: &Traffic,
+) -> Result<()> {
+    let mut packet = Vec::with_capacity(3 + 262 + payload.len());
+    UdpHeader::new(0, from).encode(&mut packet)?;
+    packet.extend_from_slice(payload);
+    match relay.send_to(&packet, to).await {
+        Ok(_) => {
+            traffic.add_down(payload.len());
+            Ok(())
+        }
+        Err(e) if is_transient(&e) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Poll the datagrams in flight, dropping those sent. Resolves once some were, so that the caller
+// takes datagrams again if it stopped at `MAX_FORWARDING`.
+async fn drive(forwarding: &mut Vec<Forwarding<'_>>) {
+    poll_fn(|cx| {
+        let in_flight = forwarding.len();
+        forwarding.retain_mut(|forward| forward.as_mut().poll(cx).is_pending());
+        if forwarding.len() < in_flight {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+// Bind the socket the targets are reached from, dual-stack where IPv6 is available.
+fn bind_outbound() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)
+        .and_then(|socket| {
+            socket.set_only_v6(false)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+            Ok(socket)
+        })
+        .or_else(|_| {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+            Ok::<_, io::Error>(socket)
+        })?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Resolve once the client closes the control connection. Anything it sends on it is ignored.
+async fn closed<S: AsyncRead + Unpin>(control: &mut S) {
+    let mut buf = [0; 64];
+    while let Ok(n) = control.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+// Reported for an earlier datagram sent to a peer that was already gone.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+    use std::time::Duration;
+
+    use super::*;
+
+    // Hands the datagrams it receives from the client to a socket of its own, except those to
+    // `stalled`, whose sending never completes, or only after `stall` with one.
+    struct Stalling {
+        stalled: TargetAddr,
+        stall: Option<Duration>,
+        socket: UdpSocket,
+    }
+
+    #[async_trait]
+    impl DatagramOutbound for Stalling {
+        async fn send_to(&self, payload: &[u8], target: &TargetAddr) -> Result<usize> {
+            if *target == self.stalled {
+                match self.stall {
+                    Some(stall) => tokio::time::sleep(stall).await,
+                    None => pending().await,
+                }
+                return Ok(payload.len());
+            }
+            match target {
+                TargetAddr::Ip(addr) => Ok(self.socket.send_to(payload, addr).await?),
+                TargetAddr::Domain(..) => unreachable!(),
+            }
+        }
+
+        async fn recv_from(&self, _: &mut [u8]) -> Result<(usize, TargetAddr)> {
+            pending().await
+        }
+    }
+
+    fn packet(target: SocketAddr, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        UdpHeader::new(0, TargetAddr::Ip(target))
+            .encode(&mut packet)
+            .unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    // Run `association` for a client bound on the loopback, returning the client socket and the
+    // relay address.
+    async fn start(mut association: Association) -> (UdpSocket, SocketAddr) {
+        let relay = match association.relay_addr().unwrap() {
+            TargetAddr::Ip(relay) => relay,
+            TargetAddr::Domain(..) => unreachable!(),
+        };
+        tokio::spawn(async move {
+            let (mut control, _client) = tokio::io::duplex(64);
+            association.run(&mut control).await
+        });
+        (UdpSocket::bind("127.0.0.1:0").await.unwrap(), relay)
+    }
+
+    fn loopback() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwards_past_stalled_targets() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stalled = "192.0.2.1:53".parse().unwrap();
+        let outbound = Stalling {
+            stalled: TargetAddr::Ip(stalled),
+            stall: None,
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        };
+        let requested = TargetAddr::Ip(loopback());
+        let association = Association::bind(loopback(), loopback(), &requested, Box::new(outbound))
+            .await
+            .unwrap();
+        let (client, relay) = start(association).await;
+
+        client
+            .send_to(&packet(stalled, b"stuck"), relay)
+            .await
+            .unwrap();
+        let target_addr = target.local_addr().unwrap();
+        client
+            .send_to(&packet(target_addr, b"ping"), relay)
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        let received = tokio::time::timeout(Duration::from_secs(1), target.recv_from(&mut buf));
+        let (len, _) = received.await.unwrap().unwrap();
+        assert_eq!(&buf[..len], b"ping");
+    }
+
+    #[tokio::test]
+    async fn takes_datagrams_again_once_the_forwards_in_flight_complete() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent = "192.0.2.1:514".parse().unwrap();
+        let outbound = Stalling {
+            stalled: TargetAddr::Ip(silent),
+            stall: Some(Duration::from_millis(100)),
+            socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        };
+        let requested = TargetAddr::Ip(loopback());
+        let association = Association::bind(loopback(), loopback(), &requested, Box::new(outbound))
+            .await
+            .unwrap();
+        let (client, relay) = start(association).await;
+
+        for _ in 0..MAX_FORWARDING {
+            client
+                .send_to(&packet(silent, b"log"), relay)
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let target_addr = target.local_addr().unwrap();
+        client
+            .send_to(&packet(target_addr, b"ping"), relay)
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        let received = tokio::time::timeout(Duration::from_secs(1), target.recv_from(&mut buf));
+        let (len, _) = received.await.unwrap().unwrap();
+        assert_eq!(&buf[..len], b"ping");
+    }
+
+    #[tokio::test]
+    async fn drops_datagrams_from_sources_never_sent_to() {
+        let outbound = DirectOutbound::bind().unwrap();
+        let outbound_port = outbound.socket.local_addr().unwrap().port();
+        let requested = TargetAddr::Ip(loopback());
+        let association = Association::bind(loopback(), loopback(), &requested, Box::new(outbound))
+            .await
+            .unwrap()
+            .filter_sources(true);
+        let (client, relay) = start(association).await;
+
+        let (target, stranger) = (
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let target_addr = target.local_addr().unwrap();
+        client
+            .send_to(&packet(target_addr, b"ping"), relay)
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        let (_, outbound_addr) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(outbound_addr.port(), outbound_port);
+
+        stranger.send_to(b"spam", outbound_addr).await.unwrap();
+        target.send_to(b"pong", outbound_addr).await.unwrap();
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], &packet(target_addr, b"pong")[..]);
+    }
+}
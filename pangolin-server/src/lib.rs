@@ -0,0 +1,34 @@
+//! The tokio SOCKS5 server of pangolin, built on the codec of `pangolin-proto` and chaining
+//! through upstream proxies with `pangolin-client`.
+
+mod access;
+mod admission;
+mod associate;
+mod rate_limit;
+mod registry;
+mod resolver;
+mod resumption;
+mod server;
+mod server_auth;
+mod service;
+mod upstream;
+mod users;
+
+pub use self::access::{AccessPolicy, AccessRules, Destination};
+pub use self::associate::{DatagramOutbound, DirectOutbound};
+pub use self::rate_limit::{RateLimit, RateLimiter};
+pub use self::registry::{ServerStats, SessionInfo, SessionLimits, SessionRegistry};
+pub use self::resolver::{Resolver, SystemResolver, TargetResolver};
+pub use self::resumption::Resumption;
+pub use self::server::{
+    Connection, Dialer, DirectDialer, DomainPolicy, RequestHandler, ServerConfig, ServerListener,
+    Socks5Server,
+};
+pub use self::server_auth::{ClientStream, Identity, NoAuth, ServerAuth, UserPassAuth};
+pub use self::service::{
+    AccessControl, AccessLayer, Layer, RateLimitLayer, RateLimited, ServerRequest, Service,
+};
+pub use self::upstream::{Upstream, UpstreamDialer};
+#[cfg(feature = "htpasswd")]
+pub use self::users::FileUserStore;
+pub use self::users::{MemoryUserStore, UserStore};
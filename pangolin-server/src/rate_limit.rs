@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+use crate::Identity;
+
+/// A sustained rate in bytes per second, and the burst allowed above it after a quiet period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    rate: u64,
+    burst: u64,
+}
+
+impl RateLimit {
+    /// `rate` bytes per second, with a burst of one second worth of data.
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self { rate, burst: rate }
+    }
+
+    pub fn burst(mut self, burst: u64) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+}
+
+/// Limits the upload, from the clients, and the download, to the clients, of the CONNECT
+/// sessions of a `Socks5Server`, each connection having buckets of its own.
+///
+/// With `per_user`, all the connections of an authenticated user share the same buckets instead,
+/// so opening more of them doesn't get the user more bandwidth. Clones share the buckets of the
+/// users.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    upload: Option<RateLimit>,
+    download: Option<RateLimit>,
+    per_user: bool,
+    users: Arc<Mutex<HashMap<String, Weak<Buckets>>>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn upload(mut self, limit: RateLimit) -> Self {
+        self.upload = Some(limit);
+        self
+    }
+
+    pub fn download(mut self, limit: RateLimit) -> Self {
+        self.download = Some(limit);
+        self
+    }
+
+    /// Share the buckets among the connections of each authenticated user. Anonymous clients
+    /// are still limited connection by connection.
+    pub fn per_user(mut self, per_user: bool) -> Self {
+        self.per_user = per_user;
+        self
+    }
+
+    /// The buckets of a connection of the client authenticated as `identity`.
+    pub(crate) fn buckets(&self, identity: &Identity) -> Arc<Buckets> {
+        let username = match identity.username() {
+            Some(username) if self.per_user => username,
+            _ => return Arc::new(self.new_buckets()),
+        };
+
+        let mut users = self.users.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(buckets) = users.get(username).and_then(Weak::upgrade) {
+            return buckets;
+        }
+        // Forget the users whose connections all ended.
+        users.retain(|_, buckets| buckets.strong_count() > 0);
+        let buckets = Arc::new(self.new_buckets());
+        users.insert(username.to_owned(), Arc::downgrade(&buckets));
+        buckets
+    }
+
+    fn new_buckets(&self) -> Buckets {
+        Buckets {
+            upload: self.upload.map(TokenBucket::new),
+            download: self.download.map(TokenBucket::new),
+        }
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("upload", &self.upload)
+            .field("download", &self.download)
+            .field("per_user", &self.per_user)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    // The tokens left, negative while in debt for the bytes of the last read or write, and when
+    // they were counted.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            state: Mutex::new((limit.burst as f64, Instant::now())),
+        }
+    }
+
+    // How long to wait before transferring more, if the bucket is empty.
+    fn delay(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let (tokens, updated) = &mut *state;
+        let now = Instant::now();
+        let refill = now.duration_since(*updated).as_secs_f64() * self.limit.rate as f64;
+        *tokens = (*tokens + refill).min(self.limit.burst as f64);
+        *updated = now;
+
+        if *tokens > 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            (1.0 - *tokens) / self.limit.rate as f64,
+        ))
+    }
+
+    fn consume(&self, n: usize) {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).0 -= n as f64;
+    }
+}
+
+/// The connection of a client, reading and writing no faster than its buckets allow.
+///
+/// Transfers are allowed as long as a bucket isn't empty, and may take it into debt by the size
+/// of a read or write, which is paid back before the next one.
+pub(crate) struct Throttled<S> {
+    inner: S,
+    buckets: Arc<Buckets>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub(crate) fn new(inner: S, buckets: Arc<Buckets>) -> Self {
+        Self {
+            inner,
+            buckets,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+// Wait for `bucket` to have tokens again, keeping the timer in `delay` meanwhile.
+fn poll_bucket(
+    cx: &mut Context<'_>,
+    bucket: Option<&TokenBucket>,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+) -> Poll<()> {
+    let bucket = match bucket {
+        Some(bucket) => bucket,
+        None => return Poll::Ready(()),
+    };
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        match bucket.delay() {
+            Some(duration) => *delay = Some(Box::pin(sleep(duration))),
+            None => return Poll::Ready(()),
+        }
+    }
+}
+
+impl<S> AsyncRead for Throttled<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let bucket = this.buckets.upload.as_ref();
+        ready!(poll_bucket(cx, bucket, &mut this.read_delay));
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(bucket) = bucket {
+            bucket.consume(buf.filled().len() - filled);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for Throttled<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let bucket = this.buckets.download.as_ref();
+        ready!(poll_bucket(cx, bucket, &mut this.write_delay));
+
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some(bucket) = bucket {
+            bucket.consume(n);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn millis(duration: Duration) -> u128 {
+        (duration.as_secs_f64() * 1000.0).round() as u128
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_buckets_at_the_rate_up_to_the_burst() {
+        let bucket = TokenBucket::new(RateLimit::new(100).burst(50));
+        assert_eq!(bucket.delay(), None);
+
+        bucket.consume(150);
+        assert_eq!(bucket.delay().map(millis), Some(1010));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(bucket.delay().map(millis), Some(10));
+
+        // Quiet periods only refill up to the burst.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(bucket.delay(), None);
+        bucket.consume(50);
+        assert_eq!(bucket.delay().map(millis), Some(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttles_transfers_past_the_burst() {
+        let limiter = RateLimiter::new().download(RateLimit::new(100));
+        let (client, _peer) = tokio::io::duplex(1024);
+        let mut throttled = Throttled::new(client, limiter.buckets(&Identity::Anonymous));
+
+        let started = Instant::now();
+        for _ in 0..4 {
+            throttled.write_all(&[0; 100]).await.unwrap();
+        }
+        // The burst lets the first write through, the others wait for the bytes before them.
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn shares_buckets_per_user_only() {
+        let limiter = RateLimiter::new().upload(RateLimit::new(100));
+        let alice = Identity::User("alice".to_owned());
+        assert!(!Arc::ptr_eq(
+            &limiter.buckets(&alice),
+            &limiter.buckets(&alice)
+        ));
+
+        let limiter = limiter.per_user(true);
+        let buckets = limiter.buckets(&alice);
+        assert!(Arc::ptr_eq(&buckets, &limiter.buckets(&alice)));
+        assert!(Arc::ptr_eq(&buckets, &limiter.clone().buckets(&alice)));
+        let bob = Identity::User("bob".to_owned());
+        assert!(!Arc::ptr_eq(&buckets, &limiter.buckets(&bob)));
+        assert!(!Arc::ptr_eq(
+            &limiter.buckets(&Identity::Anonymous),
+            &limiter.buckets(&Identity::Anonymous)
+        ));
+
+        // Users are forgotten once their connections ended.
+        drop(buckets);
+        limiter.buckets(&bob);
+        assert!(!limiter.users.lock().unwrap().contains_key("alice"));
+    }
+}
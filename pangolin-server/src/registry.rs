@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use pangolin_client::SessionId;
+use pangolin_proto::{RequestType, Socks5Error, TargetAddr};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep_until, Instant};
+
+use crate::Identity;
+
+/// The sessions of a `Socks5Server` in progress, and counters of how its clients fared.
+///
+/// Clones share the registry, so it can be handed to e.g. an admin endpoint running next to the
+/// server.
+#[derive(Debug, Clone, Default)]
+pub struct SessionRegistry {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    sessions: Mutex<HashMap<SessionId, Entry>>,
+    handshake_failures: AtomicU64,
+    accepts_paused: AtomicU64,
+    connections_rejected: AtomicU64,
+    address_types: Mutex<BTreeMap<u8, u64>>,
+    idle_timeouts: AtomicU64,
+    lifetime_timeouts: AtomicU64,
+    replies: Mutex<BTreeMap<u8, u64>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    client: SocketAddr,
+    identity: Identity,
+    command: RequestType,
+    destination: TargetAddr,
+    started: SystemTime,
+    traffic: Arc<Traffic>,
+}
+
+/// A session in progress, as of `SessionRegistry::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub client: SocketAddr,
+    pub identity: Identity,
+    pub command: RequestType,
+    /// The target of the request, as sent by the client.
+    pub destination: TargetAddr,
+    /// The bytes received from the client, and for UDP the payloads it sent.
+    pub bytes_up: u64,
+    /// The bytes sent to the client, and for UDP the payloads it was sent.
+    pub bytes_down: u64,
+    pub started: SystemTime,
+}
+
+/// The counters of a `SessionRegistry`, since the server started.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Clients that went away, timed out or failed to authenticate before their request was read.
+    pub handshake_failures: u64,
+    /// How many times the server stopped accepting, having as many clients as it may serve at
+    /// once. The clients meanwhile wait in the backlog of the listener.
+    pub accepts_paused: u64,
+    /// Clients closed once accepted, having as many connections to the server as a client may.
+    pub connections_rejected: u64,
+    /// The number of requests read with each address type: `0x01` for IPv4, `0x03` for domains
+    /// and `0x04` for IPv6.
+    pub address_types: BTreeMap<u8, u64>,
+    /// Sessions closed after nothing was relayed for their idle timeout.
+    pub idle_timeouts: u64,
+    /// Sessions closed once they lasted for their maximum duration.
+    pub lifetime_timeouts: u64,
+    /// The number of replies sent with each reply code, `0x00` counting the successes.
+    pub replies: BTreeMap<u8, u64>,
+}
+
+/// How long the sessions of a `Socks5Server` may last, CONNECT sessions and UDP associations
+/// alike. They aren't limited by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionLimits {
+    idle: Option<Duration>,
+    lifetime: Option<Duration>,
+}
+
+impl SessionLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Close the sessions that relayed nothing, in either direction, for `timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle = Some(timeout);
+        self
+    }
+
+    /// Close the sessions once they lasted for `duration`, however busy they are.
+    pub fn max_duration(mut self, duration: Duration) -> Self {
+        self.lifetime = Some(duration);
+        self
+    }
+}
+
+/// The bytes transferred by a session so far, and when it last transferred some.
+#[derive(Debug)]
+pub(crate) struct Traffic {
+    up: AtomicU64,
+    down: AtomicU64,
+    started: Instant,
+    // Milliseconds since `started`.
+    active: AtomicU64,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Self {
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+            started: Instant::now(),
+            active: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Traffic {
+    pub(crate) fn add_up(&self, n: usize) {
+        self.up.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub(crate) fn add_down(&self, n: usize) {
+        self.down.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        let active = self.started.elapsed().as_millis() as u64;
+        self.active.fetch_max(active, Ordering::Relaxed);
+    }
+
+    fn last_active(&self) -> Instant {
+        self.started + Duration::from_millis(self.active.load(Ordering::Relaxed))
+    }
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The sessions in progress, oldest first.
+    pub fn snapshot(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(id, entry)| SessionInfo {
+                id: *id,
+                client: entry.client,
+                identity: entry.identity.clone(),
+                command: entry.command,
+                destination: entry.destination.clone(),
+                bytes_up: entry.traffic.up.load(Ordering::Relaxed),
+                bytes_down: entry.traffic.down.load(Ordering::Relaxed),
+                started: entry.started,
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            handshake_failures: self.inner.handshake_failures.load(Ordering::Relaxed),
+            accepts_paused: self.inner.accepts_paused.load(Ordering::Relaxed),
+            connections_rejected: self.inner.connections_rejected.load(Ordering::Relaxed),
+            address_types: self
+                .inner
+                .address_types
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+            idle_timeouts: self.inner.idle_timeouts.load(Ordering::Relaxed),
+            lifetime_timeouts: self.inner.lifetime_timeouts.load(Ordering::Relaxed),
+            replies: self
+                .inner
+                .replies
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        }
+    }
+
+    /// Register the session of `client`, which is removed once the returned guard is dropped.
+    pub(crate) fn open(
+        &self,
+        client: SocketAddr,
+        identity: Identity,
+        command: RequestType,
+        destination: TargetAddr,
+    ) -> ActiveSession {
+        let id = SessionId::next();
+        let traffic = Arc::new(Traffic::default());
+        self.lock().insert(
+            id,
+            Entry {
+                client,
+                identity,
+                command,
+                destination,
+                started: SystemTime::now(),
+                traffic: traffic.clone(),
+            },
+        );
+        ActiveSession {
+            registry: self.clone(),
+            id,
+            traffic,
+        }
+    }
+
+    pub(crate) fn handshake_failed(&self) {
+        self.inner
+            .handshake_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn accept_paused(&self) {
+        self.inner.accepts_paused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_rejected(&self) {
+        self.inner
+            .connections_rejected
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn requested(&self, target: &TargetAddr) {
+        let address_type = match target {
+            TargetAddr::Ip(SocketAddr::V4(_)) => 0x01,
+            TargetAddr::Domain(..) => 0x03,
+            TargetAddr::Ip(SocketAddr::V6(_)) => 0x04,
+        };
+        *self
+            .inner
+            .address_types
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(address_type)
+            .or_default() += 1;
+    }
+
+    pub(crate) fn replied(&self, code: u8) {
+        *self
+            .inner
+            .replies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(code)
+            .or_default() += 1;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<SessionId, Entry>> {
+        self.inner
+            .sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A session listed in a `SessionRegistry` until it is dropped.
+#[derive(Debug)]
+pub(crate) struct ActiveSession {
+    registry: SessionRegistry,
+    id: SessionId,
+    traffic: Arc<Traffic>,
+}
+
+impl ActiveSession {
+    pub(crate) fn traffic(&self) -> Arc<Traffic> {
+        self.traffic.clone()
+    }
+
+    pub(crate) fn registry(&self) -> &SessionRegistry {
+        &self.registry
+    }
+
+    /// Resolve once the session went past one of `limits`, with the error it is closed with,
+    /// counting it in the registry. Never resolves if it isn't limited.
+    pub(crate) async fn expired(&self, limits: SessionLimits) -> Socks5Error {
+        let lifetime = limits
+            .lifetime
+            .map(|lifetime| self.traffic.started + lifetime);
+        loop {
+            let idle = limits.idle.map(|idle| self.traffic.last_active() + idle);
+            let deadline = match (idle, lifetime) {
+                (Some(idle), Some(lifetime)) => idle.min(lifetime),
+                (Some(deadline), None) | (None, Some(deadline)) => deadline,
+                (None, None) => std::future::pending().await,
+            };
+            sleep_until(deadline).await;
+
+            let now = Instant::now();
+            if lifetime.is_some_and(|lifetime| lifetime <= now) {
+                let counter = &self.registry.inner.lifetime_timeouts;
+                counter.fetch_add(1, Ordering::Relaxed);
+                return timed_out("session reached its maximum duration");
+            }
+            // Traffic may have been relayed meanwhile, pushing the idle deadline back.
+            if limits
+                .idle
+                .is_some_and(|idle| self.traffic.last_active() + idle <= now)
+            {
+                let counter = &self.registry.inner.idle_timeouts;
+                counter.fetch_add(1, Ordering::Relaxed);
+                return timed_out("session was idle for too long");
+            }
+        }
+    }
+}
+
+fn timed_out(reason: &'static str) -> Socks5Error {
+    io::Error::new(io::ErrorKind::TimedOut, reason).into()
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        self.registry.lock().remove(&self.id);
+    }
+}
+
+/// The connection of a client, counting the bytes read from it as up and written to it as down.
+pub(crate) struct Counted<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(inner: S, traffic: Arc<Traffic>) -> Self {
+        Self { inner, traffic }
+    }
+}
+
+impl<S> AsyncRead for Counted<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.traffic.add_up(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for Counted<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.traffic.add_down(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
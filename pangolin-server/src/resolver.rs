@@ -0,0 +1,103 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pangolin_client::{AddressFamily, DnsCache};
+use tokio::net::lookup_host;
+
+/// Resolves the domain targets of the requests a `Socks5Server` receives, e.g. with the system
+/// resolver or a client of a DNS server such as hickory's.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// The addresses of `host`, with `port`.
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+impl fmt::Debug for dyn Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+/// The resolver of the host, as used by `getaddrinfo`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(lookup_host((host, port)).await?.collect())
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsCache {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.lookup(host, port).await
+    }
+}
+
+/// How a `DirectDialer` resolves domain targets: with which resolver, which addresses it tries
+/// first and how long it waits for an answer.
+///
+/// Failures, timeouts and domains without an address of the allowed families are all reported
+/// as `io::ErrorKind::HostUnreachable`, which the server replies to the client with.
+#[derive(Debug, Clone)]
+pub struct TargetResolver {
+    resolver: Arc<dyn Resolver>,
+    family: AddressFamily,
+    timeout: Option<Duration>,
+}
+
+impl TargetResolver {
+    pub fn new(resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            resolver,
+            family: AddressFamily::Any,
+            timeout: None,
+        }
+    }
+
+    pub fn family(mut self, family: AddressFamily) -> Self {
+        self.family = family;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The addresses of `host` to try, in order.
+    pub async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let resolved = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.resolver.resolve(host, port))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut))),
+            None => self.resolver.resolve(host, port).await,
+        };
+        let addrs = resolved.map_err(|e| unreachable(host, &e))?;
+        let addrs = self.family.apply(addrs);
+        if addrs.is_empty() {
+            return Err(unreachable(host, &"no usable address"));
+        }
+        Ok(addrs)
+    }
+}
+
+impl Default for TargetResolver {
+    /// The system resolver, keeping its order, without a timeout.
+    fn default() -> Self {
+        Self::new(Arc::new(SystemResolver))
+    }
+}
+
+fn unreachable(host: &str, cause: &dyn fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::HostUnreachable,
+        format!("failed to resolve {}: {}", host, cause),
+    )
+}
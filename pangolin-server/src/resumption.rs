@@ -0,0 +1,169 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use pangolin_proto::{Result, Version};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::oneshot;
+
+use crate::associate::Association;
+use crate::ClientStream;
+
+const RESUMPTION_VERSION: Version = Version::new(0x01);
+
+// Whether the token of the client re-attached a parked association, or a new one was issued.
+const RESUMED: u8 = 0x00;
+const ISSUED: u8 = 0x01;
+
+type Token = [u8; 16];
+
+// Asks the session holding a parked association to hand it over on the enclosed sender.
+type Claim = oneshot::Sender<Association>;
+
+/// Lets the clients of a `Socks5Server` re-attach to their UDP association after their control
+/// connection dropped, for pangolin-to-pangolin chains, preserving the relay address and the NAT
+/// mappings of the outbound.
+///
+/// Clients offering the private method `code` get a token in its sub-negotiation, which they
+/// present again when they reconnect. Once the control connection of an association closes, the
+/// association is parked for the grace period: its sockets stay open, nothing being relayed,
+/// and the session holds on to its slot until the association is resumed or the grace period
+/// ends. The method authenticates no one, and is only selected for clients the `ServerAuth`
+/// would let in anonymously.
+#[derive(Clone)]
+pub struct Resumption {
+    code: u8,
+    grace: Duration,
+    parked: Arc<Mutex<HashMap<Token, oneshot::Sender<Claim>>>>,
+}
+
+impl Resumption {
+    /// Resume with the private method `code` (0x80-0xFE), keeping associations for `grace` after
+    /// their control connection closed.
+    pub fn new(code: u8, grace: Duration) -> Self {
+        Self {
+            code,
+            grace,
+            parked: Arc::default(),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    /// Run the sub-negotiation of the method over `stream`: re-attach the association of the
+    /// token the client presents, or issue a new token.
+    pub(crate) async fn negotiate(&self, stream: &mut dyn ClientStream) -> Result<Resume> {
+        // +----+------+----------+
+        // |VER | TLEN |  TOKEN   |
+        // +----+------+----------+
+        // | 1  |  1   | 0 to 255 |
+        // +----+------+----------+
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await?;
+        RESUMPTION_VERSION.check_request(header[0])?;
+        let mut presented = vec![0; header[1] as usize];
+        stream.read_exact(&mut presented).await?;
+
+        let resumed = match Token::try_from(&presented[..]) {
+            Ok(token) => self
+                .claim(token)
+                .await
+                .map(|association| (token, association)),
+            Err(_) => None,
+        };
+        let (status, token, association) = match resumed {
+            Some((token, association)) => (RESUMED, token, Some(association)),
+            None => (ISSUED, new_token(), None),
+        };
+
+        // +----+--------+------+----------+
+        // |VER | STATUS | TLEN |  TOKEN   |
+        // +----+--------+------+----------+
+        // | 1  |   1    |  1   |    16    |
+        // +----+--------+------+----------+
+        let mut reply = vec![RESUMPTION_VERSION.as_u8(), status, token.len() as u8];
+        reply.extend_from_slice(&token);
+        stream.write_all(&reply).await?;
+        Ok(Resume {
+            token,
+            association,
+            resumption: self.clone(),
+        })
+    }
+
+    // Take the association parked under `token` from the session holding it, if any.
+    async fn claim(&self, token: Token) -> Option<Association> {
+        let parked = self
+            .parked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&token)?;
+        let (claim, claimed) = oneshot::channel();
+        parked.send(claim).ok()?;
+        claimed.await.ok()
+    }
+
+    // Hold `association` for the grace period, handing it over if a client claims it meanwhile.
+    async fn park(&self, token: Token, association: Association) {
+        let (parked, claimed) = oneshot::channel();
+        self.parked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(token, parked);
+        match tokio::time::timeout(self.grace, claimed).await {
+            Ok(Ok(claim)) => drop(claim.send(association)),
+            _ => {
+                self.parked
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&token);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Resumption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resumption")
+            .field("code", &self.code)
+            .field("grace", &self.grace)
+            .finish()
+    }
+}
+
+/// The token a client negotiated with `Resumption`, along with the association it re-attached.
+pub(crate) struct Resume {
+    token: Token,
+    association: Option<Association>,
+    resumption: Resumption,
+}
+
+impl Resume {
+    /// The association the token re-attached, if it did.
+    pub(crate) fn take(&mut self) -> Option<Association> {
+        self.association.take()
+    }
+
+    /// Park `association`, whose control connection closed, for the grace period.
+    pub(crate) async fn park(&self, association: Association) {
+        self.resumption.park(self.token, association).await
+    }
+}
+
+// A token no one can guess: the hashes of a counter under the random keys of the process.
+fn new_token() -> Token {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let next = NEXT.fetch_add(1, Ordering::Relaxed);
+    let keys = RandomState::new();
+    let mut token = [0; 16];
+    token[..8].copy_from_slice(&keys.hash_one((next, 0)).to_le_bytes());
+    token[8..].copy_from_slice(&keys.hash_one((next, 1)).to_le_bytes());
+    token
+}
@@ -0,0 +1,1224 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pangolin_client::{relay, RelayConfig};
+use pangolin_proto::{Request, RequestType, Result, Socks5Error, TargetAddr, Version, VERSION};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::task_tracker::TaskTrackerToken;
+use tokio_util::task::TaskTracker;
+
+use crate::admission::{Admission, Admitted};
+use crate::associate::{Association, DatagramOutbound, DirectOutbound};
+use crate::registry::Counted;
+use crate::resumption::Resume;
+use crate::service::{reply_code, unspecified, write_reply};
+use crate::{
+    AccessLayer, AccessPolicy, ClientStream, Identity, Layer, NoAuth, RateLimitLayer, RateLimiter,
+    Resumption, ServerAuth, ServerRequest, Service, SessionLimits, SessionRegistry, TargetResolver,
+};
+
+// The largest request: a domain of 255 bytes.
+const MAX_REQUEST_LEN: usize = 3 + 1 + 1 + 255 + 2;
+
+/// How the server reaches the targets of CONNECT requests and UDP associations, e.g. directly or
+/// through another proxy.
+#[async_trait]
+pub trait Dialer: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    /// Connect to `target`, returning the connection along with the address it is bound to,
+    /// reported to the client. Failures are reported to the client with the reply code of the
+    /// error.
+    async fn dial(&self, target: &TargetAddr) -> Result<(Self::Stream, TargetAddr)>;
+
+    /// Open the outbound the datagrams of a UDP association are exchanged with their targets
+    /// on. The default exchanges them directly, from the host of the server.
+    async fn associate(&self) -> Result<Box<dyn DatagramOutbound>> {
+        Ok(Box::new(DirectOutbound::bind()?))
+    }
+}
+
+/// Connects to the targets from the host of the server, resolving domains locally with its
+/// `TargetResolver`, the system resolver by default.
+///
+/// Connecting may be limited by host, to protect the targets from floods of connections through
+/// the proxy: connections taking longer than the connect timeout fail with `TtlExpired`, and
+/// those beyond the number of connections a host may have in progress at once fail with
+/// `GeneralSocksServerFailure` right away. Clones share the connections in progress.
+#[derive(Debug, Clone, Default)]
+pub struct DirectDialer {
+    resolver: TargetResolver,
+    connect_timeout: Option<Duration>,
+    max_connecting: Option<usize>,
+    // The number of connections in progress to each host.
+    connecting: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl DirectDialer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve the domain targets of CONNECT requests and UDP datagrams with `resolver`.
+    pub fn resolver(mut self, resolver: TargetResolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Give up connecting to a target after `timeout`, resolution excluded.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Connect to at most `max` targets on the same host, a domain or an address, at once.
+    pub fn max_connecting(mut self, max: usize) -> Self {
+        self.max_connecting = Some(max);
+        self
+    }
+
+    // Count a connection to `host` as in progress until the returned guard is dropped.
+    fn start_connecting(&self, host: String) -> Result<Connecting<'_>> {
+        let mut connecting = self
+            .connecting
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let count = connecting.entry(host.clone()).or_default();
+        if self.max_connecting.is_some_and(|max| *count >= max) {
+            return Err(Socks5Error::GeneralSocksServerFailure);
+        }
+        *count += 1;
+        Ok(Connecting {
+            connecting: &self.connecting,
+            host,
+        })
+    }
+}
+
+// A connection to `host` in progress.
+struct Connecting<'a> {
+    connecting: &'a Mutex<HashMap<String, usize>>,
+    host: String,
+}
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        let mut connecting = self
+            .connecting
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = connecting.get_mut(&self.host) {
+            *count -= 1;
+            if *count == 0 {
+                connecting.remove(&self.host);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for DirectDialer {
+    type Stream = TcpStream;
+
+    async fn dial(&self, target: &TargetAddr) -> Result<(TcpStream, TargetAddr)> {
+        let (host, addrs) = match target {
+            TargetAddr::Ip(addr) => (addr.ip().to_string(), vec![*addr]),
+            TargetAddr::Domain(domain, port) => (
+                domain.to_ascii_lowercase(),
+                self.resolver.resolve(domain, *port).await?,
+            ),
+        };
+
+        let _connecting = self.start_connecting(host)?;
+        let stream = match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, TcpStream::connect(&addrs[..]))
+                .await
+                .map_err(|_| Socks5Error::TtlExpired)??,
+            None => TcpStream::connect(&addrs[..]).await?,
+        };
+        let bound = stream.local_addr()?;
+        Ok((stream, TargetAddr::Ip(bound)))
+    }
+
+    async fn associate(&self) -> Result<Box<dyn DatagramOutbound>> {
+        Ok(Box::new(DirectOutbound::bind_with_resolver(
+            self.resolver.clone(),
+        )?))
+    }
+}
+
+/// What a `Socks5Server` does with the requests whose target is a domain (ATYP `0x03`).
+#[derive(Debug, Clone, Default)]
+pub enum DomainPolicy {
+    /// Hand them to the services as they are, the dialer resolving them.
+    #[default]
+    Allow,
+    /// Refuse them with `AddressTypeNotSupported`, so that the proxy host never resolves a name
+    /// for its clients, which have to resolve them themselves.
+    Reject,
+    /// Resolve them with the resolver first, handing the services the first address instead.
+    /// Those that don't resolve are refused with `HostUnreachable`.
+    Resolve(TargetResolver),
+}
+
+/// Options of a `Socks5Server`.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// How clients are authenticated. Only the no authentication method is accepted without
+    /// one.
+    pub auth: Option<Arc<dyn ServerAuth>>,
+    /// Which requests are carried out. All of them are without one. Used by `Socks5Server::new`,
+    /// as an `AccessLayer`.
+    pub access: Option<Arc<dyn AccessPolicy>>,
+    /// How the `AccessLayer` of `access` resolves domain targets, to check their addresses and
+    /// connect to the first of them. The system resolver without one.
+    pub access_resolver: Option<TargetResolver>,
+    /// What is done with domain targets, before the services see the requests.
+    pub domain_policy: DomainPolicy,
+    /// Close the connections of clients that haven't sent their request within this long,
+    /// instead of holding them open forever.
+    pub handshake_timeout: Option<Duration>,
+    /// Options of the relay between each client and its target.
+    pub relay: RelayConfig,
+    /// How long the sessions may last. Used by `Socks5Server::new`, for its `RequestHandler`.
+    pub session_limits: SessionLimits,
+    /// Drop the datagrams of UDP associations coming from sources their client never sent to.
+    /// Used by `Socks5Server::new`, for its `RequestHandler`.
+    pub filter_datagram_sources: bool,
+    /// How fast the CONNECT sessions may transfer. They aren't limited without one. Used by
+    /// `Socks5Server::new`, as a `RateLimitLayer`.
+    pub rate_limiter: Option<RateLimiter>,
+    /// Serve at most this many clients at once, from their connection to the end of their
+    /// session, so that a busy server doesn't run out of file descriptors. Once reached, the
+    /// server stops accepting until a client leaves, and new clients wait in the backlog of the
+    /// listener.
+    pub max_connections: Option<usize>,
+    /// Let the clients offering its private method resume their UDP associations from a new
+    /// control connection. Used by the `RequestHandler` of `Socks5Server::new`.
+    pub resumption: Option<Resumption>,
+    /// Serve at most this many clients from each IP address at once. Further connections from
+    /// the address are closed as soon as they are accepted. The clients of a Unix socket all
+    /// count as the loopback address.
+    pub max_connections_per_ip: Option<usize>,
+}
+
+/// Carries out the requests that reach it: it dials the targets of CONNECT requests with its
+/// `Dialer` and relays their traffic, relays UDP associations over the outbound of the `Dialer`
+/// and refuses BIND with `CommandNotSupported`.
+///
+/// Sessions going past their `SessionLimits` are closed, failing with `io::ErrorKind::TimedOut`,
+/// and counted in the `ServerStats`.
+#[derive(Debug)]
+pub struct RequestHandler<D> {
+    dialer: D,
+    relay: RelayConfig,
+    limits: SessionLimits,
+    filter_sources: bool,
+}
+
+impl<D> RequestHandler<D>
+where
+    D: Dialer,
+{
+    pub fn new(dialer: D, relay: RelayConfig) -> Self {
+        Self {
+            dialer,
+            relay,
+            limits: SessionLimits::default(),
+            filter_sources: false,
+        }
+    }
+
+    pub fn limits(mut self, limits: SessionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Drop the datagrams coming back to a UDP association from sources its client never sent
+    /// to, if `filter` is set. Sources are matched by port alone for domain targets.
+    pub fn filter_sources(mut self, filter: bool) -> Self {
+        self.filter_sources = filter;
+        self
+    }
+}
+
+#[async_trait]
+impl<D> Service for RequestHandler<D>
+where
+    D: Dialer,
+{
+    async fn call(&self, mut request: ServerRequest) -> Result<()> {
+        match request.request.request_type() {
+            RequestType::Connect => {
+                let (target, bound) = match self.dialer.dial(request.request.target_addr()).await {
+                    Ok(dialed) => dialed,
+                    Err(e) => return request.reject(e).await,
+                };
+                request.reply(0x00, &bound).await?;
+                let client = Counted::new(request.stream, request.session.traffic());
+                tokio::select! {
+                    relayed = relay(client, target, self.relay) => {
+                        relayed?;
+                        Ok(())
+                    }
+                    e = request.session.expired(self.limits) => Err(e),
+                }
+            }
+            RequestType::UdpAssociate => {
+                let bound = match request.resume.as_mut().and_then(Resume::take) {
+                    Some(parked) => {
+                        Ok(parked.reattach(request.client, request.request.target_addr()))
+                    }
+                    None => {
+                        let requested = request.request.target_addr();
+                        self.associate(request.local_addr, request.client, requested)
+                            .await
+                    }
+                };
+                let mut association = match bound {
+                    Ok(association) => association
+                        .restrict(request.datagram_policies.clone(), request.identity.clone())
+                        .count(request.session.traffic()),
+                    Err(e) => return request.reject(e).await,
+                };
+                request.reply(0x00, &association.relay_addr()?).await?;
+                let ran = tokio::select! {
+                    ran = association.run(&mut request.stream) => ran,
+                    e = request.session.expired(self.limits) => Err(e),
+                };
+                // The client closed the control connection, maybe only to reconnect.
+                match (ran, &request.resume) {
+                    (Ok(()), Some(resume)) => {
+                        resume.park(association).await;
+                        Ok(())
+                    }
+                    (ran, _) => ran,
+                }
+            }
+            RequestType::Bind => request.reject(Socks5Error::CommandNotSupported).await,
+        }
+    }
+}
+
+impl<D> RequestHandler<D>
+where
+    D: Dialer,
+{
+    // Bind a new association for `client`, connected to the server at `local_addr`, on the
+    // outbound of the dialer.
+    async fn associate(
+        &self,
+        local_addr: SocketAddr,
+        client: SocketAddr,
+        requested: &TargetAddr,
+    ) -> Result<Association> {
+        let outbound = self.dialer.associate().await?;
+        let association = Association::bind(local_addr, client, requested, outbound).await?;
+        Ok(association.filter_sources(self.filter_sources))
+    }
+}
+
+/// What a `Socks5Server` accepts its clients on.
+#[derive(Debug)]
+pub enum ServerListener {
+    Tcp(TcpListener),
+    /// A Unix socket, e.g. for sandboxed applications talking to a local daemon. Its clients are
+    /// seen as connecting from, and to, the IPv4 loopback address with port 0, which is also
+    /// where their UDP associations are relayed.
+    #[cfg(unix)]
+    Unix(UnixListener),
+    /// Connections redirected to the listener by the firewall, e.g. by an iptables `REDIRECT`
+    /// rule, to act as a transparent proxy. There is no SOCKS handshake: each connection is an
+    /// anonymous CONNECT request to the destination it was originally sent to, as reported by
+    /// `SO_ORIGINAL_DST`, going through the services like any other. Nothing is replied to the
+    /// clients, whose connections are closed if their request fails.
+    #[cfg(target_os = "linux")]
+    Transparent(TcpListener),
+}
+
+// A client accepted by a `ServerListener`.
+struct Accepted {
+    socket: Box<dyn ClientStream>,
+    client: SocketAddr,
+    // The address the client connected to.
+    local_addr: SocketAddr,
+    // Where a redirected connection was sent to.
+    original_dst: Option<SocketAddr>,
+}
+
+impl ServerListener {
+    async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            ServerListener::Tcp(listener) => {
+                let (socket, client) = listener.accept().await?;
+                Ok(Accepted {
+                    local_addr: socket.local_addr()?,
+                    socket: Box::new(socket),
+                    client,
+                    original_dst: None,
+                })
+            }
+            #[cfg(unix)]
+            ServerListener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok(Accepted {
+                    socket: Box::new(socket),
+                    client: unix_peer(),
+                    local_addr: unix_peer(),
+                    original_dst: None,
+                })
+            }
+            #[cfg(target_os = "linux")]
+            ServerListener::Transparent(listener) => {
+                let (socket, client) = listener.accept().await?;
+                let local_addr = socket.local_addr()?;
+                // Fails for the connections that weren't redirected, or that were redirected
+                // to the listener itself, which would loop through it.
+                let original_dst = original_dst(&socket)
+                    .ok()
+                    .filter(|original_dst| *original_dst != local_addr);
+                Ok(Accepted {
+                    socket: Box::new(socket),
+                    client,
+                    local_addr,
+                    original_dst: Some(original_dst.unwrap_or(local_addr)),
+                })
+            }
+        }
+    }
+}
+
+// The destination of a connection redirected by netfilter.
+#[cfg(target_os = "linux")]
+fn original_dst(socket: &TcpStream) -> io::Result<SocketAddr> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+    };
+    // Safety: the kernel writes at most `len` bytes into `addr`, and the length it wrote back
+    // into `len`.
+    unsafe {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void,
+            &mut len,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        socket2::SockAddr::new(addr, len)
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP destination"))
+    }
+}
+
+impl From<TcpListener> for ServerListener {
+    fn from(listener: TcpListener) -> Self {
+        ServerListener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for ServerListener {
+    fn from(listener: UnixListener) -> Self {
+        ServerListener::Unix(listener)
+    }
+}
+
+/// A SOCKS5 server accepting clients on a TCP listener or a Unix socket. Once a client
+/// authenticated, its request is carried out by the `Service` of the server.
+///
+/// Nothing is left running in the background: `run` serves the clients on tasks owned by the
+/// future it returns, and `accept` hands them out one `Connection` at a time, for the caller to
+/// drive as it sees fit, e.g. on a `JoinSet` of its own.
+///
+/// With `new`, it is a `RequestHandler` behind the layers set up by the `ServerConfig`: the
+/// `AccessLayer` of `access`, then the `RateLimitLayer` of `rate_limiter`. More layers are added
+/// in front of them with `layer`, e.g. for logging, or the whole pipeline is given to
+/// `with_service`.
+pub struct Socks5Server {
+    listener: ServerListener,
+    service: Arc<dyn Service>,
+    config: ServerConfig,
+    registry: SessionRegistry,
+    admission: Admission,
+    // Cancelled by `shutdown`, to stop accepting and then to end the sessions left.
+    stopped: CancellationToken,
+    aborted: CancellationToken,
+    sessions: TaskTracker,
+}
+
+impl Socks5Server {
+    pub fn new<L, D>(listener: L, dialer: D, config: ServerConfig) -> Self
+    where
+        L: Into<ServerListener>,
+        D: Dialer + 'static,
+    {
+        let handler = RequestHandler::new(dialer, config.relay)
+            .limits(config.session_limits)
+            .filter_sources(config.filter_datagram_sources);
+        let mut service: Arc<dyn Service> = Arc::new(handler);
+        if let Some(limiter) = &config.rate_limiter {
+            service = Arc::new(RateLimitLayer::new(limiter.clone()).layer(service));
+        }
+        if let Some(access) = &config.access {
+            let resolver = config.access_resolver.clone().unwrap_or_default();
+            service = Arc::new(AccessLayer::with_resolver(access.clone(), resolver).layer(service));
+        }
+        Self::with_service(listener, service, config)
+    }
+
+    /// A server handing the requests to `service`. The `access` and `rate_limiter` of `config`
+    /// are left to it.
+    pub fn with_service<L, S>(listener: L, service: S, config: ServerConfig) -> Self
+    where
+        L: Into<ServerListener>,
+        S: Service + 'static,
+    {
+        Self {
+            listener: listener.into(),
+            service: Arc::new(service),
+            admission: Admission::new(config.max_connections, config.max_connections_per_ip),
+            config,
+            registry: SessionRegistry::new(),
+            stopped: CancellationToken::new(),
+            aborted: CancellationToken::new(),
+            sessions: TaskTracker::new(),
+        }
+    }
+
+    /// Listen for clients on `addr`, e.g. `127.0.0.1:1080`.
+    pub async fn bind<A, D>(addr: A, dialer: D, config: ServerConfig) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+        D: Dialer + 'static,
+    {
+        Ok(Self::new(TcpListener::bind(addr).await?, dialer, config))
+    }
+
+    /// Accept the connections redirected to `addr` by the firewall, as a transparent proxy.
+    #[cfg(target_os = "linux")]
+    pub async fn bind_transparent<A, D>(addr: A, dialer: D, config: ServerConfig) -> Result<Self>
+    where
+        A: ToSocketAddrs,
+        D: Dialer + 'static,
+    {
+        let listener = ServerListener::Transparent(TcpListener::bind(addr).await?);
+        Ok(Self::new(listener, dialer, config))
+    }
+
+    /// Listen for clients on the Unix socket at `path`, which must not exist yet.
+    #[cfg(unix)]
+    pub async fn bind_unix<P, D>(path: P, dialer: D, config: ServerConfig) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        D: Dialer + 'static,
+    {
+        Ok(Self::new(UnixListener::bind(path)?, dialer, config))
+    }
+
+    /// Wrap the service of the server with `layer`, which gets the requests first.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Arc<dyn Service>>,
+        L::Service: Service + 'static,
+    {
+        self.service = Arc::new(layer.layer(self.service));
+        self
+    }
+
+    /// The address the clients connect to. Fails for a Unix socket.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match &self.listener {
+            ServerListener::Tcp(listener) => Ok(listener.local_addr()?),
+            #[cfg(target_os = "linux")]
+            ServerListener::Transparent(listener) => Ok(listener.local_addr()?),
+            #[cfg(unix)]
+            ServerListener::Unix(_) => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, "listening on a Unix socket").into())
+            }
+        }
+    }
+
+    /// The sessions in progress and the counters of the server.
+    pub fn registry(&self) -> &SessionRegistry {
+        &self.registry
+    }
+
+    /// Serve clients, each on a task of a `JoinSet` owned by the returned future, until the
+    /// server is shut down or accepting fails, then wait for the sessions to end. Dropping the
+    /// future aborts them.
+    pub async fn run(&self) -> Result<()> {
+        let mut sessions = JoinSet::new();
+        let accepting = loop {
+            tokio::select! {
+                accepted = self.accept() => match accepted {
+                    // Failures only concern the client, whose connection is closed.
+                    Ok(Some(connection)) => drop(sessions.spawn(connection.serve())),
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                },
+                Some(_) = sessions.join_next() => {}
+            }
+        };
+        while sessions.join_next().await.is_some() {}
+        accepting
+    }
+
+    /// Accept the next client within the limits on connections, waiting for one to close if
+    /// need be, or `None` once the server is shut down. Clients refused for coming from an
+    /// address with too many connections already are skipped.
+    pub async fn accept(&self) -> Result<Option<Connection>> {
+        loop {
+            if self.admission.is_saturated() {
+                self.registry.accept_paused();
+            }
+            let slot = tokio::select! {
+                slot = self.admission.slot() => slot,
+                _ = self.stopped.cancelled() => return Ok(None),
+            };
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = self.stopped.cancelled() => return Ok(None),
+            };
+            let accepted = match accepted {
+                Ok(accepted) => accepted,
+                // The client went away before its connection was accepted.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            match self.admission.admit(slot, accepted.client.ip()) {
+                Some(admitted) => return Ok(Some(self.connection(accepted, Some(admitted)))),
+                None => self.registry.connection_rejected(),
+            }
+        }
+    }
+
+    fn connection(&self, accepted: Accepted, admitted: Option<Admitted>) -> Connection {
+        Connection {
+            accepted,
+            service: self.service.clone(),
+            config: self.config.clone(),
+            registry: self.registry.clone(),
+            aborted: self.aborted.clone(),
+            _admitted: admitted,
+            _session: self.sessions.token(),
+        }
+    }
+
+    /// Serve the client connected over `socket` until its session ends, or is aborted by
+    /// `shutdown`. It isn't counted against the limits on connections, which are those of
+    /// `run`.
+    pub async fn serve(&self, socket: TcpStream) -> Result<()> {
+        let accepted = Accepted {
+            client: socket.peer_addr()?,
+            local_addr: socket.local_addr()?,
+            socket: Box::new(socket),
+            original_dst: None,
+        };
+        self.connection(accepted, None).serve().await
+    }
+
+    /// Stop accepting clients, making `run` return, and wait for the sessions in progress to end
+    /// for up to `drain`. The sessions still going on then are aborted, closing the connections
+    /// of their clients and targets, and the returned future resolves once they are all gone.
+    pub async fn shutdown(&self, drain: Duration) {
+        self.stopped.cancel();
+        self.sessions.close();
+        if tokio::time::timeout(drain, self.sessions.wait())
+            .await
+            .is_err()
+        {
+            self.aborted.cancel();
+            self.sessions.wait().await;
+        }
+    }
+}
+
+/// A client accepted by a `Socks5Server`, whose session runs while `serve` is driven. It
+/// counts against the limits on connections, and as a session in progress for `shutdown`, from
+/// the moment it was accepted until it is served or dropped.
+pub struct Connection {
+    accepted: Accepted,
+    service: Arc<dyn Service>,
+    config: ServerConfig,
+    registry: SessionRegistry,
+    aborted: CancellationToken,
+    _admitted: Option<Admitted>,
+    _session: TaskTrackerToken,
+}
+
+impl Connection {
+    /// The address of the client.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.accepted.client
+    }
+
+    /// Serve the client until its session ends, or is aborted by `Socks5Server::shutdown`.
+    pub async fn serve(self) -> Result<()> {
+        let served = serve(self.accepted, &*self.service, &self.config, &self.registry);
+        self.aborted
+            .run_until_cancelled(served)
+            .await
+            .unwrap_or(Err(Socks5Error::Cancelled))
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("client", &self.accepted.client)
+            .field("local_addr", &self.accepted.local_addr)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Socks5Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5Server")
+            .field("listener", &self.listener)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+async fn serve(
+    accepted: Accepted,
+    service: &dyn Service,
+    config: &ServerConfig,
+    registry: &SessionRegistry,
+) -> Result<()> {
+    let Accepted {
+        mut socket,
+        client,
+        local_addr,
+        original_dst,
+    } = accepted;
+    let auth = config.auth.as_deref().unwrap_or(&NoAuth);
+    let resumption = config.resumption.as_ref();
+    let handshaken = match (original_dst, config.handshake_timeout) {
+        (Some(original_dst), _) if original_dst == local_addr => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "connection wasn't redirected").into())
+        }
+        (Some(original_dst), _) => Ok((
+            Identity::Anonymous,
+            Request::new(RequestType::Connect, TargetAddr::Ip(original_dst)),
+            None,
+        )),
+        (None, Some(timeout)) => {
+            let handshaken = handshake(&mut socket, client, auth, resumption, registry);
+            tokio::time::timeout(timeout, handshaken)
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into()))
+        }
+        (None, None) => handshake(&mut socket, client, auth, resumption, registry).await,
+    };
+    let (identity, request, resume) = handshaken.inspect_err(|_| registry.handshake_failed())?;
+    registry.requested(request.target_addr());
+    let request = match apply_domain_policy(&config.domain_policy, request).await {
+        Ok(request) => request,
+        Err(e) => {
+            write_reply(&mut socket, reply_code(&e), &unspecified(), registry).await?;
+            return Err(e);
+        }
+    };
+
+    let session = registry.open(
+        client,
+        identity.clone(),
+        request.request_type(),
+        request.target_addr().clone(),
+    );
+    service
+        .call(ServerRequest {
+            client,
+            local_addr,
+            identity,
+            request,
+            stream: socket,
+            datagram_policies: Vec::new(),
+            session,
+            transparent: original_dst.is_some(),
+            resume,
+        })
+        .await
+}
+
+// Authenticate the client, or negotiate its resumption token, and read its request.
+async fn handshake(
+    socket: &mut Box<dyn ClientStream>,
+    client: SocketAddr,
+    auth: &dyn ServerAuth,
+    resumption: Option<&Resumption>,
+    registry: &SessionRegistry,
+) -> Result<(Identity, Request, Option<Resume>)> {
+    // +----+----------+----------+
+    // |VER | NMETHODS | METHODS  |
+    // +----+----------+----------+
+    // | 1  |    1     | 1 to 255 |
+    // +----+----------+----------+
+    let mut header = [0; 2];
+    socket.read_exact(&mut header).await?;
+    Version::SOCKS5.check_request(header[0])?;
+    let mut methods = vec![0; header[1] as usize];
+    socket.read_exact(&mut methods).await?;
+
+    // Resuming authenticates no one, so it is only for the clients let in anonymously anyway.
+    let resumption = resumption.filter(|resumption| {
+        methods.contains(&resumption.code()) && auth.select_method(&[0x00]) == Some(0x00)
+    });
+    let (identity, resume) = match resumption {
+        Some(resumption) => {
+            socket.write_all(&[VERSION, resumption.code()]).await?;
+            let resume = resumption.negotiate(&mut **socket).await?;
+            (Identity::Anonymous, Some(resume))
+        }
+        None => {
+            let method = match auth.select_method(&methods) {
+                Some(method) => method,
+                None => {
+                    socket.write_all(&[VERSION, 0xFF]).await?;
+                    return Err(Socks5Error::NoAcceptableMethod);
+                }
+            };
+            socket.write_all(&[VERSION, method]).await?;
+            let identity = auth.authenticate(method, client, &mut **socket).await?;
+            (identity, None)
+        }
+    };
+
+    let mut buf = [0; MAX_REQUEST_LEN];
+    socket.read_exact(&mut buf[..5]).await?;
+    Version::SOCKS5.check_request(buf[0])?;
+    let len = Request::encoded_len(&buf[..5]).unwrap_or(5);
+    socket.read_exact(&mut buf[5..len]).await?;
+
+    match Request::decode(&buf[..len]) {
+        Ok((request, _)) => Ok((identity, request, resume)),
+        Err(e) => {
+            write_reply(socket, reply_code(&e), &unspecified(), registry).await?;
+            Err(e)
+        }
+    }
+}
+
+async fn apply_domain_policy(policy: &DomainPolicy, request: Request) -> Result<Request> {
+    let (domain, port) = match request.target_addr() {
+        TargetAddr::Domain(domain, port) => (domain, *port),
+        TargetAddr::Ip(_) => return Ok(request),
+    };
+    match policy {
+        DomainPolicy::Allow => Ok(request),
+        DomainPolicy::Reject => Err(Socks5Error::AddressTypeNotSupported),
+        DomainPolicy::Resolve(resolver) => {
+            let addr = resolver.resolve(domain, port).await?[0];
+            Ok(Request::new(request.request_type(), TargetAddr::Ip(addr)))
+        }
+    }
+}
+
+// The address the clients of a Unix socket are seen as, and the server as seen by them.
+#[cfg(unix)]
+fn unix_peer() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use pangolin_client::{
+        Either, NoAuthentication, ResumptionToken, SessionResumption, Socks5Config, Socks5Datagram,
+    };
+    use tokio::net::{TcpStream, UdpSocket};
+
+    use super::*;
+    use crate::{AccessRules, Destination, MemoryUserStore, Resolver, UserPassAuth};
+
+    async fn spawn(config: ServerConfig) -> (Arc<Socks5Server>, SocketAddr) {
+        let server = Arc::new(
+            Socks5Server::bind("127.0.0.1:0", DirectDialer::new(), config)
+                .await
+                .unwrap(),
+        );
+        let addr = server.local_addr().unwrap();
+        let running = server.clone();
+        tokio::spawn(async move { running.run().await });
+        (server, addr)
+    }
+
+    // Negotiate no authentication and send a CONNECT request to `target`, returning the reply
+    // code.
+    async fn connect(proxy: SocketAddr, target: &TargetAddr) -> (TcpStream, u8) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[VERSION, 1, 0x00]).await.unwrap();
+        let mut selected = [0; 2];
+        client.read_exact(&mut selected).await.unwrap();
+        assert_eq!(selected, [VERSION, 0x00]);
+
+        let mut request = vec![VERSION, 0x01, 0x00];
+        pangolin_proto::encode_addr(&mut request, target).unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        let len = match reply[3] {
+            0x01 => 4 + 2,
+            0x04 => 16 + 2,
+            atyp => panic!("unexpected address type {}", atyp),
+        };
+        client.read_exact(&mut vec![0; len]).await.unwrap();
+        (client, reply[1])
+    }
+
+    #[tokio::test]
+    async fn connects_and_relays() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = target.accept().await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+
+        let (server, proxy) = spawn(ServerConfig::default()).await;
+        let (mut client, code) = connect(proxy, &TargetAddr::Ip(target_addr)).await;
+        assert_eq!(code, 0x00);
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        let stats = server.registry().stats();
+        assert_eq!(stats.replies.get(&0x00), Some(&1));
+        assert_eq!(stats.address_types.get(&0x01), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn refuses_unacceptable_methods_and_versions() {
+        let (server, proxy) = spawn(ServerConfig::default()).await;
+
+        // Only username/password offered, without a store.
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[VERSION, 1, 0x02]).await.unwrap();
+        let mut selected = [0; 2];
+        client.read_exact(&mut selected).await.unwrap();
+        assert_eq!(selected, [VERSION, 0xFF]);
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[0x04, 1, 0x00]).await.unwrap();
+        // Closed, maybe with the rest of the greeting unread.
+        assert!(!matches!(client.read(&mut selected).await, Ok(n) if n > 0));
+        assert_eq!(server.registry().stats().handshake_failures, 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_domains_with_address_type_not_supported() {
+        let config = ServerConfig {
+            domain_policy: DomainPolicy::Reject,
+            ..ServerConfig::default()
+        };
+        let (server, proxy) = spawn(config).await;
+        let (_, code) = connect(proxy, &TargetAddr::Domain("localhost".into(), 80)).await;
+        assert_eq!(code, 0x08);
+
+        let stats = server.registry().stats();
+        assert_eq!(stats.address_types.get(&0x03), Some(&1));
+        assert_eq!(stats.replies.get(&0x08), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn resolves_domains_before_the_services() {
+        let config = ServerConfig {
+            domain_policy: DomainPolicy::Resolve(
+                TargetResolver::default().family(pangolin_client::AddressFamily::Ipv4Only),
+            ),
+            ..ServerConfig::default()
+        };
+        let (server, proxy) = spawn(config).await;
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+
+        let (_client, code) = connect(proxy, &TargetAddr::Domain("localhost".into(), port)).await;
+        assert_eq!(code, 0x00);
+        let sessions = server.registry().snapshot();
+        assert_eq!(
+            sessions[0].destination,
+            TargetAddr::Ip(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+        );
+    }
+
+    // Resolves every domain to an allowed address first, and to a denied one afterwards.
+    #[derive(Default)]
+    struct Rebinding(AtomicUsize);
+
+    #[async_trait]
+    impl Resolver for Rebinding {
+        async fn resolve(&self, _: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            let ip = match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => Ipv4Addr::new(127, 0, 0, 2),
+                _ => Ipv4Addr::LOCALHOST,
+            };
+            Ok(vec![SocketAddr::from((ip, port))])
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_addresses_the_access_policy_checked() {
+        let allowed = TcpListener::bind("127.0.0.2:0").await.unwrap();
+        let port = allowed.local_addr().unwrap().port();
+        let denied = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+
+        let resolver = TargetResolver::new(Arc::new(Rebinding::default()));
+        let mut rules = AccessRules::allow_by_default();
+        rules.deny(Destination::net("127.0.0.1/32".parse().unwrap()));
+        let config = ServerConfig {
+            access: Some(Arc::new(rules)),
+            access_resolver: Some(resolver.clone()),
+            ..ServerConfig::default()
+        };
+        let server = Socks5Server::bind(
+            "127.0.0.1:0",
+            DirectDialer::new().resolver(resolver),
+            config,
+        )
+        .await
+        .unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let target = TargetAddr::Domain("rebinding.example".into(), port);
+        let (_client, code) = connect(proxy, &target).await;
+        assert_eq!(code, 0x00);
+        let accepted = tokio::time::timeout(Duration::from_secs(1), allowed.accept()).await;
+        assert!(accepted.is_ok());
+        let reached = tokio::time::timeout(Duration::from_millis(100), denied.accept()).await;
+        assert!(reached.is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_idle_and_expired_sessions() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = TargetAddr::Ip(target.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = target.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 64];
+                    while let Ok(n) = socket.read(&mut buf).await {
+                        if n == 0 || socket.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let config = ServerConfig {
+            session_limits: SessionLimits::new()
+                .idle_timeout(Duration::from_millis(100))
+                .max_duration(Duration::from_millis(400)),
+            ..ServerConfig::default()
+        };
+        let (server, proxy) = spawn(config).await;
+
+        // Silent: closed after the idle timeout.
+        let (mut idle, _) = connect(proxy, &target_addr).await;
+        let started = tokio::time::Instant::now();
+        let mut buf = [0; 4];
+        assert_eq!(idle.read(&mut buf).await.unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_millis(300));
+
+        // Chatty: closed after the maximum duration only.
+        let (mut busy, _) = connect(proxy, &target_addr).await;
+        let started = tokio::time::Instant::now();
+        loop {
+            if busy.write_all(b"ping").await.is_err() {
+                break;
+            }
+            match busy.read(&mut buf).await {
+                Ok(n) if n > 0 => {}
+                _ => break,
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(started.elapsed() >= Duration::from_millis(350));
+
+        let stats = server.registry().stats();
+        assert_eq!((stats.idle_timeouts, stats.lifetime_timeouts), (1, 1));
+    }
+
+    type Resuming = Either<SessionResumption<TcpStream>, NoAuthentication<TcpStream>>;
+
+    // Associate through `proxy` with the resumption `token`.
+    async fn associate(proxy: SocketAddr, token: &ResumptionToken) -> Socks5Datagram<Resuming> {
+        let mut config = Socks5Config::default();
+        config.extensions.insert(token.clone());
+        Socks5Datagram::associate_with_config(proxy, config)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resumes_associations_from_a_new_control_connection() {
+        let config = ServerConfig {
+            resumption: Some(Resumption::new(0x88, Duration::from_secs(5))),
+            ..ServerConfig::default()
+        };
+        let (_server, proxy) = spawn(config).await;
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = TargetAddr::Ip(target.local_addr().unwrap());
+        let token = ResumptionToken::new(0x88);
+
+        let first = associate(proxy, &token).await;
+        assert!(!token.resumed());
+        first.send_to(b"ping", target_addr.clone()).await.unwrap();
+        let mut buf = [0; 64];
+        let (_, outbound) = target.recv_from(&mut buf).await.unwrap();
+        let relay = first.relay_addr().clone();
+        first.close().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The relay and the outbound are the same, and so are the NAT mappings along the way.
+        let second = associate(proxy, &token).await;
+        assert!(token.resumed());
+        assert_eq!(second.relay_addr(), &relay);
+        second.send_to(b"ping", target_addr.clone()).await.unwrap();
+        let (_, resumed_from) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(resumed_from, outbound);
+        target.send_to(b"pong", resumed_from).await.unwrap();
+        let (len, from) = second.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from), (&b"pong"[..], target_addr));
+    }
+
+    #[tokio::test]
+    async fn forgets_associations_after_the_grace_period() {
+        let config = ServerConfig {
+            resumption: Some(Resumption::new(0x88, Duration::from_millis(100))),
+            ..ServerConfig::default()
+        };
+        let (_server, proxy) = spawn(config).await;
+        let token = ResumptionToken::new(0x88);
+
+        associate(proxy, &token).await.close().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let _second = associate(proxy, &token).await;
+        assert!(!token.resumed());
+
+        // Nor are they resumed by clients that authenticate otherwise.
+        let config = ServerConfig {
+            auth: Some(Arc::new(UserPassAuth::new(MemoryUserStore::new()))),
+            resumption: Some(Resumption::new(0x88, Duration::from_secs(5))),
+            ..ServerConfig::default()
+        };
+        let (_server, proxy) = spawn(config).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[VERSION, 1, 0x88]).await.unwrap();
+        let mut selected = [0; 2];
+        client.read_exact(&mut selected).await.unwrap();
+        assert_eq!(selected, [VERSION, 0xFF]);
+    }
+
+    // A listener whose backlog is full, so that connecting to it hangs.
+    async fn unresponsive() -> (TcpListener, Vec<TcpStream>, SocketAddr) {
+        let listener = tokio::net::TcpSocket::new_v4().unwrap();
+        listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = listener.listen(1).unwrap();
+        let mut queued = Vec::new();
+        while let Ok(Ok(stream)) =
+            tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await
+        {
+            queued.push(stream);
+        }
+        (listener, queued, addr)
+    }
+
+    #[tokio::test]
+    async fn limits_connecting_by_host() {
+        let (_listener, _queued, addr) = unresponsive().await;
+        let dialer = DirectDialer::new()
+            .connect_timeout(Duration::from_millis(300))
+            .max_connecting(1);
+        let target = TargetAddr::Ip(addr);
+
+        let hanging = dialer.dial(&target);
+        let refused = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            dialer.dial(&target).await
+        };
+        let (hanging, refused) = tokio::join!(hanging, refused);
+        assert!(matches!(hanging, Err(Socks5Error::TtlExpired)));
+        assert!(matches!(
+            refused,
+            Err(Socks5Error::GeneralSocksServerFailure)
+        ));
+        assert!(dialer.connecting.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn hands_out_connections_until_shut_down() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = TargetAddr::Ip(target.local_addr().unwrap());
+        let server = Arc::new(
+            Socks5Server::bind("127.0.0.1:0", DirectDialer::new(), Default::default())
+                .await
+                .unwrap(),
+        );
+        let proxy = server.local_addr().unwrap();
+
+        let client = tokio::spawn(async move { connect(proxy, &target_addr).await });
+        let connection = server.accept().await.unwrap().unwrap();
+        let mut sessions = JoinSet::new();
+        sessions.spawn(connection.serve());
+        let (_client, code) = client.await.unwrap();
+        assert_eq!(code, 0x00);
+
+        // The session in progress is aborted once the drain is over.
+        server.shutdown(Duration::from_millis(50)).await;
+        assert!(matches!(server.accept().await, Ok(None)));
+        assert!(matches!(
+            sessions.join_next().await,
+            Some(Ok(Err(Socks5Error::Cancelled)))
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn closes_connections_not_redirected_to_a_transparent_listener() {
+        let server = Arc::new(
+            Socks5Server::bind_transparent("127.0.0.1:0", DirectDialer::new(), Default::default())
+                .await
+                .unwrap(),
+        );
+        let proxy = server.local_addr().unwrap();
+        let running = server.clone();
+        tokio::spawn(async move { running.run().await });
+
+        // Without a handshake there is nothing to refuse but the connection itself.
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[VERSION, 1, 0x00]).await.unwrap();
+        let read = client.read(&mut [0; 2]).await;
+        assert!(!matches!(read, Ok(n) if n > 0));
+        assert_eq!(server.registry().stats().handshake_failures, 1);
+    }
+}
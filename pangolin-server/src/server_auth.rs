@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pangolin_proto::{Result, Socks5Error, Version};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use crate::UserStore;
+
+const USERPASS_VERSION: Version = Version::new(0x01);
+
+// How long the failures of a client are remembered for its backoff.
+const FAILURES_TTL: Duration = Duration::from_secs(10 * 60);
+
+// How many clients the backoff keeps track of. Past it, the failures of the clients not tracked
+// yet are counted together, so that spreading guesses over many addresses doesn't escape it.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+// How often the failures older than `FAILURES_TTL` are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The connection of a client to the server, as seen by a `ServerAuth`.
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S> ClientStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Who a client authenticated as.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// A client of the no authentication method.
+    Anonymous,
+    User(String),
+}
+
+impl Identity {
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            Identity::Anonymous => None,
+            Identity::User(username) => Some(username),
+        }
+    }
+}
+
+/// How the server authenticates its clients: it picks a method among those offered by a client,
+/// then runs its sub-negotiation to learn who the client is.
+#[async_trait]
+pub trait ServerAuth: Send + Sync {
+    /// The method to authenticate the client with, among those it `offered`, or `None` to refuse
+    /// them all.
+    fn select_method(&self, offered: &[u8]) -> Option<u8>;
+
+    /// Run the sub-negotiation of `method`, as selected by `select_method`, over the `stream` of
+    /// `client`. Failing closes the connection.
+    async fn authenticate(
+        &self,
+        method: u8,
+        client: SocketAddr,
+        stream: &mut dyn ClientStream,
+    ) -> Result<Identity>;
+}
+
+impl fmt::Debug for dyn ServerAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ServerAuth")
+    }
+}
+
+/// Accepts clients offering the no authentication method, as anonymous.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoAuth;
+
+#[async_trait]
+impl ServerAuth for NoAuth {
+    fn select_method(&self, offered: &[u8]) -> Option<u8> {
+        offered.iter().copied().find(|&method| method == 0x00)
+    }
+
+    async fn authenticate(
+        &self,
+        _: u8,
+        _: SocketAddr,
+        _: &mut dyn ClientStream,
+    ) -> Result<Identity> {
+        Ok(Identity::Anonymous)
+    }
+}
+
+/// Authenticates clients with the username/password method, checking their credentials against
+/// `users`, e.g. a `MemoryUserStore` or a closure.
+///
+/// Failures are replied to no sooner than the failure delay after the credentials were read,
+/// however long checking them took. With a backoff, clients failing repeatedly are refused for a
+/// while without their credentials being checked at all.
+pub struct UserPassAuth<U> {
+    users: U,
+    failure_delay: Duration,
+    backoff: Option<(Duration, Duration)>,
+    failures: Mutex<Tracked>,
+}
+
+// The clients that failed to authenticate, by address or, for IPv6, by /64 network, which a
+// single host usually gets whole. `None` stands for the clients past `MAX_TRACKED_CLIENTS`.
+struct Tracked {
+    clients: HashMap<Option<IpAddr>, Failures>,
+    pruned: Instant,
+}
+
+// The consecutive failures of a client, and until when it is refused.
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    until: Instant,
+}
+
+impl Tracked {
+    // The key of the failures of `ip`.
+    fn key(&self, ip: IpAddr) -> Option<IpAddr> {
+        let key = match ip.to_canonical() {
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+            ip => ip,
+        };
+        Some(key).filter(|key| {
+            self.clients.len() < MAX_TRACKED_CLIENTS || self.clients.contains_key(&Some(*key))
+        })
+    }
+}
+
+impl<U> UserPassAuth<U>
+where
+    U: UserStore,
+{
+    pub fn new(users: U) -> Self {
+        Self {
+            users,
+            failure_delay: Duration::from_millis(100),
+            backoff: None,
+            failures: Mutex::new(Tracked {
+                clients: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Reply to failures `delay` after the credentials were read, 100ms by default.
+    pub fn failure_delay(mut self, delay: Duration) -> Self {
+        self.failure_delay = delay;
+        self
+    }
+
+    /// Refuse a client without checking its credentials for `base` after its first consecutive
+    /// failure, doubling with each other one up to `max`, however many connections it opens.
+    /// Clients are told apart by address, or by /64 network for IPv6. A success, or ten minutes
+    /// without failures, resets the backoff of a client.
+    pub fn backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.backoff = Some((base, max));
+        self
+    }
+
+    // Whether `ip` is backing off at `now`, and so is to be refused without being checked.
+    fn backing_off(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.backoff.is_none() {
+            return false;
+        }
+        let tracked = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = tracked.key(ip);
+        tracked
+            .clients
+            .get(&key)
+            .is_some_and(|failures| now < failures.until)
+    }
+
+    // Count a failure of `ip` at `now`, returning how long it is refused for.
+    fn failed(&self, ip: IpAddr, now: Instant) -> Duration {
+        let (base, max) = match self.backoff {
+            Some(backoff) => backoff,
+            None => return Duration::ZERO,
+        };
+        let mut tracked = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+        if now - tracked.pruned >= PRUNE_INTERVAL {
+            tracked
+                .clients
+                .retain(|_, failures| now - failures.last < FAILURES_TTL);
+            tracked.pruned = now;
+        }
+        let key = tracked.key(ip);
+        let failures = tracked.clients.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            until: now,
+        });
+        if now - failures.last >= FAILURES_TTL {
+            failures.count = 0;
+        }
+        let backoff = base
+            .checked_mul(1 << failures.count.min(31))
+            .map_or(max, |backoff| backoff.min(max));
+        failures.count += 1;
+        failures.last = now;
+        failures.until = now + backoff;
+        backoff
+    }
+
+    fn succeeded(&self, ip: IpAddr) {
+        if self.backoff.is_some() {
+            let mut tracked = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(key) = tracked.key(ip) {
+                tracked.clients.remove(&Some(key));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<U> ServerAuth for UserPassAuth<U>
+where
+    U: UserStore,
+{
+    fn select_method(&self, offered: &[u8]) -> Option<u8> {
+        offered.iter().copied().find(|&method| method == 0x02)
+    }
+
+    async fn authenticate(
+        &self,
+        _: u8,
+        client: SocketAddr,
+        stream: &mut dyn ClientStream,
+    ) -> Result<Identity> {
+        // +----+------+----------+------+----------+
+        // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+        // +----+------+----------+------+----------+
+        // | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+        // +----+------+----------+------+----------+
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await?;
+        USERPASS_VERSION.check_request(header[0])?;
+        let mut username = vec![0; header[1] as usize];
+        stream.read_exact(&mut username).await?;
+        let len = stream.read_u8().await?;
+        let mut password = vec![0; len as usize];
+        stream.read_exact(&mut password).await?;
+
+        // Credentials that aren't UTF-8 are refused rather than mangled, so that no two of them
+        // stand for the same user. Clients backing off are refused without checking theirs, so
+        // that opening more connections doesn't let them guess any faster.
+        let read = Instant::now();
+        let checked = !self.backing_off(client.ip(), read);
+        let credentials = (String::from_utf8(username), String::from_utf8(password));
+        let (username, verified) = match credentials {
+            (Ok(username), Ok(password)) if checked => {
+                let verified = self.users.verify(&username, &password).await?;
+                (username, verified)
+            }
+            _ => (String::new(), false),
+        };
+
+        // +----+--------+
+        // |VER | STATUS |
+        // +----+--------+
+        // | 1  |   1    |
+        // +----+--------+
+        if verified {
+            self.succeeded(client.ip());
+            stream.write_all(&[USERPASS_VERSION.as_u8(), 0x00]).await?;
+            return Ok(Identity::User(username));
+        }
+        if checked {
+            self.failed(client.ip(), read);
+        }
+        tokio::time::sleep_until(read + self.failure_delay).await;
+        stream.write_all(&[USERPASS_VERSION.as_u8(), 0x01]).await?;
+        Err(Socks5Error::AuthenticationFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::MemoryUserStore;
+
+    fn auth() -> UserPassAuth<MemoryUserStore> {
+        let users = MemoryUserStore::new();
+        users.insert("user", "secret");
+        UserPassAuth::new(users)
+    }
+
+    fn client() -> SocketAddr {
+        "192.0.2.1:1080".parse().unwrap()
+    }
+
+    // Authenticate with `username` and `password`, returning the identity, the status replied
+    // and how long it took.
+    async fn authenticate<U: UserStore>(
+        auth: &UserPassAuth<U>,
+        username: &[u8],
+        password: &[u8],
+    ) -> (Result<Identity>, u8, Duration) {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(1024);
+        let mut request = vec![0x01, username.len() as u8];
+        request.extend_from_slice(username);
+        request.push(password.len() as u8);
+        request.extend_from_slice(password);
+        client_stream.write_all(&request).await.unwrap();
+
+        let start = Instant::now();
+        let identity = auth.authenticate(0x02, client(), &mut server_stream).await;
+        let elapsed = start.elapsed();
+        let mut reply = [0; 2];
+        client_stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], 0x01);
+        (identity, reply[1], elapsed)
+    }
+
+    #[tokio::test]
+    async fn delays_failures_only() {
+        let auth = auth().failure_delay(Duration::from_millis(50));
+
+        let (identity, status, elapsed) = authenticate(&auth, b"user", b"secret").await;
+        assert_eq!(identity.unwrap(), Identity::User("user".into()));
+        assert_eq!(status, 0x00);
+        assert!(elapsed < Duration::from_millis(50));
+
+        for (username, password) in [(&b"user"[..], &b"wrong"[..]), (b"nobody", b"secret")] {
+            let (identity, status, elapsed) = authenticate(&auth, username, password).await;
+            assert!(matches!(identity, Err(Socks5Error::AuthenticationFailed)));
+            assert_eq!(status, 0x01);
+            assert!(elapsed >= Duration::from_millis(50));
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_credentials_that_are_not_utf8() {
+        let users = MemoryUserStore::new();
+        users.insert("us\u{fffd}r", "secret");
+        let auth = UserPassAuth::new(users).failure_delay(Duration::ZERO);
+        let (identity, status, _) = authenticate(&auth, b"us\xffr", b"secret").await;
+        assert!(matches!(identity, Err(Socks5Error::AuthenticationFailed)));
+        assert_eq!(status, 0x01);
+    }
+
+    #[tokio::test]
+    async fn refuses_other_subnegotiation_versions() {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(64);
+        client_stream.write_all(&[0x05, 0x00]).await.unwrap();
+        let identity = auth()
+            .authenticate(0x02, client(), &mut server_stream)
+            .await;
+        assert!(matches!(
+            identity,
+            Err(Socks5Error::InvalidRequestVersion {
+                expected: 0x01,
+                actual: 0x05
+            })
+        ));
+    }
+
+    #[test]
+    fn backs_off_clients_failing_repeatedly() {
+        let base = Duration::from_millis(100);
+        let auth = auth().backoff(base, base * 5);
+        let ip = client().ip();
+        let now = Instant::now();
+        let backoffs: Vec<_> = (0..5).map(|_| auth.failed(ip, now)).collect();
+        assert_eq!(backoffs, [base, base * 2, base * 4, base * 5, base * 5]);
+        assert!(auth.backing_off(ip, now + base * 4));
+        assert!(!auth.backing_off(ip, now + base * 5));
+
+        // Other clients aren't affected, and a success resets the backoff.
+        let other = "192.0.2.2".parse().unwrap();
+        assert!(!auth.backing_off(other, now));
+        assert_eq!(auth.failed(other, now), base);
+        auth.succeeded(ip);
+        assert!(!auth.backing_off(ip, now));
+        assert_eq!(auth.failed(ip, now), base);
+
+        // So do ten minutes without failures.
+        assert_eq!(auth.failed(other, now + FAILURES_TTL), base);
+
+        assert_eq!(self::auth().failed(ip, now), Duration::ZERO);
+        assert!(!self::auth().backing_off(ip, now));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refuses_clients_backing_off_without_checking_credentials() {
+        let checked = AtomicUsize::new(0);
+        let users = |username: &str, password: &str| {
+            checked.fetch_add(1, Ordering::SeqCst);
+            (username, password) == ("user", "secret")
+        };
+        let base = Duration::from_secs(1);
+        let auth = UserPassAuth::new(users)
+            .failure_delay(Duration::ZERO)
+            .backoff(base, base * 4);
+
+        let (_, status, _) = authenticate(&auth, b"user", b"wrong").await;
+        assert_eq!(status, 0x01);
+        assert_eq!(checked.load(Ordering::SeqCst), 1);
+
+        // Even the right password is refused while backing off, and not counted as a failure.
+        for _ in 0..3 {
+            let (identity, status, _) = authenticate(&auth, b"user", b"secret").await;
+            assert!(matches!(identity, Err(Socks5Error::AuthenticationFailed)));
+            assert_eq!(status, 0x01);
+        }
+        assert_eq!(checked.load(Ordering::SeqCst), 1);
+
+        tokio::time::advance(base).await;
+        let (identity, status, _) = authenticate(&auth, b"user", b"secret").await;
+        assert_eq!(identity.unwrap(), Identity::User("user".into()));
+        assert_eq!(status, 0x00);
+        assert_eq!(checked.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn bounds_the_clients_tracked() {
+        let base = Duration::from_millis(100);
+        let auth = auth().backoff(base, base * 5);
+        let now = Instant::now();
+
+        // IPv6 clients are tracked by /64 network.
+        let ip: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+        auth.failed(ip, now);
+        assert!(auth.backing_off("2001:db8:0:1::2".parse().unwrap(), now));
+        assert!(!auth.backing_off("2001:db8:0:2::1".parse().unwrap(), now));
+
+        // Past the cap, the clients not tracked yet share their failures.
+        for i in 1..MAX_TRACKED_CLIENTS {
+            auth.failed(Ipv4Addr::from(0x0a00_0000 + i as u32).into(), now);
+        }
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(auth.failed(first, now), base);
+        assert!(auth.backing_off(second, now));
+        assert_eq!(auth.failed(second, now), base * 2);
+        // While the clients tracked keep their own.
+        assert_eq!(auth.failed("10.0.0.1".parse().unwrap(), now), base * 2);
+
+        // Failures are forgotten once they expire.
+        auth.failed(first, now + FAILURES_TTL);
+        let tracked = auth.failures.lock().unwrap();
+        assert_eq!(tracked.clients.len(), 1);
+    }
+}
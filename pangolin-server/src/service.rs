@@ -0,0 +1,258 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use pangolin_proto::{encode_addr, Request, RequestType, Result, Socks5Error, TargetAddr, VERSION};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::access::AccessCheck;
+use crate::rate_limit::Throttled;
+use crate::registry::ActiveSession;
+use crate::resumption::Resume;
+use crate::{AccessPolicy, ClientStream, Identity, RateLimiter, SessionRegistry, TargetResolver};
+
+/// A request read from an authenticated client, handed down the services of a `Socks5Server`
+/// along with the connection of the client, which is to be replied to.
+pub struct ServerRequest {
+    pub client: SocketAddr,
+    /// The address of the server the client connected to.
+    pub local_addr: SocketAddr,
+    pub identity: Identity,
+    pub request: Request,
+    /// The connection of the client, which services may wrap, e.g. to sniff what it sends.
+    pub stream: Box<dyn ClientStream>,
+    // The policies the datagrams of an association are checked against, by the handler.
+    pub(crate) datagram_policies: Vec<AccessCheck>,
+    pub(crate) session: ActiveSession,
+    // Whether the client was redirected to the server without a handshake, and so isn't to be
+    // replied to.
+    pub(crate) transparent: bool,
+    // The resumption token of the client, if it negotiated one.
+    pub(crate) resume: Option<Resume>,
+}
+
+impl ServerRequest {
+    /// Reply to the client with `code`, e.g. `0x00` and the address the server is bound to on
+    /// success. Clients of a transparent listener get nothing, the reply being only counted.
+    pub async fn reply(&mut self, code: u8, bound: &TargetAddr) -> Result<()> {
+        if self.transparent {
+            self.session.registry().replied(code);
+            return Ok(());
+        }
+        write_reply(&mut self.stream, code, bound, self.session.registry()).await
+    }
+
+    /// Reply to the client with the code reporting `e`, and fail with it.
+    pub async fn reject(&mut self, e: Socks5Error) -> Result<()> {
+        self.reply(reply_code(&e), &unspecified()).await?;
+        Err(e)
+    }
+}
+
+impl fmt::Debug for ServerRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerRequest")
+            .field("client", &self.client)
+            .field("local_addr", &self.local_addr)
+            .field("identity", &self.identity)
+            .field("request", &self.request)
+            .finish()
+    }
+}
+
+/// Carries out the requests of the clients of a `Socks5Server`, after they authenticated.
+///
+/// Like a tower `Service`, it is wrapped by `Layer`s into a pipeline, each of them handling a
+/// request or passing it on to the service it wraps. The innermost is usually a
+/// `RequestHandler`, replying to the client and relaying its traffic.
+#[async_trait]
+pub trait Service: Send + Sync {
+    /// Carry out `request` until its session ends. The client must have been replied to when
+    /// this returns.
+    async fn call(&self, request: ServerRequest) -> Result<()>;
+}
+
+impl fmt::Debug for dyn Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Service")
+    }
+}
+
+#[async_trait]
+impl<F, Fut> Service for F
+where
+    F: Fn(ServerRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    async fn call(&self, request: ServerRequest) -> Result<()> {
+        self(request).await
+    }
+}
+
+#[async_trait]
+impl<S> Service for Arc<S>
+where
+    S: Service + ?Sized,
+{
+    async fn call(&self, request: ServerRequest) -> Result<()> {
+        (**self).call(request).await
+    }
+}
+
+/// Wraps a service into another, the same as tower's `Layer`.
+pub trait Layer<S> {
+    type Service;
+
+    fn layer(&self, inner: S) -> Self::Service;
+}
+
+/// Refuses the requests `policy` doesn't allow with `ConnectionNotAllowed`. UDP associations are
+/// checked datagram by datagram instead, by the `RequestHandler`.
+///
+/// Domain targets are resolved, and refused as well if `policy` denies any of their addresses.
+/// Those that don't resolve are refused with `HostUnreachable`. The others are replaced by the
+/// first of their addresses, like `DomainPolicy::Resolve` does, so that the dialer connects to an
+/// address that was checked instead of resolving the domain again.
+#[derive(Debug, Clone)]
+pub struct AccessLayer {
+    check: AccessCheck,
+}
+
+impl AccessLayer {
+    /// Resolve domain targets with the system resolver.
+    pub fn new(policy: Arc<dyn AccessPolicy>) -> Self {
+        Self::with_resolver(policy, TargetResolver::default())
+    }
+
+    pub fn with_resolver(policy: Arc<dyn AccessPolicy>, resolver: TargetResolver) -> Self {
+        Self {
+            check: AccessCheck::new(policy, resolver),
+        }
+    }
+}
+
+impl<S> Layer<S> for AccessLayer {
+    type Service = AccessControl<S>;
+
+    fn layer(&self, inner: S) -> AccessControl<S> {
+        AccessControl {
+            check: self.check.clone(),
+            inner,
+        }
+    }
+}
+
+/// The service of `AccessLayer`.
+#[derive(Debug, Clone)]
+pub struct AccessControl<S> {
+    check: AccessCheck,
+    inner: S,
+}
+
+#[async_trait]
+impl<S> Service for AccessControl<S>
+where
+    S: Service,
+{
+    async fn call(&self, mut request: ServerRequest) -> Result<()> {
+        if request.request.request_type() == RequestType::UdpAssociate {
+            request.datagram_policies.push(self.check.clone());
+        } else {
+            match self.check.check(&request.identity, &request.request).await {
+                Ok(Some(target)) => {
+                    request.request = Request::new(request.request.request_type(), target);
+                }
+                Ok(None) => return request.reject(Socks5Error::ConnectionNotAllowed).await,
+                Err(e) => return request.reject(e).await,
+            }
+        }
+        self.inner.call(request).await
+    }
+}
+
+/// Limits how fast the client of a CONNECT request may transfer, with the buckets `limiter`
+/// gives it.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: RateLimiter,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimited<S>;
+
+    fn layer(&self, inner: S) -> RateLimited<S> {
+        RateLimited {
+            limiter: self.limiter.clone(),
+            inner,
+        }
+    }
+}
+
+/// The service of `RateLimitLayer`.
+#[derive(Debug, Clone)]
+pub struct RateLimited<S> {
+    limiter: RateLimiter,
+    inner: S,
+}
+
+#[async_trait]
+impl<S> Service for RateLimited<S>
+where
+    S: Service,
+{
+    async fn call(&self, mut request: ServerRequest) -> Result<()> {
+        if request.request.request_type() == RequestType::Connect {
+            let buckets = self.limiter.buckets(&request.identity);
+            request.stream = Box::new(Throttled::new(request.stream, buckets));
+        }
+        self.inner.call(request).await
+    }
+}
+
+// +----+-----+-------+------+----------+----------+
+// |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+// +----+-----+-------+------+----------+----------+
+// | 1  |  1  | X'00' |  1   | Variable |    2     |
+// +----+-----+-------+------+----------+----------+
+pub(crate) async fn write_reply<S>(
+    socket: &mut S,
+    code: u8,
+    addr: &TargetAddr,
+    registry: &SessionRegistry,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    registry.replied(code);
+    let mut buf = vec![VERSION, code, 0x00];
+    encode_addr(&mut buf, addr)?;
+    socket.write_all(&buf).await?;
+    Ok(())
+}
+
+// The reply code reporting `e` to the client.
+pub(crate) fn reply_code(e: &Socks5Error) -> u8 {
+    match e.root() {
+        Socks5Error::Io(e) | Socks5Error::IoDuring { source: e, .. } => match e.kind() {
+            io::ErrorKind::ConnectionRefused => 0x05,
+            io::ErrorKind::NetworkUnreachable => 0x03,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => 0x04,
+            _ => 0x01,
+        },
+        Socks5Error::InvalidAddressType => 0x08,
+        e => e.reply_code().unwrap_or(0x01),
+    }
+}
+
+pub(crate) fn unspecified() -> TargetAddr {
+    TargetAddr::Ip(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+}
@@ -0,0 +1,386 @@
+use std::future::{poll_fn, Future};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::Poll;
+
+use async_trait::async_trait;
+use pangolin_client::{DynMethod, Method, ProxyUrl, Socks5Config, Socks5Datagram, Socks5Stream};
+use pangolin_proto::{Result, Socks5Error, TargetAddr};
+use tokio::sync::{MutexGuard, Notify, OnceCell};
+
+use crate::associate::{is_transient, MAX_DATAGRAM_SIZE};
+use crate::{ClientStream, DatagramOutbound, Destination, Dialer, DirectDialer, TargetResolver};
+
+/// Where an `UpstreamDialer` sends the requests to a destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upstream {
+    /// Reach the targets from the host of the server.
+    Direct,
+    /// Go through the SOCKS5 proxy of the URL.
+    Proxy(ProxyUrl),
+}
+
+/// A `Dialer` chaining the server to upstream SOCKS5 proxies, with the client of this crate:
+/// CONNECT requests are sent to the upstream of their target, and the datagrams of UDP
+/// associations to the upstream of theirs, over an association opened with it on the first
+/// datagram.
+///
+/// The upstream of a target is that of the first route whose destination matches it, or the
+/// default one.
+#[derive(Debug, Clone)]
+pub struct UpstreamDialer {
+    routes: Vec<(Destination, Upstream)>,
+    default: Upstream,
+    config: Socks5Config,
+    direct: DirectDialer,
+}
+
+impl UpstreamDialer {
+    /// Send everything to `default`, until routes are added.
+    pub fn new(default: Upstream) -> Self {
+        Self {
+            routes: Vec::new(),
+            default,
+            config: Socks5Config::default(),
+            direct: DirectDialer::new(),
+        }
+    }
+
+    /// Send what is bound to `destination` to `upstream`, unless an earlier route matches.
+    pub fn route(mut self, destination: Destination, upstream: Upstream) -> Self {
+        self.routes.push((destination, upstream));
+        self
+    }
+
+    /// Options of the connections to the upstream proxies. The resolution and credentials of
+    /// their URLs take precedence.
+    pub fn config(mut self, config: Socks5Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// How the targets of the `Direct` upstream are resolved.
+    pub fn resolver(mut self, resolver: TargetResolver) -> Self {
+        self.direct = DirectDialer::new().resolver(resolver);
+        self
+    }
+
+    pub fn upstream(&self, target: &TargetAddr) -> &Upstream {
+        self.routes
+            .iter()
+            .find(|(destination, _)| destination.matches(target))
+            .map_or(&self.default, |(_, upstream)| upstream)
+    }
+
+    // Open the outbound of `upstream`.
+    async fn open(&self, upstream: &Upstream) -> Result<Arc<dyn DatagramOutbound>> {
+        Ok(match upstream {
+            Upstream::Direct => Arc::from(self.direct.associate().await?),
+            Upstream::Proxy(url) => Arc::new(
+                Socks5Datagram::<DynMethod>::associate_with_url_and_config(
+                    url,
+                    self.config.clone(),
+                )
+                .await?,
+            ),
+        })
+    }
+}
+
+#[async_trait]
+impl Dialer for UpstreamDialer {
+    type Stream = Box<dyn ClientStream>;
+
+    async fn dial(&self, target: &TargetAddr) -> Result<(Self::Stream, TargetAddr)> {
+        match self.upstream(target) {
+            Upstream::Direct => {
+                let (stream, bound) = self.direct.dial(target).await?;
+                Ok((Box::new(stream), bound))
+            }
+            Upstream::Proxy(url) => {
+                let stream = Socks5Stream::<DynMethod>::connect_with_url_and_config(
+                    url,
+                    target.clone(),
+                    self.config.clone(),
+                )
+                .await?;
+                let bound = stream.proxy_bound_addr();
+                Ok((Box::new(stream), bound))
+            }
+        }
+    }
+
+    async fn associate(&self) -> Result<Box<dyn DatagramOutbound>> {
+        Ok(Box::new(RoutedOutbound {
+            dialer: self.clone(),
+            outbounds: Mutex::new(Vec::new()),
+            opened: Notify::new(),
+        }))
+    }
+}
+
+#[async_trait]
+impl<M> DatagramOutbound for Socks5Datagram<M>
+where
+    M: Method + Send + 'static,
+{
+    async fn send_to(&self, payload: &[u8], target: &TargetAddr) -> Result<usize> {
+        Socks5Datagram::send_to(self, payload, target.clone()).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, TargetAddr)> {
+        Socks5Datagram::recv_from(self, buf).await
+    }
+}
+
+// The outbound of an association of an `UpstreamDialer`, sending each datagram through the
+// outbound of its upstream. Receiving waits on all of them at once, within the association.
+struct RoutedOutbound {
+    dialer: UpstreamDialer,
+    // Only locked to look an upstream up: the outbounds are opened outside of it.
+    outbounds: Mutex<Vec<Arc<Opened>>>,
+    // Notified when an outbound is opened, to receive from it as well.
+    opened: Notify,
+}
+
+// The outbound of an upstream, opened by the first datagram sent to it, and where it receives.
+struct Opened {
+    upstream: Upstream,
+    outbound: OnceCell<Arc<dyn DatagramOutbound>>,
+    buf: tokio::sync::Mutex<Vec<u8>>,
+}
+
+impl Opened {
+    // Receive the next datagram into the buffer, returning it along with the length and the
+    // source of the payload.
+    async fn receive(
+        &self,
+        outbound: &dyn DatagramOutbound,
+    ) -> Result<(MutexGuard<'_, Vec<u8>>, usize, TargetAddr)> {
+        let mut buf = self.buf.lock().await;
+        loop {
+            match outbound.recv_from(&mut buf).await {
+                Ok((len, from)) => return Ok((buf, len, from)),
+                Err(Socks5Error::Io(e)) if is_transient(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl RoutedOutbound {
+    // The outbound of `upstream`, opened if it is the first datagram sent through it. Datagrams
+    // to other upstreams don't wait for the opening, those to the same one share it.
+    async fn outbound(&self, upstream: &Upstream) -> Result<Arc<dyn DatagramOutbound>> {
+        let opened = {
+            let mut outbounds = self
+                .outbounds
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            match outbounds.iter().find(|opened| opened.upstream == *upstream) {
+                Some(opened) => opened.clone(),
+                None => {
+                    let opened = Arc::new(Opened {
+                        upstream: upstream.clone(),
+                        outbound: OnceCell::new(),
+                        buf: tokio::sync::Mutex::new(vec![0; MAX_DATAGRAM_SIZE]),
+                    });
+                    outbounds.push(opened.clone());
+                    opened
+                }
+            }
+        };
+
+        if let Some(outbound) = opened.outbound.get() {
+            return Ok(outbound.clone());
+        }
+        // A failure leaves the cell empty, for the next datagram to try again.
+        let outbound = opened
+            .outbound
+            .get_or_try_init(|| self.dialer.open(upstream))
+            .await?;
+        self.opened.notify_waiters();
+        Ok(outbound.clone())
+    }
+
+    // Forget the outbound of `opened` once it stopped receiving, e.g. because its upstream ended
+    // the association, so that the next datagram to the upstream opens a new one.
+    fn evict(&self, opened: &Arc<Opened>) {
+        self.outbounds
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|other| !Arc::ptr_eq(other, opened));
+    }
+}
+
+#[async_trait]
+impl DatagramOutbound for RoutedOutbound {
+    async fn send_to(&self, payload: &[u8], target: &TargetAddr) -> Result<usize> {
+        let outbound = self.outbound(self.dialer.upstream(target)).await?;
+        outbound.send_to(payload, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, TargetAddr)> {
+        loop {
+            // Registered before looking at the outbounds, not to miss any opened meanwhile.
+            let opened = self.opened.notified();
+            tokio::pin!(opened);
+            let outbounds: Vec<_> = self
+                .outbounds
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .filter_map(|opened| Some((opened.clone(), opened.outbound.get()?.clone())))
+                .collect();
+            let mut receiving: Vec<_> = outbounds
+                .iter()
+                .map(|(opened, outbound)| (opened, Box::pin(opened.receive(&**outbound))))
+                .collect();
+
+            let received = poll_fn(|cx| {
+                let mut i = 0;
+                while i < receiving.len() {
+                    match receiving[i].1.as_mut().poll(cx) {
+                        Poll::Ready(Ok(received)) => return Poll::Ready(Some(received)),
+                        Poll::Ready(Err(_)) => {
+                            self.evict(receiving[i].0);
+                            drop(receiving.swap_remove(i));
+                        }
+                        Poll::Pending => i += 1,
+                    }
+                }
+                match opened.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(None),
+                    Poll::Pending => Poll::Pending,
+                }
+            })
+            .await;
+
+            if let Some((payload, len, from)) = received {
+                let len = len.min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                return Ok((len, from));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+    use crate::Socks5Server;
+
+    // A socket echoing every datagram it receives.
+    async fn echo() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 64];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let _ = socket.send_to(&buf[..len], from).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn receives_from_every_upstream() {
+        let server = Socks5Server::bind("127.0.0.1:0", DirectDialer::new(), Default::default())
+            .await
+            .unwrap();
+        let proxy = ProxyUrl::parse(&format!("socks5://{}", server.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let (direct, proxied) = (echo().await, echo().await);
+        let (direct, proxied) = (TargetAddr::Ip(direct), TargetAddr::Ip(proxied));
+        let port = match proxied {
+            TargetAddr::Ip(addr) => addr.port(),
+            _ => unreachable!(),
+        };
+        let dialer = UpstreamDialer::new(Upstream::Direct)
+            .route(Destination::any().port(port), Upstream::Proxy(proxy));
+        let outbound = dialer.associate().await.unwrap();
+
+        let mut buf = [0; 64];
+        for target in [&direct, &proxied] {
+            outbound.send_to(b"ping", target).await.unwrap();
+            let (len, from) = outbound.recv_from(&mut buf).await.unwrap();
+            assert_eq!((&buf[..len], &from), (&b"ping"[..], target));
+        }
+
+        // Both are still received from, whichever is waited on first.
+        let mut pong = [0; 64];
+        let mut receiving = outbound.recv_from(&mut pong);
+        tokio::select! {
+            _ = &mut receiving => panic!("received before sending"),
+            sent = outbound.send_to(b"pong", &proxied) => sent.unwrap(),
+        };
+        let (len, from) = receiving.await.unwrap();
+        assert_eq!((&pong[..len], &from), (&b"pong"[..], &proxied));
+    }
+
+    fn routed(dialer: UpstreamDialer) -> RoutedOutbound {
+        RoutedOutbound {
+            dialer,
+            outbounds: Mutex::new(Vec::new()),
+            opened: Notify::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_outbounds_without_holding_up_the_others() {
+        // An upstream accepting connections, never to answer them.
+        let stalled = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_url = ProxyUrl::parse(&format!("socks5://{}", stalled.local_addr().unwrap()));
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = stalled.accept().await {
+                held.push(socket);
+            }
+        });
+
+        let direct = TargetAddr::Ip(echo().await);
+        let routed = routed(UpstreamDialer::new(Upstream::Direct).route(
+            Destination::any().port(9),
+            Upstream::Proxy(stalled_url.unwrap()),
+        ));
+        let discard = TargetAddr::Ip("192.0.2.1:9".parse().unwrap());
+        tokio::select! {
+            biased;
+            _ = routed.send_to(b"lost", &discard) => panic!("the upstream answered"),
+            sent = routed.send_to(b"ping", &direct) => sent.unwrap(),
+        };
+        let mut buf = [0; 64];
+        let (len, from) = routed.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], &from), (&b"ping"[..], &direct));
+    }
+
+    #[tokio::test]
+    async fn evicts_outbounds_whose_upstream_ended_the_association() {
+        let server = Arc::new(
+            Socks5Server::bind("127.0.0.1:0", DirectDialer::new(), Default::default())
+                .await
+                .unwrap(),
+        );
+        let proxy = ProxyUrl::parse(&format!("socks5://{}", server.local_addr().unwrap())).unwrap();
+        let running = server.clone();
+        tokio::spawn(async move { running.run().await });
+
+        let proxied = TargetAddr::Ip(echo().await);
+        let routed = routed(UpstreamDialer::new(Upstream::Proxy(proxy)));
+        routed.send_to(b"ping", &proxied).await.unwrap();
+        let mut buf = [0; 64];
+        routed.recv_from(&mut buf).await.unwrap();
+        assert_eq!(routed.outbounds.lock().unwrap().len(), 1);
+
+        server.shutdown(Duration::ZERO).await;
+        let receiving =
+            tokio::time::timeout(Duration::from_millis(100), routed.recv_from(&mut buf));
+        assert!(receiving.await.is_err());
+        assert!(routed.outbounds.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+#[cfg(feature = "htpasswd")]
+use std::convert::TryFrom;
+#[cfg(feature = "htpasswd")]
+use std::io;
+#[cfg(feature = "htpasswd")]
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+
+use async_trait::async_trait;
+use pangolin_proto::Result;
+use sha2::{Digest, Sha256};
+
+/// Where `UserPassAuth` checks the credentials of clients, e.g. a database or a directory
+/// service.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    /// Whether `password` is the password of `username`.
+    async fn verify(&self, username: &str, password: &str) -> Result<bool>;
+}
+
+#[async_trait]
+impl<F> UserStore for F
+where
+    F: Fn(&str, &str) -> bool + Send + Sync,
+{
+    async fn verify(&self, username: &str, password: &str) -> Result<bool> {
+        Ok(self(username, password))
+    }
+}
+
+/// Users and their passwords kept in memory, which can be changed while the server runs.
+///
+/// Only the SHA-256 digests of the passwords are kept, and verifying takes the same time whether
+/// the user exists or not.
+#[derive(Debug, Default)]
+pub struct MemoryUserStore {
+    users: RwLock<HashMap<String, [u8; 32]>>,
+}
+
+impl MemoryUserStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `username`, or change its password.
+    pub fn insert<U: Into<String>, P: Into<String>>(&self, username: U, password: P) {
+        self.users
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(username.into(), sha256_digest(&password.into()));
+    }
+
+    pub fn remove(&self, username: &str) -> bool {
+        self.users
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(username)
+            .is_some()
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn verify(&self, username: &str, password: &str) -> Result<bool> {
+        let users = self.users.read().unwrap_or_else(PoisonError::into_inner);
+        // Unknown users are compared against a digest no password has, the same way.
+        let (expected, known) = match users.get(username) {
+            Some(expected) => (expected, true),
+            None => (&[0; 32], false),
+        };
+        Ok(constant_time_eq(&sha256_digest(password), expected) & known)
+    }
+}
+
+/// Users read from a file in the format of Apache's htpasswd: a `username:hash` line per user,
+/// where the hash is bcrypt (`$2y$...`, as written by `htpasswd -B`) or SHA-1 (`{SHA}...`).
+///
+/// The password of an unknown user is checked against a dummy hash of the kind the file uses, so
+/// that it takes as long to refuse as a wrong password.
+#[cfg(feature = "htpasswd")]
+#[derive(Debug)]
+pub struct FileUserStore {
+    path: PathBuf,
+    users: RwLock<Users>,
+}
+
+#[cfg(feature = "htpasswd")]
+#[derive(Debug)]
+struct Users {
+    hashes: HashMap<String, Hash>,
+    // Checked for the users not in the file.
+    dummy: Hash,
+}
+
+#[cfg(feature = "htpasswd")]
+#[derive(Debug, Clone)]
+enum Hash {
+    Bcrypt(String),
+    Sha1([u8; 20]),
+}
+
+#[cfg(feature = "htpasswd")]
+impl FileUserStore {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let users = parse_htpasswd(&std::fs::read_to_string(&path)?)?;
+        Ok(Self {
+            path,
+            users: RwLock::new(users),
+        })
+    }
+
+    /// Read the file again, e.g. after a user was added. The users are kept as they were if it
+    /// can't be read.
+    pub fn reload(&self) -> Result<()> {
+        let users = parse_htpasswd(&std::fs::read_to_string(&self.path)?)?;
+        *self.users.write().unwrap_or_else(PoisonError::into_inner) = users;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "htpasswd")]
+#[async_trait]
+impl UserStore for FileUserStore {
+    async fn verify(&self, username: &str, password: &str) -> Result<bool> {
+        let hash = {
+            let users = self.users.read().unwrap_or_else(PoisonError::into_inner);
+            users
+                .hashes
+                .get(username)
+                .cloned()
+                .ok_or_else(|| users.dummy.clone())
+        };
+
+        let (hash, known) = match hash {
+            Ok(hash) => (hash, true),
+            Err(dummy) => (dummy, false),
+        };
+        let verified = match hash {
+            Hash::Bcrypt(hash) => {
+                // bcrypt is slow on purpose, keep it off the runtime.
+                let password = password.to_owned();
+                tokio::task::spawn_blocking(move || {
+                    bcrypt::verify(password, &hash).unwrap_or(false)
+                })
+                .await
+                .map_err(io::Error::other)?
+            }
+            Hash::Sha1(hash) => constant_time_eq(&sha1_digest(password), &hash),
+        };
+        Ok(verified & known)
+    }
+}
+
+#[cfg(feature = "htpasswd")]
+fn parse_htpasswd(content: &str) -> Result<Users> {
+    use base64::Engine;
+
+    let mut hashes = HashMap::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} in htpasswd line {:?}", reason, line),
+            )
+        };
+
+        let (username, hash) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("missing hash"))?;
+        let hash = if hash.starts_with("$2") {
+            Hash::Bcrypt(hash.to_owned())
+        } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+            let digest = base64::engine::general_purpose::STANDARD
+                .decode(digest)
+                .ok()
+                .and_then(|digest| <[u8; 20]>::try_from(digest).ok())
+                .ok_or_else(|| invalid("invalid SHA-1 digest"))?;
+            Hash::Sha1(digest)
+        } else {
+            return Err(invalid("unsupported hash").into());
+        };
+        hashes.insert(username.to_owned(), hash);
+    }
+
+    let dummy = dummy_hash(hashes.values())?;
+    Ok(Users { hashes, dummy })
+}
+
+// A hash of the kind of `hashes`, bcrypt with the same cost if any is, that no password matches
+// in practice.
+#[cfg(feature = "htpasswd")]
+fn dummy_hash<'a, I: Iterator<Item = &'a Hash>>(mut hashes: I) -> Result<Hash> {
+    let cost = hashes.find_map(|hash| match hash {
+        // `$2y$10$...`
+        Hash::Bcrypt(hash) => hash.split('$').nth(2)?.parse::<u32>().ok(),
+        Hash::Sha1(_) => None,
+    });
+    match cost {
+        Some(cost) => {
+            let hash = bcrypt::hash(sha256_digest("pangolin dummy password"), cost)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Hash::Bcrypt(hash))
+        }
+        None => Ok(Hash::Sha1([0; 20])),
+    }
+}
+
+#[cfg(feature = "htpasswd")]
+fn sha1_digest(password: &str) -> [u8; 20] {
+    sha1::Sha1::digest(password.as_bytes()).into()
+}
+
+fn sha256_digest(password: &str) -> [u8; 32] {
+    Sha256::digest(password.as_bytes()).into()
+}
+
+// Compare the digests `a` and `b` in constant time.
+fn constant_time_eq<const N: usize>(a: &[u8; N], b: &[u8; N]) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_store_verifies_passwords() {
+        let store = MemoryUserStore::new();
+        store.insert("alice", "secret");
+        assert!(store.verify("alice", "secret").await.unwrap());
+        assert!(!store.verify("alice", "secret2").await.unwrap());
+        assert!(!store.verify("alice", "").await.unwrap());
+        assert!(!store.verify("bob", "secret").await.unwrap());
+
+        assert!(store.remove("alice"));
+        assert!(!store.verify("alice", "secret").await.unwrap());
+    }
+
+    #[test]
+    fn digests_compare_equal_only_when_equal() {
+        assert!(constant_time_eq(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!constant_time_eq(&[1, 2, 3], &[1, 2, 4]));
+        assert_ne!(sha256_digest("a"), sha256_digest("b"));
+    }
+
+    #[cfg(feature = "htpasswd")]
+    fn store(content: &str) -> FileUserStore {
+        FileUserStore {
+            path: PathBuf::new(),
+            users: RwLock::new(parse_htpasswd(content).unwrap()),
+        }
+    }
+
+    #[cfg(feature = "htpasswd")]
+    #[tokio::test]
+    async fn htpasswd_sha1() {
+        // `htpasswd -nbs alice password`
+        let store = store("# users\nalice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n\n");
+        assert!(store.verify("alice", "password").await.unwrap());
+        assert!(!store.verify("alice", "Password").await.unwrap());
+        assert!(!store.verify("bob", "password").await.unwrap());
+        assert!(matches!(store.users.read().unwrap().dummy, Hash::Sha1(_)));
+    }
+
+    #[cfg(feature = "htpasswd")]
+    #[tokio::test]
+    async fn htpasswd_bcrypt() {
+        let hash = bcrypt::hash("password", 4).unwrap();
+        let store = store(&format!("alice:{}", hash));
+        assert!(store.verify("alice", "password").await.unwrap());
+        assert!(!store.verify("alice", "wrong").await.unwrap());
+        assert!(!store.verify("bob", "password").await.unwrap());
+
+        // Unknown users are checked against a bcrypt hash of the same cost.
+        let users = store.users.read().unwrap();
+        match &users.dummy {
+            Hash::Bcrypt(dummy) => assert_eq!(dummy.split('$').nth(2), Some("04")),
+            dummy => panic!("unexpected dummy {:?}", dummy),
+        }
+    }
+
+    #[cfg(feature = "htpasswd")]
+    #[test]
+    fn invalid_htpasswd_lines() {
+        assert!(parse_htpasswd("alice").is_err());
+        assert!(parse_htpasswd("alice:plain").is_err());
+        assert!(parse_htpasswd("alice:{SHA}not base64").is_err());
+        // A valid base64 digest of the wrong length.
+        assert!(parse_htpasswd("alice:{SHA}AAAA").is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use std::error::Error;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::time::timeout;
+
+use pangolin::socks::{
+    Credentials, DynMethod, Socks5Config, Socks5Datagram, Socks5Stream, TargetAddr,
+};
+
+pub type BenchResult<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+struct Options {
+    proxy: String,
+    username: Option<String>,
+    password: Option<String>,
+    echo: SocketAddr,
+    handshakes: usize,
+    bytes: u64,
+    udp_packets: usize,
+    udp_timeout: Duration,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> BenchResult<Self> {
+        let mut options = Options {
+            proxy: String::new(),
+            username: None,
+            password: None,
+            echo: SocketAddr::from(([127, 0, 0, 1], 0)),
+            handshakes: 100,
+            bytes: 64 * 1024 * 1024,
+            udp_packets: 100,
+            udp_timeout: Duration::from_millis(1000),
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", flag))?;
+            match flag.as_str() {
+                "--proxy" => options.proxy = value.clone(),
+                "--username" => options.username = Some(value.clone()),
+                "--password" => options.password = Some(value.clone()),
+                "--echo" => options.echo = value.parse()?,
+                "--handshakes" => options.handshakes = value.parse()?,
+                "--bytes" => options.bytes = value.parse()?,
+                "--udp-packets" => options.udp_packets = value.parse()?,
+                "--udp-timeout-ms" => options.udp_timeout = Duration::from_millis(value.parse()?),
+                _ => return Err(format!("unknown option {}", flag).into()),
+            }
+        }
+
+        if options.proxy.is_empty() {
+            return Err("--proxy is required".into());
+        }
+        Ok(options)
+    }
+
+    fn config(&self) -> Socks5Config {
+        Socks5Config {
+            credentials: self.username.as_ref().map(|username| {
+                Credentials::new(username.as_str(), self.password.clone().unwrap_or_default())
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// Run `pangolin bench` and print its report to stdout.
+pub async fn run(args: &[String]) -> BenchResult<()> {
+    let options = Options::parse(args)?;
+
+    let tcp_echo = spawn_tcp_echo(options.echo).await?;
+    let udp_echo = spawn_udp_echo(options.echo).await?;
+
+    let mut report = String::new();
+    write!(report, "{{\"proxy\":{}", json_string(&options.proxy))?;
+    write!(
+        report,
+        ",\"handshake\":{}",
+        handshakes(&options, tcp_echo).await
+    )?;
+    write!(report, ",\"tcp\":{}", throughput(&options, tcp_echo).await)?;
+    write!(report, ",\"udp\":{}}}", udp(&options, udp_echo).await)?;
+    println!("{}", report);
+
+    Ok(())
+}
+
+async fn spawn_tcp_echo(addr: SocketAddr) -> BenchResult<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+pub async fn spawn_udp_echo(addr: SocketAddr) -> BenchResult<SocketAddr> {
+    let socket = UdpSocket::bind(addr).await?;
+    let addr = socket.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..len], peer).await;
+        }
+    });
+    Ok(addr)
+}
+
+// Time complete CONNECT handshakes, from the TCP connect to the proxy's reply.
+async fn handshakes(options: &Options, echo: SocketAddr) -> String {
+    let mut samples = Vec::with_capacity(options.handshakes);
+    let mut failures = 0;
+    let mut last_error = None;
+
+    for _ in 0..options.handshakes {
+        let start = Instant::now();
+        match Socks5Stream::<DynMethod>::connect_with_config(
+            options.proxy.as_str(),
+            TargetAddr::Ip(echo),
+            options.config(),
+        )
+        .await
+        {
+            Ok(_) => samples.push(start.elapsed()),
+            Err(e) => {
+                failures += 1;
+                last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    let mut json = format!("{{\"failures\":{}", failures);
+    if let Some(e) = last_error {
+        let _ = write!(json, ",\"last_error\":{}", json_string(&e));
+    }
+    let _ = write!(json, ",\"latency\":{}}}", latency(samples));
+    json
+}
+
+// Echo `options.bytes` through a single stream, writing and reading concurrently.
+async fn throughput(options: &Options, echo: SocketAddr) -> String {
+    let result: BenchResult<Duration> = async {
+        let stream = Socks5Stream::<DynMethod>::connect_with_config(
+            options.proxy.as_str(),
+            TargetAddr::Ip(echo),
+            options.config(),
+        )
+        .await?;
+        let (mut reader, mut writer) = io::split(stream);
+        let total = options.bytes;
+
+        let start = Instant::now();
+        let write = async {
+            let chunk = vec![0x5a; 64 * 1024];
+            let mut left = total;
+            while left > 0 {
+                let n = left.min(chunk.len() as u64) as usize;
+                writer.write_all(&chunk[..n]).await?;
+                left -= n as u64;
+            }
+            writer.flush().await
+        };
+        let read = async {
+            let mut buf = vec![0; 64 * 1024];
+            let mut left = total;
+            while left > 0 {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                left = left.saturating_sub(n as u64);
+            }
+            Ok(())
+        };
+        tokio::try_join!(write, read)?;
+        Ok(start.elapsed())
+    }
+    .await;
+
+    match result {
+        Ok(elapsed) => {
+            let seconds = elapsed.as_secs_f64();
+            format!(
+                "{{\"bytes\":{},\"seconds\":{:.6},\"mbit_per_sec\":{:.3}}}",
+                options.bytes,
+                seconds,
+                options.bytes as f64 * 8.0 / seconds / 1e6
+            )
+        }
+        Err(e) => error_json(&*e),
+    }
+}
+
+// Echo numbered datagrams one at a time through a single association.
+async fn udp(options: &Options, echo: SocketAddr) -> String {
+    let result: BenchResult<(usize, Vec<Duration>)> = async {
+        let datagram = Socks5Datagram::<DynMethod>::bind_with_config(
+            options.proxy.as_str(),
+            "0.0.0.0:0",
+            options.config(),
+        )
+        .await?;
+
+        let mut samples = Vec::with_capacity(options.udp_packets);
+        for seq in 0..options.udp_packets as u64 {
+            let payload = seq.to_be_bytes();
+            let start = Instant::now();
+            datagram.send_to(&payload, TargetAddr::Ip(echo)).await?;
+
+            // Late echoes of earlier datagrams are skipped; only this one counts.
+            let wait = async {
+                loop {
+                    let (data, _) = datagram.recv_from_bytes().await?;
+                    if data[..] == payload[..] {
+                        return Ok::<_, Box<dyn Error + Send + Sync>>(());
+                    }
+                }
+            };
+            if let Ok(result) = timeout(options.udp_timeout, wait).await {
+                result?;
+                samples.push(start.elapsed());
+            }
+        }
+        Ok((options.udp_packets, samples))
+    }
+    .await;
+
+    match result {
+        Ok((sent, samples)) => {
+            let loss = if sent == 0 {
+                0.0
+            } else {
+                (sent - samples.len()) as f64 / sent as f64
+            };
+            format!(
+                "{{\"sent\":{},\"received\":{},\"loss\":{:.4},\"rtt\":{}}}",
+                sent,
+                samples.len(),
+                loss,
+                latency(samples)
+            )
+        }
+        Err(e) => error_json(&*e),
+    }
+}
+
+// Summarize samples in microseconds, or `null` if there are none.
+pub fn latency(mut samples: Vec<Duration>) -> String {
+    if samples.is_empty() {
+        return "null".to_owned();
+    }
+    samples.sort();
+
+    let micros = |d: &Duration| d.as_micros();
+    let percentile = |p: usize| micros(&samples[(samples.len() - 1) * p / 100]);
+    let mean = samples.iter().map(micros).sum::<u128>() / samples.len() as u128;
+
+    format!(
+        "{{\"samples\":{},\"min_us\":{},\"mean_us\":{},\"p50_us\":{},\"p90_us\":{},\"p99_us\":{},\"max_us\":{}}}",
+        samples.len(),
+        micros(&samples[0]),
+        mean,
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        micros(&samples[samples.len() - 1]),
+    )
+}
+
+fn error_json(e: &(dyn Error + Send + Sync)) -> String {
+    format!("{{\"error\":{}}}", json_string(&e.to_string()))
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -0,0 +1,505 @@
+//! A DNS stub resolver forwarding the queries of local clients to an upstream resolver through a
+//! SOCKS5 UDP association, caching the answers.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::socks::{Method, Result, Socks5Datagram, TargetAddr};
+
+// Large enough for any message carried over UDP, EDNS included.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+const HEADER_LEN: usize = 12;
+// The type of the EDNS pseudo-record, whose TTL field holds flags instead.
+const TYPE_OPT: u16 = 41;
+const RCODE_NOERROR: u8 = 0;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Options of a `DnsStub`.
+#[derive(Debug, Clone)]
+pub struct DnsStubConfig {
+    /// The resolver the queries are forwarded to, through the proxy.
+    pub upstream: TargetAddr,
+    /// How long to wait for the upstream resolver before answering the client with `SERVFAIL`.
+    pub timeout: Duration,
+    /// The number of answers kept in the cache; 0 disables it.
+    pub cache_size: usize,
+    /// Bounds on how long positive answers are cached, whatever their TTL.
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    /// How long `NXDOMAIN` and empty answers are cached.
+    pub negative_ttl: Duration,
+}
+
+impl DnsStubConfig {
+    pub fn new(upstream: TargetAddr) -> Self {
+        Self {
+            upstream,
+            timeout: Duration::from_secs(2),
+            cache_size: 1024,
+            min_ttl: Duration::from_secs(0),
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+            negative_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for DnsStubConfig {
+    fn default() -> Self {
+        Self::new(TargetAddr::Ip(SocketAddr::from((
+            Ipv4Addr::new(1, 1, 1, 1),
+            53,
+        ))))
+    }
+}
+
+/// Counters of the traffic handled by a `DnsStub`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DnsStubStats {
+    /// Queries received from the clients.
+    pub queries: u64,
+    /// Queries answered from the cache.
+    pub cache_hits: u64,
+    /// Answers of the upstream resolver relayed to the clients.
+    pub answered: u64,
+    /// Queries the upstream resolver didn't answer in time.
+    pub timeouts: u64,
+    /// Malformed messages, and answers matching no pending query.
+    pub dropped: u64,
+}
+
+/// A DNS stub resolver: it receives queries on a local UDP socket, answers them from its cache or
+/// forwards them through a `Socks5Datagram` to the upstream resolver, and relays the answers back.
+///
+/// Queries are forwarded with IDs of their own, so that those of different clients can't collide
+/// on the shared association.
+pub struct DnsStub<M> {
+    socket: UdpSocket,
+    datagram: Socks5Datagram<M>,
+    config: DnsStubConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    cache: HashMap<Vec<u8>, Cached>,
+    // Forwarded queries by upstream ID.
+    pending: HashMap<u16, Pending>,
+    ids: RandomState,
+    counter: u64,
+    stats: DnsStubStats,
+}
+
+struct Cached {
+    answer: Vec<u8>,
+    stored_at: Instant,
+    expires_at: Instant,
+}
+
+struct Pending {
+    client: SocketAddr,
+    // The query as sent by the client, to answer it with `SERVFAIL` on timeout.
+    query: Vec<u8>,
+    question: Question,
+    deadline: Instant,
+}
+
+impl<M> DnsStub<M>
+where
+    M: Method,
+{
+    pub fn new(socket: UdpSocket, datagram: Socks5Datagram<M>, config: DnsStubConfig) -> Self {
+        Self {
+            socket,
+            datagram,
+            config,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Listen for queries on `addr`, e.g. `127.0.0.1:53`.
+    pub async fn bind<A: ToSocketAddrs>(
+        addr: A,
+        datagram: Socks5Datagram<M>,
+        config: DnsStubConfig,
+    ) -> Result<Self> {
+        Ok(Self::new(UdpSocket::bind(addr).await?, datagram, config))
+    }
+
+    /// The address the queries are received on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn stats(&self) -> DnsStubStats {
+        self.lock().stats
+    }
+
+    /// Serve queries until the association or the local socket fails.
+    pub async fn run(&self) -> Result<()> {
+        tokio::try_join!(self.serve_clients(), self.serve_upstream())?;
+        Ok(())
+    }
+
+    async fn serve_clients(&self) -> Result<()> {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        loop {
+            let (len, client) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // Reported for an earlier answer sent to a client that was already gone.
+                Err(e) if is_transient(&e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            match self.query(&buf[..len], client) {
+                Outgoing::Answer(answer) => self.answer_client(&answer, client).await?,
+                Outgoing::Forward(query) => {
+                    self.datagram
+                        .send_to(&query, self.config.upstream.clone())
+                        .await?;
+                }
+                Outgoing::Drop => {}
+            }
+        }
+    }
+
+    async fn serve_upstream(&self) -> Result<()> {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        let mut sweep = interval((self.config.timeout / 4).max(Duration::from_millis(10)));
+        sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                received = self.datagram.recv_from(&mut buf) => {
+                    let (len, _) = received?;
+                    if let Some((answer, client)) = self.answer(&mut buf[..len]) {
+                        self.answer_client(&answer, client).await?;
+                    }
+                }
+                _ = sweep.tick() => {
+                    for (answer, client) in self.expire(Instant::now()) {
+                        self.answer_client(&answer, client).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    // Answer `query` from the cache, or register it and rewrite it for the upstream resolver.
+    fn query(&self, query: &[u8], client: SocketAddr) -> Outgoing {
+        let mut state = self.lock();
+        state.stats.queries += 1;
+
+        let question = match Question::parse(query) {
+            Some(question) if !is_response(query) => question,
+            _ => {
+                state.stats.dropped += 1;
+                return Outgoing::Drop;
+            }
+        };
+
+        let now = Instant::now();
+        if let Some(mut answer) = state.cached(&question.key, now) {
+            state.stats.cache_hits += 1;
+            // The names only match case-insensitively: echo the question of the client.
+            answer[..2].copy_from_slice(&query[..2]);
+            answer[HEADER_LEN..question.end].copy_from_slice(&query[HEADER_LEN..question.end]);
+            return Outgoing::Answer(answer);
+        }
+
+        let id = match state.next_id() {
+            Some(id) => id,
+            None => {
+                state.stats.dropped += 1;
+                return Outgoing::Drop;
+            }
+        };
+        state.pending.insert(
+            id,
+            Pending {
+                client,
+                query: query.to_vec(),
+                question,
+                deadline: now + self.config.timeout,
+            },
+        );
+
+        let mut forwarded = query.to_vec();
+        forwarded[..2].copy_from_slice(&id.to_be_bytes());
+        Outgoing::Forward(forwarded)
+    }
+
+    // Match an answer of the upstream resolver with its query, caching it and restoring the ID
+    // of the client.
+    fn answer(&self, answer: &mut [u8]) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut state = self.lock();
+
+        let matched = Question::parse(answer).and_then(|question| {
+            let id = u16::from_be_bytes([answer[0], answer[1]]);
+            let pending = state.pending.get(&id)?;
+            (is_response(answer) && pending.question.key == question.key).then_some(id)
+        });
+        let pending = match matched.and_then(|id| state.pending.remove(&id)) {
+            Some(pending) => pending,
+            None => {
+                state.stats.dropped += 1;
+                return None;
+            }
+        };
+        state.stats.answered += 1;
+
+        answer[..2].copy_from_slice(&pending.query[..2]);
+        if let Some(ttl) = self.cache_ttl(answer) {
+            let now = Instant::now();
+            state.store(
+                pending.question.key,
+                answer.to_vec(),
+                now,
+                now + ttl,
+                self.config.cache_size,
+            );
+        }
+        Some((answer.to_vec(), pending.client))
+    }
+
+    // Drop the queries the upstream resolver didn't answer in time, answering them with
+    // `SERVFAIL`.
+    fn expire(&self, now: Instant) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut state = self.lock();
+        let expired: Vec<u16> = state
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut answers = Vec::with_capacity(expired.len());
+        for id in expired {
+            if let Some(pending) = state.pending.remove(&id) {
+                state.stats.timeouts += 1;
+                answers.push((
+                    servfail(&pending.query, pending.question.end),
+                    pending.client,
+                ));
+            }
+        }
+        answers
+    }
+
+    // How long `answer` may be cached, if at all.
+    fn cache_ttl(&self, answer: &mut [u8]) -> Option<Duration> {
+        if self.config.cache_size == 0 || is_truncated(answer) {
+            return None;
+        }
+        let ancount = u16::from_be_bytes([answer[6], answer[7]]);
+        let min_ttl = rewrite_ttls(answer, |ttl| ttl)?;
+
+        match rcode(answer) {
+            RCODE_NOERROR if ancount > 0 => {
+                let ttl = Duration::from_secs(min_ttl.unwrap_or(0).into());
+                Some(ttl.clamp(self.config.min_ttl, self.config.max_ttl))
+            }
+            RCODE_NOERROR | RCODE_NXDOMAIN => Some(self.config.negative_ttl),
+            _ => None,
+        }
+    }
+
+    async fn answer_client(&self, answer: &[u8], client: SocketAddr) -> Result<()> {
+        match self.socket.send_to(answer, client).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_transient(&e) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+enum Outgoing {
+    Answer(Vec<u8>),
+    Forward(Vec<u8>),
+    Drop,
+}
+
+impl State {
+    // A copy of the cached answer to `key`, its TTLs decreased by the time spent in the cache.
+    fn cached(&mut self, key: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let cached = self.cache.get(key)?;
+        if cached.expires_at <= now {
+            self.cache.remove(key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(cached.stored_at).as_secs();
+        let elapsed = u32::try_from(elapsed).unwrap_or(u32::MAX);
+        let mut answer = cached.answer.clone();
+        rewrite_ttls(&mut answer, |ttl| ttl.saturating_sub(elapsed))?;
+        Some(answer)
+    }
+
+    fn store(
+        &mut self,
+        key: Vec<u8>,
+        answer: Vec<u8>,
+        now: Instant,
+        expires_at: Instant,
+        capacity: usize,
+    ) {
+        if self.cache.len() >= capacity && !self.cache.contains_key(&key) {
+            self.cache.retain(|_, cached| cached.expires_at > now);
+        }
+        if self.cache.len() >= capacity && !self.cache.contains_key(&key) {
+            let soonest = self
+                .cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                self.cache.remove(&soonest);
+            }
+        }
+        self.cache.insert(
+            key,
+            Cached {
+                answer,
+                stored_at: now,
+                expires_at,
+            },
+        );
+    }
+
+    // An ID unused by the pending queries, hard to guess for hosts spoofing answers.
+    fn next_id(&mut self) -> Option<u16> {
+        for _ in 0..16 {
+            let id = self.ids.hash_one(self.counter) as u16;
+            self.counter += 1;
+            if !self.pending.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+// The single question of a message.
+struct Question {
+    // The name, lowercased, followed by the type and the class.
+    key: Vec<u8>,
+    // Where the question ends in the message.
+    end: usize,
+}
+
+impl Question {
+    fn parse(message: &[u8]) -> Option<Self> {
+        if message.len() < HEADER_LEN || opcode(message) != 0 {
+            return None;
+        }
+        if u16::from_be_bytes([message[4], message[5]]) != 1 {
+            return None;
+        }
+
+        // Names in questions are never compressed.
+        let mut key = Vec::new();
+        let mut pos = HEADER_LEN;
+        loop {
+            let len = *message.get(pos)? as usize;
+            if len & 0xc0 != 0 {
+                return None;
+            }
+            let label = message.get(pos..pos + 1 + len)?;
+            key.extend(label.iter().map(u8::to_ascii_lowercase));
+            pos += 1 + len;
+            if len == 0 {
+                break;
+            }
+        }
+        key.extend_from_slice(message.get(pos..pos + 4)?);
+
+        Some(Self { key, end: pos + 4 })
+    }
+}
+
+fn is_response(message: &[u8]) -> bool {
+    message[2] & 0x80 != 0
+}
+
+fn is_truncated(message: &[u8]) -> bool {
+    message[2] & 0x02 != 0
+}
+
+fn opcode(message: &[u8]) -> u8 {
+    (message[2] >> 3) & 0x0f
+}
+
+fn rcode(message: &[u8]) -> u8 {
+    message[3] & 0x0f
+}
+
+// Apply `f` to the TTL of every resource record, returning the smallest one, or `None` if the
+// message is malformed.
+fn rewrite_ttls<F: Fn(u32) -> u32>(message: &mut [u8], f: F) -> Option<Option<u32>> {
+    let count = |i: usize| u16::from_be_bytes([message[i], message[i + 1]]) as usize;
+    let records = count(6) + count(8) + count(10);
+
+    let mut pos = skip_name(message, HEADER_LEN)? + 4;
+    let mut min = None;
+    for _ in 0..records {
+        pos = skip_name(message, pos)?;
+        let fixed = message.get(pos..pos + 10)?;
+        let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+
+        if kind != TYPE_OPT {
+            let field = &mut message[pos + 4..pos + 8];
+            let ttl = f(u32::from_be_bytes([field[0], field[1], field[2], field[3]]));
+            field.copy_from_slice(&ttl.to_be_bytes());
+            min = Some(min.map_or(ttl, |min: u32| min.min(ttl)));
+        }
+        pos += 10 + rdlength;
+        if pos > message.len() {
+            return None;
+        }
+    }
+    Some(min)
+}
+
+// The position after the name at `pos`, which may end with a compression pointer.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)? as usize;
+        match len & 0xc0 {
+            0 if len == 0 => return Some(pos + 1),
+            0 => pos += 1 + len,
+            0xc0 => return Some(pos + 2).filter(|end| *end <= message.len()),
+            _ => return None,
+        }
+    }
+}
+
+// A `SERVFAIL` answer to `query`, whose question ends at `end`.
+fn servfail(query: &[u8], end: usize) -> Vec<u8> {
+    let mut answer = query[..end].to_vec();
+    // Keep the opcode and RD, and set QR and RA.
+    answer[2] = (answer[2] & 0x79) | 0x80;
+    answer[3] = 0x80 | RCODE_SERVFAIL;
+    answer[6..HEADER_LEN].fill(0);
+    answer
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
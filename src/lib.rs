@@ -0,0 +1,4 @@
+#[cfg(feature = "dns-stub")]
+pub mod dns_stub;
+pub mod socks;
+pub mod testing;
@@ -1,23 +1,72 @@
-use tokio::net::TcpStream;
+mod bench;
+mod soak;
+#[cfg(feature = "dns-stub")]
+mod stub;
 
-use crate::socks::{NoAuthentication, Result, Socks5Datagram, TargetAddr};
+use std::env;
+use std::process;
 
-pub mod socks;
+const USAGE: &str = "\
+usage: pangolin bench --proxy <addr> [options]
+       pangolin soak --proxy <addr> [options]
+       pangolin dns-stub --proxy <addr> [options]
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut socket =
-        Socks5Datagram::<NoAuthentication<TcpStream>>::bind("172.18.0.2:1080", "0.0.0.0:7878")
-            .await?;
+bench: measure a SOCKS5 proxy against local echo targets and print the results as JSON.
+
+options:
+    --proxy <addr>          the proxy to measure, e.g. 127.0.0.1:1080
+    --username <name>       authenticate with username/password
+    --password <password>
+    --echo <addr>           where the echo targets listen [default: 127.0.0.1:0]
+    --handshakes <n>        handshakes to time [default: 100]
+    --bytes <n>             bytes to echo through one stream [default: 67108864]
+    --udp-packets <n>       datagrams to echo through one association [default: 100]
+    --udp-timeout-ms <ms>   time to wait for each echoed datagram [default: 1000]
+
+soak: keep UDP associations alive for hours, probing them against a local echo target, and
+print a JSON report of their deaths, round trip times and the memory growth of the process.
+Progress is printed to stderr.
 
-    // An echo UDP server from my VPS.
-    let remote = "65.52.160.71:7878".parse().unwrap();
+options:
+    --proxy, --username, --password, --echo
+                            as for bench
+    --associations <n>      associations kept alive at once [default: 10]
+    --duration-secs <s>     how long to run [default: 3600]
+    --interval-ms <ms>      time between the probes of an association [default: 10000]
+    --probe-timeout-ms <ms> time to wait for each echoed probe [default: 2000]
+    --dead-after <n>        unanswered probes in a row after which an association that is
+                            still open is considered silently dead and replaced [default: 3]
+    --progress-secs <s>     time between progress lines [default: 60]
 
-    socket.send_to(b"hello", TargetAddr::Ip(remote)).await?;
+dns-stub: answer DNS queries on a local UDP socket, forwarding them to an upstream resolver
+through the UDP association of the proxy and caching the answers. Only available when built
+with the dns-stub feature.
+
+options:
+    --proxy, --username, --password
+                            as for bench
+    --listen <addr>         where the queries are received [default: 127.0.0.1:5353]
+    --upstream <addr>       the resolver to forward the queries to [default: 1.1.1.1:53]
+    --timeout-ms <ms>       time to wait for the upstream resolver [default: 2000]
+    --cache-size <n>        answers kept in the cache, 0 to disable it [default: 1024]";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    let mut buf = [0; 10];
-    let target_addr = socket.recv_from(&mut buf).await?;
-    assert_eq!(target_addr, TargetAddr::Ip(remote));
+    let result = match args.first().map(String::as_str) {
+        Some("bench") => bench::run(&args[1..]).await,
+        Some("soak") => soak::run(&args[1..]).await,
+        #[cfg(feature = "dns-stub")]
+        Some("dns-stub") => stub::run(&args[1..]).await,
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
 
-    Ok(())
+    if let Err(e) = result {
+        eprintln!("pangolin: {}", e);
+        process::exit(1);
+    }
 }
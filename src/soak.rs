@@ -0,0 +1,370 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use tokio::time::{sleep, timeout};
+
+use pangolin::socks::{
+    Credentials, DynMethod, Socks5Config, Socks5Datagram, Socks5Error, TargetAddr,
+};
+
+use crate::bench::{json_string, latency, spawn_udp_echo, BenchResult};
+
+struct Options {
+    proxy: String,
+    username: Option<String>,
+    password: Option<String>,
+    echo: SocketAddr,
+    associations: usize,
+    duration: Duration,
+    interval: Duration,
+    probe_timeout: Duration,
+    dead_after: u32,
+    progress: Duration,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> BenchResult<Self> {
+        let mut options = Options {
+            proxy: String::new(),
+            username: None,
+            password: None,
+            echo: SocketAddr::from(([127, 0, 0, 1], 0)),
+            associations: 10,
+            duration: Duration::from_secs(3600),
+            interval: Duration::from_secs(10),
+            probe_timeout: Duration::from_millis(2000),
+            dead_after: 3,
+            progress: Duration::from_secs(60),
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", flag))?;
+            match flag.as_str() {
+                "--proxy" => options.proxy = value.clone(),
+                "--username" => options.username = Some(value.clone()),
+                "--password" => options.password = Some(value.clone()),
+                "--echo" => options.echo = value.parse()?,
+                "--associations" => options.associations = value.parse()?,
+                "--duration-secs" => options.duration = Duration::from_secs(value.parse()?),
+                "--interval-ms" => options.interval = Duration::from_millis(value.parse()?),
+                "--probe-timeout-ms" => {
+                    options.probe_timeout = Duration::from_millis(value.parse()?)
+                }
+                "--dead-after" => options.dead_after = value.parse()?,
+                "--progress-secs" => options.progress = Duration::from_secs(value.parse()?),
+                _ => return Err(format!("unknown option {}", flag).into()),
+            }
+        }
+
+        if options.proxy.is_empty() {
+            return Err("--proxy is required".into());
+        }
+        if options.dead_after == 0 {
+            return Err("--dead-after must be at least 1".into());
+        }
+        Ok(options)
+    }
+
+    fn config(&self) -> Socks5Config {
+        Socks5Config {
+            credentials: self.username.as_ref().map(|username| {
+                Credentials::new(username.as_str(), self.password.clone().unwrap_or_default())
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+// What happened to the associations of one slot, each replacing the previous one once it died.
+#[derive(Default)]
+struct Stats {
+    binds: u64,
+    bind_failures: u64,
+    probes: u64,
+    answered: u64,
+    // Associations that stopped answering while their control connection stayed open.
+    silent_deaths: u64,
+    // Associations whose control connection was closed by the proxy.
+    terminations: u64,
+    // Associations dropped after any other error.
+    errors: u64,
+    longest_silence: u32,
+    last_error: Option<String>,
+    rtt: Vec<Duration>,
+    lifetimes: Vec<Duration>,
+}
+
+// How an association stopped being probed.
+enum End {
+    Deadline,
+    Silent,
+    Failed(Socks5Error),
+}
+
+/// Run `pangolin soak`, printing progress to stderr and the final report to stdout.
+pub async fn run(args: &[String]) -> BenchResult<()> {
+    let options = Arc::new(Options::parse(args)?);
+    let echo = spawn_udp_echo(options.echo).await?;
+    let stats: Arc<Vec<Mutex<Stats>>> = Arc::new(
+        (0..options.associations)
+            .map(|_| Default::default())
+            .collect(),
+    );
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let rss_start = rss_kb();
+    let mut rss_max = rss_start;
+
+    let slots: Vec<_> = (0..options.associations)
+        .map(|slot| {
+            let (options, stats) = (options.clone(), stats.clone());
+            tokio::spawn(async move { soak(slot, &options, echo, deadline, &stats[slot]).await })
+        })
+        .collect();
+
+    while Instant::now() < deadline {
+        sleep(options.progress.min(deadline - Instant::now())).await;
+        let rss = rss_kb();
+        rss_max = rss_max.max(rss);
+        eprintln!("{}", progress(start.elapsed(), &stats, rss));
+    }
+    for slot in slots {
+        slot.await?;
+    }
+
+    let rss_end = rss_kb();
+    rss_max = rss_max.max(rss_end);
+    println!(
+        "{}",
+        report(
+            &options,
+            start.elapsed(),
+            &stats,
+            rss_start,
+            rss_end,
+            rss_max
+        )
+    );
+    Ok(())
+}
+
+// Keep an association of `slot` alive until `deadline`, probing it every `options.interval`
+// and replacing it whenever it dies.
+async fn soak(
+    slot: usize,
+    options: &Options,
+    echo: SocketAddr,
+    deadline: Instant,
+    stats: &Mutex<Stats>,
+) {
+    let lock = || stats.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut seq = 0u64;
+
+    while Instant::now() < deadline {
+        let datagram = match Socks5Datagram::<DynMethod>::bind_with_config(
+            options.proxy.as_str(),
+            "0.0.0.0:0",
+            options.config(),
+        )
+        .await
+        {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                {
+                    let mut stats = lock();
+                    stats.bind_failures += 1;
+                    stats.last_error = Some(e.to_string());
+                }
+                sleep(options.interval).await;
+                continue;
+            }
+        };
+        lock().binds += 1;
+        let born = Instant::now();
+        let mut silence = 0;
+
+        let end = loop {
+            if Instant::now() >= deadline {
+                break End::Deadline;
+            }
+            sleep(options.interval.min(deadline - Instant::now())).await;
+
+            seq += 1;
+            let mut payload = [0; 16];
+            payload[..8].copy_from_slice(&(slot as u64).to_be_bytes());
+            payload[8..].copy_from_slice(&seq.to_be_bytes());
+
+            let sent = Instant::now();
+            lock().probes += 1;
+            let answer = async {
+                datagram.send_to(&payload, TargetAddr::Ip(echo)).await?;
+                // Late echoes of earlier probes are skipped; only this one counts.
+                loop {
+                    let (data, _) = datagram.recv_from_bytes().await?;
+                    if data[..] == payload[..] {
+                        return Ok::<_, Socks5Error>(());
+                    }
+                }
+            };
+
+            match timeout(options.probe_timeout, answer).await {
+                Ok(Ok(())) => {
+                    let mut stats = lock();
+                    stats.answered += 1;
+                    stats.rtt.push(sent.elapsed());
+                    silence = 0;
+                }
+                Ok(Err(e)) => break End::Failed(e),
+                Err(_) => {
+                    silence += 1;
+                    let mut stats = lock();
+                    stats.longest_silence = stats.longest_silence.max(silence);
+                    if silence >= options.dead_after {
+                        break End::Silent;
+                    }
+                }
+            }
+        };
+
+        let mut stats = lock();
+        match end {
+            End::Deadline => break,
+            End::Silent => stats.silent_deaths += 1,
+            End::Failed(Socks5Error::AssociationTerminated) => stats.terminations += 1,
+            End::Failed(e) => {
+                stats.errors += 1;
+                stats.last_error = Some(e.to_string());
+            }
+        }
+        stats.lifetimes.push(born.elapsed());
+    }
+}
+
+fn totals(stats: &[Mutex<Stats>]) -> Stats {
+    let mut totals = Stats::default();
+    for stats in stats {
+        let stats = stats.lock().unwrap_or_else(PoisonError::into_inner);
+        totals.binds += stats.binds;
+        totals.bind_failures += stats.bind_failures;
+        totals.probes += stats.probes;
+        totals.answered += stats.answered;
+        totals.silent_deaths += stats.silent_deaths;
+        totals.terminations += stats.terminations;
+        totals.errors += stats.errors;
+        totals.longest_silence = totals.longest_silence.max(stats.longest_silence);
+        if stats.last_error.is_some() {
+            totals.last_error = stats.last_error.clone();
+        }
+        totals.rtt.extend_from_slice(&stats.rtt);
+        totals.lifetimes.extend_from_slice(&stats.lifetimes);
+    }
+    totals
+}
+
+fn counters(totals: &Stats) -> String {
+    let answered = if totals.probes == 0 {
+        0.0
+    } else {
+        totals.answered as f64 / totals.probes as f64
+    };
+    format!(
+        "\"binds\":{},\"bind_failures\":{},\"probes\":{},\"answered\":{},\"answered_ratio\":{:.4},\"silent_deaths\":{},\"terminations\":{},\"errors\":{}",
+        totals.binds,
+        totals.bind_failures,
+        totals.probes,
+        totals.answered,
+        answered,
+        totals.silent_deaths,
+        totals.terminations,
+        totals.errors,
+    )
+}
+
+fn progress(elapsed: Duration, stats: &[Mutex<Stats>], rss_kb: Option<u64>) -> String {
+    let totals = totals(stats);
+    format!(
+        "{{\"elapsed_secs\":{},{},\"rss_kb\":{}}}",
+        elapsed.as_secs(),
+        counters(&totals),
+        json_number(rss_kb),
+    )
+}
+
+fn report(
+    options: &Options,
+    elapsed: Duration,
+    stats: &[Mutex<Stats>],
+    rss_start: Option<u64>,
+    rss_end: Option<u64>,
+    rss_max: Option<u64>,
+) -> String {
+    let totals = totals(stats);
+    let growth = match (rss_start, rss_end) {
+        (Some(start), Some(end)) => Some(end as i64 - start as i64),
+        _ => None,
+    };
+
+    let mut json = format!(
+        "{{\"proxy\":{},\"associations\":{},\"elapsed_secs\":{}",
+        json_string(&options.proxy),
+        options.associations,
+        elapsed.as_secs()
+    );
+    let _ = write!(json, ",{}", counters(&totals));
+    let _ = write!(json, ",\"longest_silence\":{}", totals.longest_silence);
+    if let Some(e) = &totals.last_error {
+        let _ = write!(json, ",\"last_error\":{}", json_string(e));
+    }
+    let _ = write!(json, ",\"rtt\":{}", latency(totals.rtt));
+    let _ = write!(json, ",\"lifetime\":{}", lifetimes(totals.lifetimes));
+    let _ = write!(
+        json,
+        ",\"memory\":{{\"start_kb\":{},\"end_kb\":{},\"max_kb\":{},\"growth_kb\":{}}}}}",
+        json_number(rss_start),
+        json_number(rss_end),
+        json_number(rss_max),
+        json_number(growth),
+    );
+    json
+}
+
+// Summarize how long the associations that died had lived, in seconds.
+fn lifetimes(mut lifetimes: Vec<Duration>) -> String {
+    if lifetimes.is_empty() {
+        return "null".to_owned();
+    }
+    lifetimes.sort();
+
+    let total: Duration = lifetimes.iter().sum();
+    format!(
+        "{{\"deaths\":{},\"min_secs\":{:.1},\"mean_secs\":{:.1},\"max_secs\":{:.1}}}",
+        lifetimes.len(),
+        lifetimes[0].as_secs_f64(),
+        total.as_secs_f64() / lifetimes.len() as f64,
+        lifetimes[lifetimes.len() - 1].as_secs_f64(),
+    )
+}
+
+fn json_number<T: ToString>(n: Option<T>) -> String {
+    n.map_or_else(|| "null".to_owned(), |n| n.to_string())
+}
+
+// The resident set size of the process, where `/proc` is available.
+fn rss_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
@@ -0,0 +1,4 @@
+//! The SOCKS5 client and server, re-exported from `pangolin-client` and `pangolin-server`.
+
+pub use pangolin_client::*;
+pub use pangolin_server::*;
@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use pangolin::dns_stub::{DnsStub, DnsStubConfig};
+use pangolin::socks::{Credentials, DynMethod, Socks5Config, Socks5Datagram, TargetAddr};
+
+use crate::bench::BenchResult;
+
+struct Options {
+    proxy: String,
+    username: Option<String>,
+    password: Option<String>,
+    listen: SocketAddr,
+    stub: DnsStubConfig,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> BenchResult<Self> {
+        let mut options = Options {
+            proxy: String::new(),
+            username: None,
+            password: None,
+            listen: SocketAddr::from(([127, 0, 0, 1], 5353)),
+            stub: DnsStubConfig::default(),
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", flag))?;
+            match flag.as_str() {
+                "--proxy" => options.proxy = value.clone(),
+                "--username" => options.username = Some(value.clone()),
+                "--password" => options.password = Some(value.clone()),
+                "--listen" => options.listen = value.parse()?,
+                "--upstream" => options.stub.upstream = TargetAddr::Ip(value.parse()?),
+                "--timeout-ms" => options.stub.timeout = Duration::from_millis(value.parse()?),
+                "--cache-size" => options.stub.cache_size = value.parse()?,
+                _ => return Err(format!("unknown option {}", flag).into()),
+            }
+        }
+
+        if options.proxy.is_empty() {
+            return Err("--proxy is required".into());
+        }
+        Ok(options)
+    }
+
+    fn config(&self) -> Socks5Config {
+        Socks5Config {
+            credentials: self.username.as_ref().map(|username| {
+                Credentials::new(username.as_str(), self.password.clone().unwrap_or_default())
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// Run `pangolin dns-stub` until the association or the local socket fails.
+pub async fn run(args: &[String]) -> BenchResult<()> {
+    let options = Options::parse(args)?;
+    let datagram = Socks5Datagram::<DynMethod>::bind_with_config(
+        options.proxy.as_str(),
+        "0.0.0.0:0",
+        options.config(),
+    )
+    .await?;
+
+    let stub = DnsStub::bind(options.listen, datagram, options.stub.clone()).await?;
+    eprintln!(
+        "pangolin: resolving on {} through {}",
+        stub.local_addr()?,
+        options.proxy
+    );
+    stub.run().await?;
+    Ok(())
+}
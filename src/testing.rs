@@ -0,0 +1,170 @@
+//! Test doubles for code written against the traits of pangolin.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+
+use tokio::io::ReadBuf;
+
+use crate::socks::{AsyncDatagram, Result, TargetAddr};
+
+/// An in-memory `AsyncDatagram` with scripted inbound packets, captured sends and deterministic
+/// loss, for unit tests that shouldn't bind real UDP sockets.
+///
+/// Clones share their state: keep one in the test to script and inspect the traffic while the
+/// code under test owns another.
+#[derive(Clone, Default)]
+pub struct MockDatagram {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    inbound: VecDeque<(Vec<u8>, TargetAddr)>,
+    sent: Vec<(Vec<u8>, TargetAddr)>,
+    dropped: usize,
+    drop_next: usize,
+    loss: Option<Loss>,
+    recv_waker: Option<Waker>,
+}
+
+// Drops packets with a fixed probability, drawn from a seeded xorshift generator so that runs
+// are reproducible.
+struct Loss {
+    rate: f64,
+    rng: u64,
+}
+
+impl Loss {
+    fn drop(&mut self) -> bool {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        ((self.rng >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+}
+
+impl State {
+    fn lose(&mut self) -> bool {
+        let lost = if self.drop_next > 0 {
+            self.drop_next -= 1;
+            true
+        } else {
+            self.loss.as_mut().is_some_and(Loss::drop)
+        };
+        if lost {
+            self.dropped += 1;
+        }
+        lost
+    }
+}
+
+impl MockDatagram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a packet from `from` for the next `recv_from`, unless it is lost.
+    pub fn push_inbound<D: Into<Vec<u8>>>(&self, data: D, from: TargetAddr) {
+        let mut state = self.lock();
+        if state.lose() {
+            return;
+        }
+        state.inbound.push_back((data.into(), from));
+        if let Some(waker) = state.recv_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// The packets sent so far and not lost, with their targets.
+    pub fn sent(&self) -> Vec<(Vec<u8>, TargetAddr)> {
+        self.lock().sent.clone()
+    }
+
+    /// Like `sent`, clearing the captured packets.
+    pub fn take_sent(&self) -> Vec<(Vec<u8>, TargetAddr)> {
+        std::mem::take(&mut self.lock().sent)
+    }
+
+    /// The number of inbound packets still queued.
+    pub fn pending(&self) -> usize {
+        self.lock().inbound.len()
+    }
+
+    /// The number of packets lost so far, in either direction.
+    pub fn dropped(&self) -> usize {
+        self.lock().dropped
+    }
+
+    /// Lose the next `n` packets, sent or pushed.
+    pub fn drop_next(&self, n: usize) {
+        self.lock().drop_next = n;
+    }
+
+    /// Lose each packet with probability `rate`, drawn from a generator seeded with `seed`.
+    pub fn set_loss(&self, rate: f64, seed: u64) {
+        self.lock().loss = Some(Loss {
+            rate,
+            // xorshift is stuck at zero.
+            rng: seed.max(1),
+        });
+    }
+
+    pub fn clear_loss(&self) {
+        let mut state = self.lock();
+        state.loss = None;
+        state.drop_next = 0;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl AsyncDatagram for MockDatagram {
+    fn poll_send_ready(&self, _: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let mut state = self.lock();
+        if state.inbound.is_empty() {
+            state.recv_waker = Some(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_send_to(
+        &self,
+        _: &mut Context<'_>,
+        buf: &[u8],
+        target: &TargetAddr,
+    ) -> Poll<Result<usize>> {
+        let mut state = self.lock();
+        if !state.lose() {
+            state.sent.push((buf.to_vec(), target.clone()));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<TargetAddr>> {
+        let mut state = self.lock();
+        match state.inbound.pop_front() {
+            Some((data, from)) => {
+                let n = buf.remaining().min(data.len());
+                buf.put_slice(&data[..n]);
+                Poll::Ready(Ok(from))
+            }
+            None => {
+                state.recv_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
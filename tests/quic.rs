@@ -0,0 +1,61 @@
+#![cfg(feature = "quinn")]
+
+use std::future::poll_fn;
+use std::io::{self, IoSliceMut};
+use std::sync::Arc;
+
+use pangolin::socks::{DirectDialer, NoAuthentication, QuicSocket, Socks5Datagram, Socks5Server};
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::AsyncUdpSocket;
+use tokio::net::{TcpStream, UdpSocket};
+
+#[tokio::test]
+async fn carries_packets_through_the_proxy() {
+    let server = Socks5Server::bind("127.0.0.1:0", DirectDialer::new(), Default::default())
+        .await
+        .unwrap();
+    let proxy = server.local_addr().unwrap();
+    tokio::spawn(async move { server.run().await });
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let datagram = Socks5Datagram::<NoAuthentication<TcpStream>>::bind(proxy, "127.0.0.1:0")
+        .await
+        .unwrap();
+    let socket = Arc::new(QuicSocket::new(datagram));
+
+    let transmit = Transmit {
+        destination: peer.local_addr().unwrap(),
+        ecn: None,
+        contents: b"initial",
+        segment_size: None,
+        src_ip: None,
+    };
+    loop {
+        match socket.try_send(&transmit) {
+            Ok(()) => break,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let mut poller = socket.clone().create_io_poller();
+                poll_fn(|cx| poller.as_mut().poll_writable(cx))
+                    .await
+                    .unwrap();
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    let mut buf = [0; 64];
+    let (len, relay) = peer.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"initial");
+
+    peer.send_to(b"handshake", relay).await.unwrap();
+    let mut buf = [0; 64];
+    let mut meta = [RecvMeta::default()];
+    let received = poll_fn(|cx| {
+        let mut bufs = [IoSliceMut::new(&mut buf)];
+        socket.poll_recv(cx, &mut bufs, &mut meta)
+    })
+    .await
+    .unwrap();
+    assert_eq!(received, 1);
+    assert_eq!(meta[0].addr, peer.local_addr().unwrap());
+    assert_eq!(&buf[..meta[0].len], b"handshake");
+}